@@ -1,5 +1,5 @@
 use clap::{arg, ArgMatches, Command};
-use djinn_client_lib::DjinnClient;
+use djinn_client_lib::{Credentials, DjinnClient, TransportSecurity};
 
 #[tokio::main]
 async fn main() {
@@ -13,8 +13,36 @@ async fn main() {
     let host = host_arg.to_owned();
     let port_arg = matches.get_one::<String>("port").unwrap();
     let port = port_arg.to_owned().parse::<usize>().unwrap();
+    let max_transfers = matches
+        .get_one::<String>("max-transfers")
+        .map(|max_transfers| max_transfers.parse::<usize>().expect("Invalid --max-transfers"));
+    let max_packet_size = matches
+        .get_one::<String>("max-packet-size")
+        .map(|max_packet_size| max_packet_size.parse::<usize>().expect("Invalid --max-packet-size"));
+
+    let security = match get_transport_security(&matches) {
+        Ok(security) => security,
+        Err(reason) => {
+            eprintln!("{}", reason);
+            std::process::exit(1);
+        }
+    };
+
+    let credentials = match get_credentials(&matches) {
+        Ok(credentials) => credentials,
+        Err(reason) => {
+            eprintln!("{}", reason);
+            std::process::exit(1);
+        }
+    };
 
-    let mut djinn_client = DjinnClient::new(host, port).await.unwrap();
+    let mut djinn_client = match DjinnClient::new(host, port, security, credentials, max_transfers, max_packet_size).await {
+        Ok(djinn_client) => djinn_client,
+        Err(error) => {
+            eprintln!("Failed to connect: {}", error);
+            std::process::exit(1);
+        }
+    };
 
     // Handle subcommands
     handle_subcommand(&matches, &mut djinn_client).await;
@@ -33,6 +61,12 @@ fn get_cli_matches() -> ArgMatches {
         .version("1.0")
         .arg(arg!( --host -h [HOST] "The host to connect to").required(true))
         .arg(arg!( --port -p [PORT] "The port to connect to").required(true))
+        .arg(arg!( --"max-transfers" [MAX_TRANSFERS] "How many files to send at the same time"))
+        .arg(arg!( --"max-packet-size" [MAX_PACKET_SIZE] "Largest packet in bytes to accept from the server, raise it for large sync indexes"))
+        .arg(arg!( --"ca-cert" [CA_CERT] "PEM file with the CA that signed the server certificate"))
+        .arg(arg!( --fingerprint [FINGERPRINT] "SHA-256 fingerprint of the server certificate to trust"))
+        .arg(arg!( --plaintext "Connect without encryption, file contents and paths can be read on the network"))
+        .arg(arg!( --user -u [USER] "The user to authenticate as, the password is read from DJINN_PASSWORD"))
         .subcommand(Command::new("echo").about("Ping the host"))
         .subcommand(
             Command::new("get")
@@ -49,19 +83,49 @@ fn get_cli_matches() -> ArgMatches {
         .subcommand(
             Command::new("sync")
                 .about("Sync a directory")
-                .arg(arg!( --path -p [PATH] "The path to sync").required(true))
-                .arg(arg!( --target -t [TARGET] "The target to sync to").required(true)),
+                .arg(arg!( --path -p [PATH] "The path to sync, as share:path for a named share").required(true))
+                .arg(arg!( --target -t [TARGET] "The target to sync to").required(true))
+                .arg(arg!( --"dry-run" "Print what would be synced without changing any files")),
         )
         .subcommand(
             Command::new("monkey")
                 .about("Act like a monkey")
-                .arg(arg!( --path -p [PATH] "The path to sync").required(true))
+                .arg(arg!( --path -p [PATH] "The path to sync, as share:path for a named share").required(true))
                 .arg(arg!( --target -t [TARGET] "The target to sync to").required(true)),
         );
 
     matches.get_matches()
 }
 
+/// Exactly one way of trusting the server has to be picked, plaintext is never the default
+fn get_transport_security(matches: &ArgMatches) -> Result<TransportSecurity, String> {
+    let ca_cert = matches.get_one::<String>("ca-cert");
+    let fingerprint = matches.get_one::<String>("fingerprint");
+    let plaintext = matches.get_flag("plaintext");
+
+    match (ca_cert, fingerprint, plaintext) {
+        (Some(ca_cert), None, false) => Ok(TransportSecurity::CaCertificate(ca_cert.to_owned())),
+        (None, Some(fingerprint), false) => Ok(TransportSecurity::PinnedFingerprint(fingerprint.to_owned())),
+        (None, None, true) => Ok(TransportSecurity::Plaintext),
+        (None, None, false) => Err("Pass --ca-cert or --fingerprint to verify the server, or --plaintext to connect unencrypted".to_string()),
+        _ => Err("Only one of --ca-cert, --fingerprint and --plaintext can be used".to_string()),
+    }
+}
+
+/// The password comes from the environment so it does not show up in the process list
+fn get_credentials(matches: &ArgMatches) -> Result<Option<Credentials>, String> {
+    let Some(user) = matches.get_one::<String>("user") else {
+        return Ok(None);
+    };
+
+    let password = std::env::var("DJINN_PASSWORD").map_err(|_| "Set DJINN_PASSWORD to the password of --user".to_string())?;
+
+    Ok(Some(Credentials {
+        user: user.to_owned(),
+        password,
+    }))
+}
+
 async fn handle_subcommand(matches: &ArgMatches, djinn_client: &mut DjinnClient) {
     match matches.subcommand() {
         Some(("echo", _matches)) => {
@@ -89,7 +153,12 @@ async fn handle_subcommand(matches: &ArgMatches, djinn_client: &mut DjinnClient)
             let target_arg = matches.get_one::<String>("target").unwrap();
             let target = target_arg.to_owned();
 
-            djinn_client.sync_internal(path, target).await;
+            let dry_run = matches.get_flag("dry-run");
+
+            if let Err(error) = djinn_client.sync_internal(path, target, dry_run).await {
+                eprintln!("Sync failed: {}", error);
+                std::process::exit(1);
+            }
         }
         Some(("monkey", matches)) => {
             let path_arg = matches.get_one::<String>("path").unwrap();
@@ -1,15 +1,34 @@
 use std::error::Error;
 
-use crate::{connectivity::Connection, commands::{EchoCommand, GetCommand}, syncing::{SyncManager, UserMonkey}};
+use crate::{connectivity::{Connection, Credentials, TransportSecurity}, commands::{EchoCommand, GetCommand}, syncing::{SyncManager, UserMonkey}};
 
 pub struct ClientInstance {
   connection: Connection,
 }
 
 impl ClientInstance {
-  pub async fn new(host: String, port: usize) -> Result<ClientInstance, Box<dyn Error>> {
-    let mut connection = Connection::new(host, port);
+  pub async fn new(
+    host: String,
+    port: usize,
+    security: TransportSecurity,
+    credentials: Option<Credentials>,
+    max_concurrent_transfers: Option<usize>,
+    max_packet_size: Option<usize>,
+  ) -> Result<ClientInstance, Box<dyn Error>> {
+    let mut connection = Connection::new(host, port, security);
+    if let Some(max_concurrent_transfers) = max_concurrent_transfers {
+      connection = connection.with_max_concurrent_transfers(max_concurrent_transfers);
+    }
+    if let Some(max_packet_size) = max_packet_size {
+      connection = connection.with_max_packet_size(max_packet_size);
+    }
     connection.connect().await?;
+    connection.handshake().await?;
+
+    // Without credentials the server has to allow anonymous clients
+    if let Some(credentials) = credentials {
+      connection.authenticate(&credentials).await?;
+    }
 
     Ok(ClientInstance {
       connection,
@@ -33,12 +52,12 @@ impl ClientInstance {
     info!("Put command not implemented")
   }
 
-  pub async fn sync_internal(&mut self, path: String, target: String) {
-    let mut handler = SyncManager::new(path, target);
-    handler.start(&mut self.connection).await.expect("Sync handler failed");
+  pub async fn sync_internal(&mut self, path: String, target: String, dry_run: bool) -> Result<(), Box<dyn Error>> {
+    let mut handler = SyncManager::new(path, target).with_dry_run(dry_run);
+    handler.start(&mut self.connection).await
   }
 
-  pub async fn monkey_internal(&mut self, path: String, target: String) {
+  pub async fn monkey_internal(&mut self, _path: String, target: String) {
     let mut monkey = UserMonkey::new(
         target.clone()
       );
@@ -1,6 +1,9 @@
-use std::{collections::HashMap, error::Error};
+use std::error::Error;
 
-use djinn_core_lib::data::packets::{ControlPacket, ControlPacketType, PacketType};
+use djinn_core_lib::data::{
+    messages::ControlMessage,
+    packets::{ControlPacket, PacketType},
+};
 
 use crate::connectivity::Connection;
 
@@ -13,7 +16,7 @@ impl EchoCommand {
 
     pub async fn execute(&self, connection: &mut Connection) -> Result<(), Box<dyn Error>> {
         // Create packet
-        let packet = ControlPacket::new(ControlPacketType::EchoRequest, HashMap::new());
+        let packet = ControlMessage::EchoRequest.to_packet(None);
 
         // Send packet
         let start = std::time::Instant::now();
@@ -24,8 +27,7 @@ impl EchoCommand {
         let response_packet = connection.read_next_packet().await?.unwrap();
 
         if !matches!(response_packet.get_packet_type(), PacketType::Control) {
-            return Err(Box::new(std::io::Error::new(
-                std::io::ErrorKind::Other,
+            return Err(Box::new(std::io::Error::other(
                 "Unexpected packet type",
             )));
         }
@@ -36,11 +38,10 @@ impl EchoCommand {
             .unwrap();
 
         if !matches!(
-            control_packet.control_packet_type,
-            ControlPacketType::EchoReply
+            ControlMessage::from_packet(control_packet)?,
+            ControlMessage::EchoReply
         ) {
-            return Err(Box::new(std::io::Error::new(
-                std::io::ErrorKind::Other,
+            return Err(Box::new(std::io::Error::other(
                 "Unexpected control packet type",
             )));
         }
@@ -1,8 +1,9 @@
-use std::{collections::HashMap, error::Error};
+use std::error::Error;
 
 
-use djinn_core_lib::data::packets::{ControlPacket, ControlPacketType, PacketType, DataPacket, PacketReader};
-use tokio::{fs::File, io::{AsyncWriteExt}};
+use djinn_core_lib::data::messages::{ControlMessage, TransferDirection, TransferRequest, TransferStart};
+use djinn_core_lib::data::packets::{ControlPacket, PacketType, DataPacket, PacketReader, TransferChecksum};
+use tokio::{fs::{remove_file, File}, io::{AsyncWriteExt}};
 
 use crate::connectivity::Connection;
 
@@ -18,11 +19,18 @@ impl GetCommand {
     pub async fn execute(&self, connection: &mut Connection) -> Result<String, Box<dyn Error>> {
         //Ask for the file from the server
         debug!("Sending transfer request");
-        let mut params = HashMap::new();
-        params.insert("file_path".to_string(), self.file_path.clone());
-        params.insert("type".to_string(), "get".to_string());
-
-        let packet = ControlPacket::new(ControlPacketType::TransferRequest, params);
+        let request = TransferRequest {
+            file_path: self.file_path.clone(),
+            transfer_id: 0,
+            direction: TransferDirection::ToClient,
+            modified_time: None,
+            delta: false,
+            signature: None,
+            key: None,
+            offset: 0,
+        };
+
+        let packet = ControlMessage::TransferRequest(request).to_packet(None);
         connection.send_packet(packet).await?;
 
         debug!("Sent transfer request");
@@ -33,8 +41,7 @@ impl GetCommand {
         debug!("Received transfer ack");
 
         if !matches!(response_packet.get_packet_type(), PacketType::Control) {
-            return Err(Box::new(std::io::Error::new(
-                std::io::ErrorKind::Other,
+            return Err(Box::new(std::io::Error::other(
                 "Unexpected packet type",
             )));
         }
@@ -44,36 +51,29 @@ impl GetCommand {
             .downcast_ref::<ControlPacket>()
             .unwrap();
 
-        // If the server denies the request, return an error
-        if matches!(
-            control_packet.control_packet_type,
-            ControlPacketType::TransferDeny
-        ) {
-            let reason = control_packet.params.get("reason").unwrap();
-            return Err(Box::new(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                format!("Transfer denied: {}", reason),
-            )));
-        }
-
-        if !matches!(
-            control_packet.control_packet_type,
-            ControlPacketType::TransferAck
-        ) {
-            return Err(Box::new(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                "Unexpected control packet type",
-            )));
-        }
+        let transfer_ack = match ControlMessage::from_packet(control_packet)? {
+            ControlMessage::TransferAck(transfer_ack) => transfer_ack,
+            // If the server denies the request, return an error
+            ControlMessage::TransferDeny(transfer_deny) => {
+                return Err(Box::new(std::io::Error::other(
+                    format!("Transfer denied: {}", transfer_deny.reason),
+                )));
+            }
+            _ => {
+                return Err(Box::new(std::io::Error::other(
+                    "Unexpected control packet type",
+                )));
+            }
+        };
 
         // If the server accepts the request, start receiving the file
         debug!("Transfer accepted, starting transfer");
 
-        let job_id = control_packet.params.get("job_id").unwrap();
-
         //Send start transfer packet
-        let mut packet = ControlPacket::new(ControlPacketType::TransferStart, HashMap::new());
-        packet.params.insert("job_id".to_string(), job_id.clone());
+        let packet = ControlMessage::TransferStart(TransferStart {
+            job_id: transfer_ack.job_id,
+        })
+        .to_packet(None);
         connection.send_packet(packet).await?;
 
         debug!("Sent transfer start");
@@ -85,8 +85,7 @@ impl GetCommand {
         let mut possible_reader = connection.reader.lock().await;
 
         if possible_reader.is_none() {
-            return Err(Box::new(std::io::Error::new(
-                std::io::ErrorKind::Other,
+            return Err(Box::new(std::io::Error::other(
                 "Reader is none",
             )));
         }
@@ -94,11 +93,12 @@ impl GetCommand {
         let reader = possible_reader.as_mut().unwrap();
 
 
-        let mut packet_reader = PacketReader::new();
+        let mut packet_reader = PacketReader::with_max_packet_size(connection.max_packet_size);
+        let mut checksum = TransferChecksum::new();
         let mut last_packet_received = false;
 
         while !last_packet_received {
-            let packets = packet_reader.read(reader, None).await;
+            let packets = packet_reader.read(reader, None).await?;
 
             if packets.is_empty() {
                 debug!("Connection closed");
@@ -111,6 +111,15 @@ impl GetCommand {
                     .downcast_ref::<DataPacket>()
                     .unwrap();
 
+                if !checksum.verify(data_packet) {
+                    drop(file);
+                    remove_file(&self.file_path).await?;
+
+                    return Err(Box::new(std::io::Error::other(
+                        "Checksum mismatch, transfer discarded",
+                    )));
+                }
+
                 if !data_packet.has_data {
                     last_packet_received = true;
                     break;
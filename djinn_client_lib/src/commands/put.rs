@@ -1,6 +1,7 @@
-use std::{collections::HashMap, error::Error};
+use std::error::Error;
 
-use djinn_core_lib::data::packets::{ControlPacket, ControlPacketType, PacketType};
+use djinn_core_lib::data::messages::{ControlMessage, TransferDirection, TransferRequest};
+use djinn_core_lib::data::packets::{ControlPacket, PacketType};
 use tokio::fs::File;
 
 use crate::connectivity::Connection;
@@ -17,12 +18,19 @@ impl PutCommand {
     pub async fn execute(&self, connection: &mut Connection) -> Result<String, Box<dyn Error>> {
         //Ask for the file from the server
         debug!("Sending transfer request");
-        let mut params = HashMap::new();
-        params.insert("file_path".to_string(), self.file_path.clone());
-        params.insert("type".to_string(), "put".to_string());
-
-
-        let packet = ControlPacket::new(ControlPacketType::TransferRequest, params);
+        let request = TransferRequest {
+            file_path: self.file_path.clone(),
+            transfer_id: 0,
+            direction: TransferDirection::ToServer,
+            modified_time: None,
+            delta: false,
+            signature: None,
+            key: None,
+            offset: 0,
+        };
+
+
+        let packet = ControlMessage::TransferRequest(request).to_packet(None);
         connection.send_packet(packet).await?;
 
         debug!("Sent transfer request");
@@ -33,8 +41,7 @@ impl PutCommand {
         debug!("Received transfer ack");
 
         if !matches!(response_packet.get_packet_type(), PacketType::Control) {
-            return Err(Box::new(std::io::Error::new(
-                std::io::ErrorKind::Other,
+            return Err(Box::new(std::io::Error::other(
                 "Unexpected packet type",
             )));
         }
@@ -44,32 +51,25 @@ impl PutCommand {
             .downcast_ref::<ControlPacket>()
             .unwrap();
 
-        // If the server denies the request, return an error
-        if matches!(
-            control_packet.control_packet_type,
-            ControlPacketType::TransferDeny
-        ) {
-            let reason = control_packet.params.get("reason").unwrap();
-            return Err(Box::new(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                format!("Transfer denied: {}", reason),
-            )));
-        }
-
-        if !matches!(
-            control_packet.control_packet_type,
-            ControlPacketType::TransferAck
-        ) {
-            return Err(Box::new(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                "Unexpected control packet type",
-            )));
-        }
+        let transfer_ack = match ControlMessage::from_packet(control_packet)? {
+            ControlMessage::TransferAck(transfer_ack) => transfer_ack,
+            // If the server denies the request, return an error
+            ControlMessage::TransferDeny(transfer_deny) => {
+                return Err(Box::new(std::io::Error::other(
+                    format!("Transfer denied: {}", transfer_deny.reason),
+                )));
+            }
+            _ => {
+                return Err(Box::new(std::io::Error::other(
+                    "Unexpected control packet type",
+                )));
+            }
+        };
 
         // If the server accepts the request, start receiving the file
         debug!("Transfer accepted, starting transfer");
 
-        let job_id = control_packet.params.get("job_id").unwrap();
+        let _job_id = transfer_ack.job_id;
         let mut file = File::create(self.file_path.clone()).await?;
 
         //Send file parts
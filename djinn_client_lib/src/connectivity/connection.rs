@@ -2,44 +2,77 @@ use std::error::Error;
 use std::sync::Arc;
 
 
+use djinn_core_lib::connectivity::BoxedStream;
+use djinn_core_lib::data::handshake::Capability;
+use djinn_core_lib::data::handshake::Hello;
+use djinn_core_lib::data::handshake::MIN_PROTOCOL_VERSION;
+use djinn_core_lib::data::handshake::PROTOCOL_VERSION;
+use djinn_core_lib::data::messages::Authenticate;
+use djinn_core_lib::data::messages::ControlMessage;
+use djinn_core_lib::data::packets::ControlPacket;
 use djinn_core_lib::data::packets::PacketReader;
+use djinn_core_lib::data::packets::SendScheduler;
+use djinn_core_lib::data::packets::DEFAULT_MAX_CONCURRENT_TRANSFERS;
+use djinn_core_lib::data::packets::DEFAULT_MAX_PACKET_SIZE;
 use djinn_core_lib::data::packets::packet::Packet;
 use djinn_core_lib::data::packets::packet::duplicate_packet;
-use tokio::io::AsyncWriteExt;
 use tokio::io::BufReader;
 use tokio::io::ReadHalf;
-use tokio::io::WriteHalf;
 use tokio::net::TcpStream;
 use tokio::sync::Mutex;
 
+use super::Credentials;
+use super::TransportSecurity;
+
 pub struct Connection {
-    pub reader: Arc<Mutex<Option<BufReader<ReadHalf<TcpStream>>>>>,
-    pub write_stream: Arc<Mutex<Option<WriteHalf<TcpStream>>>>,
+    pub reader: Arc<Mutex<Option<BufReader<ReadHalf<BoxedStream>>>>>,
+    pub sender: Option<SendScheduler>,
+    /// How many files are sent to the server at the same time
+    pub max_concurrent_transfers: usize,
+    /// Largest packet in bytes the server may send, sync indexes of big trees need more than the default
+    pub max_packet_size: usize,
     pub active: bool,
     pub host: String,
     pub port: usize,
-    pub packet_reader: PacketReader
+    pub security: TransportSecurity,
+    pub packet_reader: PacketReader,
+    pub handshake: Option<Hello>
 }
 
 impl Connection {
-    pub fn new(host: String, port: usize) -> Connection {
+    pub fn new(host: String, port: usize, security: TransportSecurity) -> Connection {
         Connection {
             reader: Arc::new(Mutex::new(None)),
-            write_stream: Arc::new(Mutex::new(None)),
+            sender: None,
+            max_concurrent_transfers: DEFAULT_MAX_CONCURRENT_TRANSFERS,
+            max_packet_size: DEFAULT_MAX_PACKET_SIZE,
             active: false,
             host,
             port,
-            packet_reader: PacketReader::new()
+            security,
+            packet_reader: PacketReader::new(),
+            handshake: None
         }
     }
 
+    pub fn with_max_concurrent_transfers(mut self, max_concurrent_transfers: usize) -> Connection {
+        self.max_concurrent_transfers = max_concurrent_transfers;
+        self
+    }
+
+    pub fn with_max_packet_size(mut self, max_packet_size: usize) -> Connection {
+        self.max_packet_size = max_packet_size;
+        self.packet_reader = PacketReader::with_max_packet_size(max_packet_size);
+        self
+    }
+
     pub async fn connect(&mut self) -> Result<(), Box<dyn Error>> {
         let addr = format!("{}:{}", self.host, self.port);
         let stream = TcpStream::connect(addr).await?;
+        let stream = self.security.secure(&self.host, stream).await?;
         let (read_stream, write_stream) = tokio::io::split(stream);
 
-        let mut internal_write_stream = self.write_stream.lock().await;
-        *internal_write_stream = Some(write_stream);
+        self.sender = Some(SendScheduler::new(write_stream, self.max_concurrent_transfers));
 
         //Create reader
         let mut internal_reader = self.reader.lock().await;
@@ -49,47 +82,120 @@ impl Connection {
         Ok(())
     }
 
+    pub async fn handshake(&mut self) -> Result<(), Box<dyn Error>> {
+        let mut client_hello = Hello::new(vec![Capability::Compression]);
+        client_hello.name = Some(client_name());
+        self.send_packet(ControlMessage::Hello(client_hello).to_packet(None)).await?;
+
+        let response_packet = self
+            .read_next_packet()
+            .await?
+            .ok_or("Server closed the connection during the handshake")?;
+
+        let control_packet = response_packet
+            .as_any()
+            .downcast_ref::<ControlPacket>()
+            .ok_or("Unexpected data packet during the handshake")?;
+
+        let message = ControlMessage::from_packet(control_packet)
+            .map_err(|error| format!("Malformed handshake response: {}", error))?;
+
+        match message {
+            ControlMessage::HelloAck(agreed) => {
+
+                if agreed.protocol_version < MIN_PROTOCOL_VERSION || agreed.protocol_version > PROTOCOL_VERSION {
+                    return Err(format!(
+                        "Server picked protocol v{} but this client speaks v{}-v{}",
+                        agreed.protocol_version, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION
+                    )
+                    .into());
+                }
+
+                debug!(
+                    "Handshake done: protocol v{}, server {}, capabilities {:?}",
+                    agreed.protocol_version, agreed.software_version, agreed.capabilities
+                );
+
+                self.handshake = Some(agreed);
+                Ok(())
+            }
+            ControlMessage::HelloDeny(deny) => {
+                Err(format!("Server rejected the connection: {}", deny.reason).into())
+            }
+            _ => Err("Unexpected control packet during the handshake".into()),
+        }
+    }
+
+    pub async fn authenticate(&mut self, credentials: &Credentials) -> Result<(), Box<dyn Error>> {
+        let request = ControlMessage::Authenticate(Authenticate {
+            user: credentials.user.clone(),
+            password: credentials.password.clone(),
+        });
+        self.send_packet(request.to_packet(None)).await?;
+
+        let response_packet = self
+            .read_next_packet()
+            .await?
+            .ok_or("Server closed the connection during authentication")?;
+
+        let control_packet = response_packet
+            .as_any()
+            .downcast_ref::<ControlPacket>()
+            .ok_or("Unexpected data packet during authentication")?;
+
+        let message = ControlMessage::from_packet(control_packet)
+            .map_err(|error| format!("Malformed authentication response: {}", error))?;
+
+        match message {
+            ControlMessage::AuthenticateAck(ack) => {
+                debug!("Authenticated as {}", ack.user);
+                Ok(())
+            }
+            ControlMessage::AuthenticateDeny(deny) => {
+                Err(format!("Server refused the credentials: {}", deny.reason).into())
+            }
+            _ => Err("Unexpected control packet during authentication".into()),
+        }
+    }
+
+    /// Whether both sides agreed to compress data packets
+    pub fn compression(&self) -> bool {
+        self.handshake.as_ref().is_some_and(|handshake| handshake.has_capability(Capability::Compression))
+    }
+
     pub async fn disconnect(&mut self) -> Result<(), Box<dyn Error>> {
         //Drop halves
         let mut reader = self.reader.lock().await;
         *reader = None;
-        let mut write_stream = self.write_stream.lock().await;
-        *write_stream = None;
+        if let Some(sender) = self.sender.take() {
+            if let Err(error) = sender.shutdown().await {
+                debug!("Failed to shut down connection: {}", error);
+            }
+        }
 
         self.active = false;
         Ok(())
     }
 
+    /// Queues a control packet, it is written ahead of the data of running uploads
     pub async fn send_packet(&self, packet: impl Packet) -> Result<(), Box<dyn Error>> {
-        let mut stream = self.write_stream.lock().await;
-        if stream.is_some() {
-            // Convert packet to buffer
-            let buffer = packet.to_buffer();
-            // Write buffer to stream with writer
-            let writer_stream = stream.as_mut().unwrap();
-
-            writer_stream.write_all(&buffer).await?;
-            writer_stream.flush().await?;
-        } else {
-            return Err(Box::new(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                "Stream is not connected",
-            )));
-        }
+        self.get_sender()?.send_control(packet)?;
         Ok(())
     }
 
+    /// Waits until the packets sent so far are written
     pub async fn flush(&self) -> Result<(), Box<dyn Error>> {
-        let mut write_stream = self.write_stream.lock().await;
-        if write_stream.is_some() {
-            write_stream.as_mut().unwrap().flush().await?;
-        } else {
-            return Err(Box::new(std::io::Error::new(
-                std::io::ErrorKind::Other,
+        self.get_sender()?.flush().await?;
+        Ok(())
+    }
+
+    pub fn get_sender(&self) -> Result<&SendScheduler, Box<dyn Error>> {
+        match &self.sender {
+            Some(sender) => Ok(sender),
+            None => Err(Box::new(std::io::Error::other(
                 "Stream is not connected",
-            )));
+            ))),
         }
-        Ok(())
     }
 
     pub async fn read_next_packet(&mut self) -> Result<Option<Box<dyn Packet>>, Box<dyn Error>> {
@@ -97,18 +203,27 @@ impl Connection {
 
         if possible_reader.is_some() {
             let reader = possible_reader.as_mut().unwrap();
-            let packets = self.packet_reader.read(reader, Some(1)).await;
+            let packets = self.packet_reader.read(reader, Some(1)).await?;
 
             if packets.is_empty() {
                 return Ok(None);
             }
 
-            return Ok(Some(duplicate_packet(&packets[0])));
+            Ok(Some(duplicate_packet(packets[0].as_ref())))
         } else {
-            Err(Box::new(std::io::Error::new(
-                std::io::ErrorKind::Other,
+            Err(Box::new(std::io::Error::other(
                 "Stream is not connected",
             )))
         }
     }
 }
+
+/// Name this client introduces itself with, which ends up in the names of conflict copies
+pub fn client_name() -> String {
+    std::env::var("HOSTNAME")
+        .ok()
+        .or_else(|| std::fs::read_to_string("/etc/hostname").ok())
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+        .unwrap_or_else(|| "client".to_string())
+}
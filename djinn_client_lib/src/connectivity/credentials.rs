@@ -0,0 +1,6 @@
+/// User and password the client authenticates with after the handshake
+#[derive(Clone, Debug)]
+pub struct Credentials {
+    pub user: String,
+    pub password: String,
+}
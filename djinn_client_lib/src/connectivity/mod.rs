@@ -1,2 +1,9 @@
 mod connection;
-pub use connection::Connection;
\ No newline at end of file
+pub use connection::Connection;
+pub use connection::client_name;
+
+mod transport_security;
+pub use transport_security::TransportSecurity;
+
+mod credentials;
+pub use credentials::Credentials;
@@ -0,0 +1,27 @@
+use std::io;
+
+use djinn_core_lib::connectivity::{tls, BoxedStream};
+use tokio::net::TcpStream;
+
+/// How the connection to the server is secured
+#[derive(Clone, Debug)]
+pub enum TransportSecurity {
+    /// TLS, trusting servers with a certificate signed by a CA from this PEM file
+    CaCertificate(String),
+    /// TLS, trusting only the server certificate with this SHA-256 fingerprint
+    PinnedFingerprint(String),
+    /// No encryption at all, only when explicitly asked for
+    Plaintext,
+}
+
+impl TransportSecurity {
+    pub async fn secure(&self, host: &str, stream: TcpStream) -> io::Result<BoxedStream> {
+        match self {
+            TransportSecurity::CaCertificate(ca_path) => tls::connect(tls::client_config_with_ca(ca_path)?, host, stream).await,
+            TransportSecurity::PinnedFingerprint(fingerprint) => {
+                tls::connect(tls::client_config_with_fingerprint(fingerprint)?, host, stream).await
+            }
+            TransportSecurity::Plaintext => Ok(Box::new(stream)),
+        }
+    }
+}
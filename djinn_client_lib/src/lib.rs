@@ -4,5 +4,7 @@ mod client_instance;
 mod syncing;
 
 pub use client_instance::ClientInstance as DjinnClient;
+pub use connectivity::Credentials;
+pub use connectivity::TransportSecurity;
 
 #[macro_use] extern crate log;
\ No newline at end of file
@@ -1,25 +1,29 @@
-use std::{error::Error, time::Duration, collections::HashMap, sync::Arc};
+use std::{error::Error, time::Duration, sync::Arc};
 
-use djinn_core_lib::data::{syncing::IndexManager, packets::{ControlPacket, ControlPacketType, packet::Packet}};
-use tokio::{time::sleep, sync::Mutex, io::{WriteHalf, AsyncWriteExt}, net::TcpStream};
+use djinn_core_lib::data::{syncing::{HashCache, IndexManager, StateStore}, messages::{ControlMessage, SyncIndex}, packets::SendScheduler};
+use tokio::{time::sleep, sync::Mutex};
+
+use super::changed_base_entries;
 
 pub struct FsPoller {
     pub path: String,
     pub job_id: u32,
-    pub was_just_syncing: bool
+    pub was_just_syncing: bool,
+    pub hash_cache: HashCache
 }
 
 impl FsPoller {
-    pub fn new(path: String, job_id: u32) -> FsPoller {
+    pub fn new(path: String, job_id: u32, hash_cache: HashCache) -> FsPoller {
         FsPoller {
             path,
             job_id,
-            was_just_syncing: false
+            was_just_syncing: false,
+            hash_cache
         }
     }
 
-    pub async fn poll(&mut self, write_stream_arc: Arc<Mutex<Option<WriteHalf<TcpStream>>>>, is_syncing_arc: Arc<Mutex<bool>>) -> Result<(), Box<dyn Error>> {
-        let mut index_manager = IndexManager::new(self.path.clone());
+    pub async fn poll(&mut self, sender: SendScheduler, is_syncing_arc: Arc<Mutex<bool>>) -> Result<(), Box<dyn Error>> {
+        let mut index_manager = IndexManager::with_hash_cache(self.path.clone(), self.hash_cache.clone());
         index_manager.build().await;
 
         loop {
@@ -34,7 +38,7 @@ impl FsPoller {
 
 
             //Check if the index has changed
-            let mut new_index_manager = IndexManager::new(self.path.clone());
+            let mut new_index_manager = IndexManager::with_hash_cache(self.path.clone(), self.hash_cache.clone());
             new_index_manager.build().await;
 
             // Remove timestamps from the index
@@ -46,37 +50,25 @@ impl FsPoller {
             if index_without_timestamps != new_index_without_timestamps || self.was_just_syncing {
                 debug!("Index has changed, sending new index response");
                 //Send new index response
-                let mut params = HashMap::new();
                 let mut index = new_index_manager.index.clone();
 
                 // Add deleted files with timestamp 0 by looping through the old index
-                for (key, _) in index_without_timestamps.iter() {
+                for (key, entry) in index_without_timestamps.iter() {
                     if !index.contains_key(key) {
-                       index.insert(key.clone(), 0);
+                       index.insert(key.clone(), entry.to_deleted());
                     }
                 }
 
-                //Stringify the timestamps
-                for (key, value) in index.iter() {
-                    params.insert(key.clone(), value.to_string());
-                }
-
-                let mut packet = ControlPacket::new(ControlPacketType::SyncIndexUpdate, params);
+                let state_store = StateStore::new(&self.path);
+                let base = changed_base_entries(&state_store.load().await, &index);
 
-                packet.job_id = Some(self.job_id);
+                let packet = ControlMessage::SyncIndexUpdate(SyncIndex { index: index.clone(), base }).to_packet(Some(self.job_id));
 
-                let mut write_stream_option = write_stream_arc.lock().await;
-
-                if write_stream_option.is_none() {
-                    debug!("Write stream is none");
+                if let Err(error) = sender.send_control(packet) {
+                    debug!("Connection is closed: {}", error);
                     continue;
                 }
 
-                let write_stream = write_stream_option.as_mut().unwrap();
-
-                write_stream.write_all(packet.to_buffer().as_slice()).await?;
-                write_stream.flush().await?;
-
                 debug!("SENT INDEX UPDATE, {:?}", index);
 
 
@@ -1,9 +1,11 @@
 mod sync_manager;
 pub use sync_manager::SyncManager;
+pub use sync_manager::changed_base_entries;
+mod sync_checklist;
+pub use sync_checklist::SyncChecklist;
 mod fs_poller;
 mod transfer;
 pub use transfer::Transfer;
-pub use transfer::TransferDirection;
 pub use transfer::TransferStatus;
 mod packet_handler;
 pub use packet_handler::PacketHandler;
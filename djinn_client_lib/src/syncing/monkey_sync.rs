@@ -97,7 +97,7 @@ impl UserMonkey {
         info!("Created file {}", self.target.clone() + "/" + &file_name);
     }
     async fn update_file(&self) {
-        if self.files.len() == 0 {
+        if self.files.is_empty() {
             return;
         }
 
@@ -126,7 +126,7 @@ impl UserMonkey {
     }
 
     async fn delete_file(&self) {
-        if self.files.len() == 0 {
+        if self.files.is_empty() {
             return;
         }
 
@@ -1,18 +1,20 @@
-use std::{collections::HashMap, path::Path};
+use std::path::Path;
 
 use djinn_core_lib::data::{
-    packets::{packet::Packet, ControlPacket, ControlPacketType, DataPacket, PacketType},
-    syncing::IndexManager,
+    delta::DeltaApplier,
+    messages::{ControlMessage, SyncIndex, TransferDirection, TransferStart},
+    packets::{packet::Packet, ControlPacket, DataPacket, PacketType, TransferCancelReason, TransferDenyReason},
+    syncing::{IndexManager, PartialTransfer},
 };
 use filetime::{set_file_mtime, FileTime};
 use tokio::{
-    fs::{create_dir_all, rename, File},
+    fs::create_dir_all,
     io::AsyncWriteExt,
 };
 
 use crate::{
     connectivity::Connection,
-    syncing::{fs_poller::FsPoller, TransferDirection, TransferHandler, TransferStatus},
+    syncing::{changed_base_entries, fs_poller::FsPoller, TransferHandler, TransferStatus},
 };
 
 use super::SyncManager;
@@ -44,7 +46,7 @@ impl PacketHandler {
                 let data_packet: &DataPacket =
                     packet_ref.as_any().downcast_ref::<DataPacket>().unwrap();
 
-                self.handle_data_packet(sync_manager, data_packet).await;
+                self.handle_data_packet(sync_manager, data_packet, connection).await;
             }
         }
     }
@@ -55,95 +57,120 @@ impl PacketHandler {
         packet: &ControlPacket,
         connection: &Connection,
     ) {
-        match packet.control_packet_type {
-            ControlPacketType::SyncIndexRequest => {
+        let message = match ControlMessage::from_packet(packet) {
+            Ok(message) => message,
+            Err(error) => {
+                warn!("Ignoring malformed {:?} packet: {}", packet.control_packet_type, error);
+                return;
+            }
+        };
+
+        match message {
+            ControlMessage::SyncIndexRequest => {
                 let full_path = sync_manager.target.clone();
-                let mut index_manager = IndexManager::new(full_path);
+                let mut index_manager = IndexManager::with_hash_cache(full_path, sync_manager.hash_cache.clone());
                 index_manager.build().await;
 
-                let mut params = HashMap::new();
-                let index = index_manager.index;
+                let mut index = index_manager.index.clone();
 
-                //Stringify the timestamps
-                for (key, value) in index.iter() {
-                    params.insert(key.clone(), value.to_string());
+                // Report files which were deleted while offline
+                for (path, entry) in sync_manager.last_synced_index.iter() {
+                    if !index.contains_key(path) {
+                        index.insert(path.clone(), entry.to_deleted());
+                    }
                 }
 
+                let base = changed_base_entries(&sync_manager.last_synced_index, &index);
+
                 // Send sync index response
-                let mut packet = ControlPacket::new(ControlPacketType::SyncIndexResponse, params);
-                packet.job_id = sync_manager.job_id;
+                let packet = ControlMessage::SyncIndexResponse(SyncIndex { index: index.clone(), base })
+                    .to_packet(sync_manager.job_id);
                 connection.send_packet(packet).await.unwrap();
 
                 // Log
                 debug!("Sync index response sent: {:?}", index);
             }
-            ControlPacketType::SyncUpdate => {
+            ControlMessage::SyncUpdate(sync_update) => {
                 debug!("Sync update received");
-                sync_manager.handle_sync_update(packet, connection).await;
+                sync_manager.handle_sync_update(&sync_update, connection).await;
             }
-            ControlPacketType::SyncAck => {
+            ControlMessage::SyncAck(sync_ack) => {
                 info!("Sync ack received");
-                let job_id = packet.params.get("job_id").unwrap().parse::<u32>().unwrap();
-                sync_manager.job_id = Some(job_id);
+                sync_manager.job_id = Some(sync_ack.job_id);
+
+                // A dry run only waits for the plan
+                if sync_manager.dry_run {
+                    return;
+                }
 
                 // Spawn fs poller
                 let new_target = sync_manager.target.clone();
                 let new_job_id = sync_manager.job_id.unwrap();
-                let write_stream_arc = connection.write_stream.clone();
+                let sender = connection.get_sender().expect("Connection is not connected").clone();
                 let new_is_syncing = sync_manager.is_syncing.clone();
+                let new_hash_cache = sync_manager.hash_cache.clone();
 
                 tokio::spawn(async move {
-                    let mut fs_poller = FsPoller::new(new_target, new_job_id);
+                    let mut fs_poller = FsPoller::new(new_target, new_job_id, new_hash_cache);
                     fs_poller
-                        .poll(write_stream_arc, new_is_syncing)
+                        .poll(sender, new_is_syncing)
                         .await
                         .unwrap();
                 });
             }
-            ControlPacketType::SyncDeny => {
+            ControlMessage::SyncDeny(sync_deny) => {
                 info!("Sync deny received");
 
-                let reason = packet.params.get("reason").unwrap();
-                panic!("Sync denied: {}", reason)
+                // Unknown shares and paths without access are refused, that ends the sync
+                sync_manager.denied = Some(sync_deny.reason);
+                sync_manager.finished = true;
             }
-            ControlPacketType::TransferAck => {
+            ControlMessage::TransferAck(transfer_ack) => {
                 info!("Transfer ack received");
 
                 // Update status of transfer
-                let transfer_id = packet
-                    .params
-                    .get("transfer_id")
-                    .unwrap()
-                    .parse::<u32>()
-                    .unwrap();
+                let job_id = transfer_ack.job_id;
 
-                let job_id = packet.params.get("job_id").unwrap().parse::<u32>().unwrap();
-
-                let mut option_arc_transfer = sync_manager.get_transfer_by_id(transfer_id).await;
+                let mut option_arc_transfer = sync_manager.get_transfer_by_id(transfer_ack.transfer_id).await;
                 let transfer_arc = option_arc_transfer.as_mut().unwrap();
                 let mut transfer = transfer_arc.lock().await;
 
                 transfer.status = TransferStatus::Accepted;
                 transfer.job_id = job_id;
+                transfer.offset = transfer_ack.offset;
+
+                if transfer_ack.offset > 0 {
+                    info!("Continuing {} at {} bytes", transfer.file_path, transfer_ack.offset);
+                }
 
-                // Save modified date to transfer if it's a download
+                // Rebuild downloads from the local copy, upload only the changes to the server's copy
+                if transfer_ack.delta {
+                    let full_path = sync_manager.target.clone() + "/" + &transfer.file_path;
+                    let block_size = transfer.signature.as_ref().map_or(0, |signature| signature.block_size);
+
+                    match DeltaApplier::open(&full_path, block_size).await {
+                        Ok(applier) => transfer.delta = Some(applier),
+                        Err(error) => {
+                            warn!("Can not apply delta to {}, discarding transfer: {}", transfer.file_path, error);
+                            transfer.status = TransferStatus::Failed;
+                        }
+                    }
+                } else if matches!(transfer.direction, TransferDirection::ToServer) {
+                    transfer.signature = transfer_ack.signature;
+                } else {
+                    transfer.signature = None;
+                }
+
+                // Save modified date and version to transfer if it's a download
                 if matches!(transfer.direction, TransferDirection::ToClient) {
-                    transfer.original_modified_time = packet
-                        .params
-                        .get("modified_time")
-                        .unwrap()
-                        .parse::<u64>()
-                        .unwrap();
+                    transfer.original_modified_time = transfer_ack.modified_time.unwrap();
+                    transfer.key = transfer_ack.key;
                 }
 
                 // Start the transfer
                 if matches!(transfer.direction, TransferDirection::ToClient) {
                     debug!("Sending transfer start packet");
-                    let mut packet =
-                        ControlPacket::new(ControlPacketType::TransferStart, HashMap::new());
-                    packet
-                        .params
-                        .insert("job_id".to_string(), job_id.clone().to_string());
+                    let packet = ControlMessage::TransferStart(TransferStart { job_id }).to_packet(None);
                     connection
                         .send_packet(packet)
                         .await
@@ -153,47 +180,55 @@ impl PacketHandler {
                     debug!("Start sending file");
                     let transfer_handler = TransferHandler::new();
                     transfer_handler
-                        .start_sending_file(sync_manager, &transfer, connection)
+                        .start_sending_file(sync_manager, transfer_arc.clone(), &transfer, connection)
                         .await;
                 }
             }
-            ControlPacketType::TransferDeny => {
+            ControlMessage::TransferDeny(transfer_deny) => {
                 info!("Transfer deny received");
 
                 // Update status of transfer
-                let transfer_id = packet
-                    .params
-                    .get("transfer_id")
-                    .unwrap()
-                    .parse::<u32>()
-                    .unwrap();
-
-                let mut option_arc_transfer = sync_manager.get_transfer_by_id(transfer_id).await;
+                let mut option_arc_transfer = sync_manager.get_transfer_by_id(transfer_deny.transfer_id).await;
                 let transfer_arc = option_arc_transfer.as_mut().unwrap();
                 let mut transfer = transfer_arc.lock().await;
 
                 transfer.status = TransferStatus::Denied;
 
-                // Cross of checklist
-                sync_manager
-                    .write_off_sync_update_checklist(transfer.file_path.clone())
-                    .await;
+                if transfer_deny.reason == TransferDenyReason::ChecksumMismatch {
+                    warn!("Server discarded {}: checksum mismatch", transfer.file_path);
+                } else if transfer_deny.reason == TransferDenyReason::PermissionDenied {
+                    warn!("Not allowed to transfer {}", transfer.file_path);
+                }
+
+                // Cross of checklist, the file was not synced
+                sync_manager.checklist.fail(transfer.file_path.clone()).await;
 
                 //TODO: handle deny reason
             }
+            ControlMessage::TransferCancel(transfer_cancel) => {
+                let Some(transfer_arc) = sync_manager.get_transfer_by_job_id(transfer_cancel.job_id).await else {
+                    warn!("Server canceled unknown job {}", transfer_cancel.job_id);
+                    return;
+                };
+                let mut transfer = transfer_arc.lock().await;
+
+                // Nothing to undo for a transfer which already ended
+                if !matches!(transfer.status, TransferStatus::Accepted | TransferStatus::InProgress) {
+                    return;
+                }
+
+                info!("Server canceled {} ({})", transfer.file_path, transfer_cancel.reason);
+                TransferHandler::new()
+                    .drop_transfer(sync_manager, &mut transfer)
+                    .await;
+            }
             _ => {
-                //Log type
-                debug!(
-                    "Unknown control packet type: {:?}",
-                    packet.control_packet_type as u8
-                );
-                // Throw error
-                panic!("Unknown control packet type")
+                warn!("Ignoring unexpected {:?} packet", packet.control_packet_type);
             }
         }
     }
 
-    pub async fn handle_data_packet(&self, sync_manager: &mut SyncManager, packet: &DataPacket) {
+    pub async fn handle_data_packet(&self, sync_manager: &mut SyncManager, packet: &DataPacket, connection: &Connection) {
         // Get the transfer by job id
         let job_id = packet.job_id;
         let mut option_arc_transfer = sync_manager.get_transfer_by_job_id(job_id).await;
@@ -209,35 +244,75 @@ impl PacketHandler {
             create_dir_all(Path::new(&full_path).parent().unwrap())
                 .await
                 .unwrap();
-            // Create the file
-            transfer.open_file = Some(File::create(full_path + ".djinn_temp").await.unwrap());
+            // Create the file, keeping what an interrupted download already received
+            let (file, checksum) = PartialTransfer::new(&full_path)
+                .open(transfer.key.as_deref(), transfer.offset)
+                .await
+                .unwrap();
+            transfer.open_file = Some(file);
+            transfer.checksum = checksum;
             transfer.status = TransferStatus::InProgress;
         }
 
         // Write the data to the file
         if matches!(transfer.status, TransferStatus::InProgress) {
+            let full_path = sync_manager.target.clone() + "/" + &transfer.file_path;
+            let partial = PartialTransfer::new(&full_path);
+
+            // Local edits made while downloading win, the poller sends them and the server sorts out the conflict
+            let transfer_handler = TransferHandler::new();
+            if transfer_handler.local_file_changed(&transfer, &full_path).await {
+                transfer_handler
+                    .cancel_transfer(sync_manager, &mut transfer, connection, TransferCancelReason::TargetChanged)
+                    .await;
+                return;
+            }
+
+            // Delta chunks are ops, the checksum covers the file they rebuild
+            let transfer = &mut *transfer;
+            let verified = match transfer.delta.as_mut() {
+                Some(delta) if packet.has_data => {
+                    transfer.checksum.verify_chunk(packet)
+                        && delta
+                            .apply(&packet.data, transfer.open_file.as_mut().unwrap(), &mut transfer.checksum)
+                            .await
+                            .is_ok()
+                }
+                Some(delta) => delta.is_complete() && transfer.checksum.verify(packet),
+                None => transfer.checksum.verify(packet),
+            };
+
+            // Drop the transfer on a corrupted chunk or file, the rest of its packets are ignored
+            if !verified {
+                warn!("Checksum mismatch for {}, discarding transfer", transfer.file_path);
+                transfer.status = TransferStatus::Failed;
+                transfer.open_file = None;
+                transfer.delta = None;
+                partial.discard().await.unwrap();
+
+                sync_manager.checklist.fail(transfer.file_path.clone()).await;
+                return;
+            }
+
             let file = transfer.open_file.as_mut().unwrap();
             if packet.has_data {
-                file.write_all(&packet.data).await.unwrap();
+                if transfer.delta.is_none() {
+                    file.write_all(&packet.data).await.unwrap();
+                }
             } else {
                 file.flush().await.unwrap();
                 transfer.status = TransferStatus::Completed;
+                transfer.delta = None;
                 // Move file and set modified time
-                let full_path = sync_manager.target.clone() + "/" + &transfer.file_path;
-
                 let file_time = FileTime::from_unix_time(transfer.original_modified_time as i64, 0);
-                set_file_mtime(full_path.clone() + ".djinn_temp", file_time).unwrap();
+                set_file_mtime(partial.temp_path(), file_time).unwrap();
 
-                rename(full_path.clone() + ".djinn_temp", &full_path)
-                    .await
-                    .unwrap();
+                partial.finish(&full_path).await.unwrap();
 
                 // Update checklist
-                sync_manager
-                    .write_off_sync_update_checklist(transfer.file_path.clone())
-                    .await;
+                sync_manager.checklist.write_off(transfer.file_path.clone()).await;
             }
-        } else {
+        } else if !matches!(transfer.status, TransferStatus::Failed | TransferStatus::Canceled) {
             panic!("Transfer data received for transfer that is not in progress")
         }
     }
@@ -0,0 +1,116 @@
+use std::{collections::HashMap, sync::Arc};
+
+use djinn_core_lib::data::syncing::{HashCache, IndexManager, StateStore, SyncPlan};
+use tokio::sync::Mutex;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ChecklistStatus {
+    Pending,
+    Synced,
+    /// Given up, the path keeps its last synced version as the base
+    Failed,
+}
+
+/// Paths of the current sync update, shared with the tasks sending files which write themselves off when done.
+/// Once every path is written off, syncing stops and the synced index becomes the base of the next run.
+#[derive(Clone)]
+pub struct SyncChecklist {
+    target: String,
+    hash_cache: HashCache,
+    is_syncing: Arc<Mutex<bool>>,
+    paths: Arc<Mutex<HashMap<String, ChecklistStatus>>>,
+}
+
+impl SyncChecklist {
+    pub fn new(target: String, hash_cache: HashCache, is_syncing: Arc<Mutex<bool>>) -> SyncChecklist {
+        SyncChecklist {
+            target,
+            hash_cache,
+            is_syncing,
+            paths: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Starts a checklist with every path of the plan, an empty plan means the target is in sync already
+    pub async fn create(&self, sync_plan: &SyncPlan) {
+        let new_paths: HashMap<String, ChecklistStatus> = sync_plan
+            .iter()
+            .map(|(key, _)| (key.clone(), ChecklistStatus::Pending))
+            .collect();
+
+        debug!("Created sync update checklist: {:?}", new_paths);
+
+        if new_paths.is_empty() {
+            self.save_state(vec![]).await;
+            return;
+        }
+
+        *self.is_syncing.lock().await = true;
+        *self.paths.lock().await = new_paths;
+    }
+
+    /// Adds a path which the sync update produced itself, like a conflict copy
+    pub async fn add(&self, path: String) {
+        self.paths.lock().await.insert(path, ChecklistStatus::Pending);
+    }
+
+    /// Marks a path as synced
+    pub async fn write_off(&self, path: String) {
+        self.set_status(path, ChecklistStatus::Synced).await;
+    }
+
+    /// Marks a path as done without syncing it, the next run picks it up again
+    pub async fn fail(&self, path: String) {
+        self.set_status(path, ChecklistStatus::Failed).await;
+    }
+
+    async fn set_status(&self, path: String, status: ChecklistStatus) {
+        debug!("Writing off sync update checklist: {} ({:?})", path, status);
+        let mut paths = self.paths.lock().await;
+
+        // Late answers to a finished sync update must not end up in the next one
+        let Some(current) = paths.get_mut(&path) else {
+            return;
+        };
+        *current = status;
+
+        if paths.values().any(|status| *status == ChecklistStatus::Pending) {
+            return;
+        }
+
+        debug!("Sync update checklist finished: {:?}", paths);
+        let failed = paths
+            .drain()
+            .filter(|(_, status)| *status == ChecklistStatus::Failed)
+            .map(|(path, _)| path)
+            .collect();
+        drop(paths);
+
+        // Saved before the poller runs again, it sends its changes against this base
+        self.save_state(failed).await;
+        *self.is_syncing.lock().await = false;
+    }
+
+    /// Saves what the target looks like after the sync update, failed paths keep their last synced version
+    async fn save_state(&self, failed: Vec<String>) {
+        let state_store = StateStore::new(&self.target);
+
+        let mut index_manager = IndexManager::with_hash_cache(self.target.clone(), self.hash_cache.clone());
+        index_manager.build().await;
+        let mut index = index_manager.index;
+
+        if !failed.is_empty() {
+            let last_synced_index = state_store.load().await;
+            for path in failed {
+                match last_synced_index.get(&path) {
+                    Some(entry) => index.insert(path, entry.clone()),
+                    None => index.remove(&path),
+                };
+            }
+        }
+
+        if let Err(error) = state_store.save(&index).await {
+            warn!("Failed to save sync state: {}", error);
+        }
+    }
+}
@@ -1,51 +1,89 @@
-use std::{collections::HashMap, error::Error, sync::Arc};
-
-use djinn_core_lib::data::packets::{ControlPacket, ControlPacketType};
+use std::{collections::HashMap, error::Error, path::Path, sync::Arc, time::Duration};
+
+use djinn_core_lib::{
+    connectivity::BoxedStream,
+    data::{
+        messages::{ControlMessage, SyncRequest, SyncUpdate},
+        packets::TransferDenyReason,
+        syncing::{conflict_file_name, expire_partial_transfers, resolve_path, HashCache, IndexEntry, StateStore, SyncAction, SyncPlan},
+    },
+};
 
 use tokio::{
-    fs::{remove_file, self},
+    fs::{remove_file, rename, self},
     io::{BufReader, ReadHalf},
-    net::TcpStream,
     sync::Mutex,
 };
 
-use crate::connectivity::Connection;
+use crate::connectivity::{client_name, Connection};
+
+use super::{PacketHandler, SyncChecklist, Transfer, TransferHandler};
 
-use super::{PacketHandler, Transfer, TransferHandler};
+/// How long the partial file of an interrupted download is kept to continue it
+const PARTIAL_TRANSFER_RETENTION: Duration = Duration::from_secs(24 * 60 * 60);
 
 pub struct SyncManager {
     pub path: String,
     pub target: String,
     pub job_id: Option<u32>,
-    pub polling_ready: bool,
     pub transfers: Vec<Arc<Mutex<Transfer>>>,
     pub next_transfer_id: u32,
     pub is_syncing: Arc<Mutex<bool>>,
-    pub current_sync_update_checklist: HashMap<String, bool>,
+    pub checklist: SyncChecklist,
+    pub hash_cache: HashCache,
+    /// Index of the target at the end of the previous run
+    pub last_synced_index: HashMap<String, IndexEntry>,
+    /// Print the plan of the first sync update and stop, without changing any files
+    pub dry_run: bool,
+    /// Stop listening after the current packets
+    pub finished: bool,
+    /// Why the server refused to sync, if it did
+    pub denied: Option<TransferDenyReason>,
 }
 
 impl SyncManager {
     pub fn new(path: String, target: String) -> SyncManager {
+        let is_syncing = Arc::new(Mutex::new(false));
+        let hash_cache = HashCache::new();
+
         SyncManager {
             path,
+            checklist: SyncChecklist::new(target.clone(), hash_cache.clone(), is_syncing.clone()),
             target,
             job_id: None,
-            polling_ready: false,
             transfers: vec![],
             next_transfer_id: 0,
-            is_syncing: Arc::new(Mutex::new(false)),
-            current_sync_update_checklist: HashMap::new(),
+            is_syncing,
+            hash_cache,
+            last_synced_index: HashMap::new(),
+            dry_run: false,
+            finished: false,
+            denied: None,
         }
     }
 
+    pub fn with_dry_run(mut self, dry_run: bool) -> SyncManager {
+        self.dry_run = dry_run;
+        self
+    }
+
     pub async fn start(&mut self, connection: &mut Connection) -> Result<(), Box<dyn Error>> {
+        // Load what was synced before, so changes made while offline can be detected
+        self.last_synced_index = StateStore::new(&self.target).load().await;
+
+        // Downloads which were not continued in time start over
+        if !self.dry_run {
+            expire_partial_transfers(Path::new(&self.target), PARTIAL_TRANSFER_RETENTION).await;
+        }
+
         //Ask the server to start syncing
         info!("Asking server if we can sync");
 
-        let mut params = HashMap::new();
-        params.insert("path".to_string(), self.path.clone());
-
-        let packet = ControlPacket::new(ControlPacketType::SyncRequest, params);
+        let packet = ControlMessage::SyncRequest(SyncRequest {
+            path: self.path.clone(),
+            dry_run: self.dry_run,
+        })
+        .to_packet(None);
         connection.send_packet(packet).await?;
 
         //Start listening to the server for updates and commands
@@ -54,12 +92,16 @@ impl SyncManager {
         let reader_arc = connection.reader.clone();
         self.listen(reader_arc, connection).await?;
 
+        if let Some(reason) = &self.denied {
+            return Err(format!("Server denied the sync: {}", reason).into());
+        }
+
         Ok(())
     }
 
     async fn listen(
         &mut self,
-        reader: Arc<Mutex<Option<BufReader<ReadHalf<TcpStream>>>>>,
+        reader: Arc<Mutex<Option<BufReader<ReadHalf<BoxedStream>>>>>,
         connection: &mut Connection,
     ) -> Result<(), Box<dyn Error>> {
         // Open reader
@@ -69,7 +111,15 @@ impl SyncManager {
 
         loop {
             // Read packets
-            let packets = connection.packet_reader.read(reader, None).await;
+            let packets = match connection.packet_reader.read(reader, None).await {
+                Ok(packets) => packets,
+                Err(error) => {
+                    warn!("Dropping connection to server: {}", error);
+                    drop(reader_option);
+                    connection.disconnect().await?;
+                    break;
+                }
+            };
 
             if packets.is_empty() {
                 // Connection closed
@@ -83,14 +133,26 @@ impl SyncManager {
                     .handle_boxed_packet(self, packet, connection)
                     .await;
             }
+
+            if self.finished {
+                break;
+            }
         }
 
         Ok(())
     }
 
-    pub async fn handle_sync_update(&mut self, packet: &ControlPacket, connection: &Connection) {
+    pub async fn handle_sync_update(&mut self, sync_update: &SyncUpdate, connection: &Connection) {
         info!("Sync update received");
-        debug!("Sync update packet: {:?}", packet.params);
+        debug!("Sync plan: {}", sync_update.plan);
+
+        let plan = self.safe_plan(&sync_update.plan).await;
+
+        if self.dry_run {
+            self.print_plan(&plan);
+            self.finished = true;
+            return;
+        }
 
         let transfer_handler = TransferHandler::new();
 
@@ -101,91 +163,159 @@ impl SyncManager {
         }
         drop(is_syncing);
 
-        self.create_sync_update_checklist(packet.params.clone())
-            .await;
+        self.checklist.create(&plan).await;
 
-        // Loop through hashmap params
-        for (key, value) in packet.params.iter() {
+        // Loop through changes
+        for (key, plan_entry) in plan.iter() {
             let key = key.clone();
-            let value = value.clone();
 
-            if value == "GET" {
-                // Get the file from the client
-                info!("Getting file {}", key);
-                transfer_handler
-                    .start_get_file(self, key, connection)
-                    .await;
-            } else if value == "DELETE" {
-                // Delete the file from the client
-                info!("Deleting file {}", key);
-
-                // Check if file exists
-                if fs::metadata(self.target.clone() + "/" + &key).await.is_ok() {
-                    remove_file(self.target.clone() + "/" + &key)
-                        .await
-                        .expect("Failed to delete file");
+            match plan_entry.action {
+                SyncAction::Get => {
+                    // Get the file from the client
+                    info!("Getting file {} ({})", key, plan_entry.reason);
+                    transfer_handler
+                        .start_get_file(self, key, connection)
+                        .await;
+                }
+                SyncAction::Delete => {
+                    // Delete the file from the client
+                    info!("Deleting file {} ({})", key, plan_entry.reason);
+
+                    // Resolved again right before touching it, a symlink may have been swapped in since the plan arrived
+                    let full_path = match resolve_path(&self.target, &key).await {
+                        Ok(full_path) => full_path,
+                        Err(error) => {
+                            warn!("Not deleting {}: {}", key, error);
+                            self.checklist.fail(key).await;
+                            continue;
+                        }
+                    };
+
+                    // Check if file exists
+                    if fs::metadata(&full_path).await.is_ok() {
+                        if let Err(error) = remove_file(&full_path).await {
+                            warn!("Failed to delete {}: {}", key, error);
+                            self.checklist.fail(key).await;
+                            continue;
+                        }
+                    }
+
+                    self.checklist.write_off(key.clone()).await;
+                }
+                SyncAction::Conflict => {
+                    // Keep the local version as a copy next to the server version
+                    let unix_time = std::time::SystemTime::now()
+                        .duration_since(std::time::UNIX_EPOCH)
+                        .unwrap()
+                        .as_secs();
+                    let copy_path = conflict_file_name(&key, &client_name(), unix_time);
+                    info!("Conflict on {}, keeping local version as {}", key, copy_path);
+
+                    let full_path = match resolve_path(&self.target, &key).await {
+                        Ok(full_path) => full_path,
+                        Err(error) => {
+                            warn!("Not resolving conflict on {}: {}", key, error);
+                            self.checklist.fail(key).await;
+                            continue;
+                        }
+                    };
+                    let renamed = match resolve_path(&self.target, &copy_path).await {
+                        Ok(full_copy_path) => rename(&full_path, &full_copy_path).await,
+                        Err(error) => Err(std::io::Error::other(error)),
+                    };
+
+                    // Without the copy the download would overwrite the local version
+                    match renamed {
+                        Ok(()) => {
+                            self.checklist.add(copy_path.clone()).await;
+                            transfer_handler
+                                .start_put_file(self, copy_path, connection)
+                                .await;
+                            transfer_handler
+                                .start_get_file(self, key, connection)
+                                .await;
+                        }
+                        Err(error) => {
+                            warn!("Failed to keep conflict copy of {}: {}", key, error);
+                            self.checklist.fail(key).await;
+                            continue;
+                        }
+                    }
+                }
+                SyncAction::Move => {
+                    // Rename the local copy instead of downloading it again
+                    let from = plan_entry.from.clone().unwrap_or_default();
+                    info!("Moving file {} to {} ({})", from, key, plan_entry.reason);
+
+                    let (full_from_path, full_to_path) = match (resolve_path(&self.target, &from).await, resolve_path(&self.target, &key).await) {
+                        (Ok(full_from_path), Ok(full_to_path)) => (full_from_path, full_to_path),
+                        (Err(error), _) | (_, Err(error)) => {
+                            warn!("Not moving {} to {}: {}", from, key, error);
+                            self.checklist.fail(key).await;
+                            continue;
+                        }
+                    };
+                    if let Some(parent) = Path::new(&full_to_path).parent() {
+                        if let Err(error) = fs::create_dir_all(parent).await {
+                            warn!("Failed to create the directory of {}: {}", key, error);
+                            self.checklist.fail(key).await;
+                            continue;
+                        }
+                    }
+
+                    match rename(&full_from_path, &full_to_path).await {
+                        Ok(()) => self.checklist.write_off(key.clone()).await,
+                        Err(error) => {
+                            warn!("Failed to move {} to {}, downloading instead: {}", from, key, error);
+                            transfer_handler
+                                .start_get_file(self, key, connection)
+                                .await;
+                        }
+                    }
+                }
+                SyncAction::Put => {
+                    // Put the file on the client
+                    info!("Putting file {} ({})", key, plan_entry.reason);
+                    transfer_handler
+                        .start_put_file(self, key, connection)
+                        .await;
+                }
+                SyncAction::SelfDelete | SyncAction::SelfConflict | SyncAction::SelfMove => {
+                    // Carried out by the server, never meant for a client
+                    warn!("Ignoring server side action {} for {}", plan_entry.action, key);
+                    self.checklist.write_off(key.clone()).await;
                 }
-
-                self.write_off_sync_update_checklist(key.clone()).await;
-            } else if value == "PUT" {
-                // Put the file on the client
-                info!("Putting file {}", key);
-                transfer_handler
-                    .start_put_file(self, key, connection)
-                    .await;
-            } else {
-                //Log type
-                debug!("Unknown sync update type: {}", value);
             }
         }
     }
 
-    pub async fn create_sync_update_checklist(&mut self, sync_update: HashMap<String, String>) {
-        let mut new_hashmap: HashMap<String, bool> = HashMap::new();
-
-        for (key, _) in sync_update.iter() {
-            new_hashmap.insert(key.clone(), false);
-        }
-
-        self.current_sync_update_checklist = new_hashmap;
-
-        debug!(
-            "Created sync update checklist: {:?}",
-            self.current_sync_update_checklist
-        );
-
-        // If list is not empty, set syncing to true
-        if !self.current_sync_update_checklist.is_empty() {
-            let is_syncing_arc = self.is_syncing.clone();
-            let mut is_syncing = is_syncing_arc.lock().await;
-            *is_syncing = true;
-        }
-    }
+    /// Leaves out what would touch files outside the target, the server must not be able to reach them.
+    /// Actions touching the disk directly resolve their paths once more right before they do.
+    async fn safe_plan(&self, sync_plan: &SyncPlan) -> SyncPlan {
+        let mut safe_plan = SyncPlan::new();
 
-    pub async fn write_off_sync_update_checklist(&mut self, path: String) {
-        debug!("Writing off sync update checklist: {}", path);
-        self.current_sync_update_checklist.insert(path, true);
+        for (key, plan_entry) in sync_plan.iter() {
+            let from = match &plan_entry.from {
+                Some(from) => resolve_path(&self.target, from).await.map(|_| ()),
+                None => Ok(()),
+            };
 
-        // Check if all values are true
-        let mut all_true = true;
-        for (_, value) in self.current_sync_update_checklist.iter() {
-            if !value {
-                all_true = false;
+            match resolve_path(&self.target, key).await.and(from) {
+                Ok(_) => {
+                    safe_plan.entries.insert(key.clone(), plan_entry.clone());
+                }
+                Err(error) => warn!("Ignoring {} from the server: {}", plan_entry.action, error),
             }
         }
 
-        if all_true {
-            // Log checklist
-            debug!(
-                "Sync update checklist finished: {:?}",
-                self.current_sync_update_checklist
-            );
-            // Set sync update to false
-            let is_syncing_arc = self.is_syncing.clone();
-            let mut is_syncing = is_syncing_arc.lock().await;
-            *is_syncing = false;
-
-            self.current_sync_update_checklist = HashMap::new();
+        safe_plan
+    }
+
+    fn print_plan(&self, sync_plan: &SyncPlan) {
+        if sync_plan.is_empty() {
+            println!("Dry run: {} is in sync with {}", self.target, self.path);
+        } else {
+            println!("Dry run: {} planned actions, no files were changed{}", sync_plan.len(), sync_plan);
         }
     }
 
@@ -211,3 +341,19 @@ impl SyncManager {
         None
     }
 }
+
+/// Last synced entries of the files which changed since, sent along with an index so the server can detect conflicts
+pub fn changed_base_entries(
+    last_synced_index: &HashMap<String, IndexEntry>,
+    index: &HashMap<String, IndexEntry>,
+) -> HashMap<String, IndexEntry> {
+    last_synced_index
+        .iter()
+        .filter(|(path, base)| {
+            index
+                .get(*path)
+                .is_some_and(|entry| !entry.is_deleted() && !entry.is_same_as(base))
+        })
+        .map(|(path, base)| (path.clone(), base.clone()))
+        .collect()
+}
@@ -1,3 +1,4 @@
+use djinn_core_lib::data::{delta::{DeltaApplier, Signature}, messages::TransferDirection, packets::TransferChecksum, syncing::FileVersion};
 use tokio::fs::File;
 
 pub struct Transfer {
@@ -7,12 +8,19 @@ pub struct Transfer {
     pub file_path: String,
     pub original_modified_time: u64,
     pub id: u32,
-    pub job_id: u32
-}
-
-pub enum TransferDirection {
-    ToServer,
-    ToClient,
+    pub job_id: u32,
+    pub checksum: TransferChecksum,
+    /// Downloads: signature of the local copy. Uploads: signature of the server's copy.
+    pub signature: Option<Signature>,
+    /// Set when a download arrives as a delta against the local copy
+    pub delta: Option<DeltaApplier>,
+    /// Version of the file being sent, so an interrupted transfer can be continued
+    pub key: Option<String>,
+    /// Where the transfer continues, as agreed with the server
+    pub offset: u64,
+    /// Uploads: version of the file being sent. Downloads: version of the local copy being replaced.
+    /// The transfer is canceled when the local file no longer matches it.
+    pub local_version: Option<FileVersion>
 }
 
 pub enum TransferStatus {
@@ -20,7 +28,9 @@ pub enum TransferStatus {
     Accepted,
     Denied,
     InProgress,
-    Completed
+    Completed,
+    Failed,
+    Canceled
 }
 
 impl Transfer {
@@ -32,7 +42,13 @@ impl Transfer {
             file_path,
             original_modified_time: 0,
             id,
-            job_id: 0
+            job_id: 0,
+            checksum: TransferChecksum::new(),
+            signature: None,
+            delta: None,
+            key: None,
+            offset: 0,
+            local_version: None
         }
     }
 }
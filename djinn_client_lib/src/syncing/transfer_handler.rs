@@ -1,13 +1,16 @@
-use std::{collections::HashMap, sync::Arc};
+use std::sync::Arc;
 
-use djinn_core_lib::data::packets::{
-    packet::Packet, ControlPacket, ControlPacketType, DataPacketGenerator,
+use djinn_core_lib::data::{
+    delta::{DeltaPacketGenerator, Signature, DELTA_MIN_SIZE},
+    messages::{ControlMessage, TransferCancel, TransferDirection, TransferRequest},
+    packets::{DataPacket, DataPacketGenerator, TransferCancelReason},
+    syncing::{transfer_key, FileVersion, PartialTransfer},
 };
-use tokio::{fs, io::AsyncWriteExt, sync::Mutex};
+use tokio::{fs, sync::Mutex};
 
-use crate::{connectivity::Connection, syncing::TransferDirection};
+use crate::connectivity::Connection;
 
-use super::{SyncManager, Transfer};
+use super::{SyncManager, Transfer, TransferStatus};
 
 pub struct TransferHandler {}
 
@@ -25,22 +28,34 @@ impl TransferHandler {
         let transfer_id = sync_manager.next_transfer_id;
         sync_manager.next_transfer_id += 1;
 
-        sync_manager
-            .transfers
-            .push(Arc::new(Mutex::new(Transfer::new(
-                TransferDirection::ToClient,
-                transfer_id,
-                path.clone(),
-            ))));
+        // Let the server send only what changed if there is a local copy
+        let full_path = sync_manager.target.clone() + "/" + &path;
+        let signature = self.local_signature(&full_path).await;
+
+        // Or only the rest if a previous download was interrupted
+        let (key, offset) = match PartialTransfer::new(&full_path).existing().await {
+            Some((key, offset)) => (Some(key), offset),
+            None => (None, 0),
+        };
 
-        let mut params = HashMap::new();
-        params.insert("file_path".to_string(), path.clone());
-        params.insert("transfer_id".to_string(), transfer_id.to_string());
-        params.insert("direction".to_string(), "toClient".to_string());
+        let mut transfer = Transfer::new(TransferDirection::ToClient, transfer_id, path.clone());
+        transfer.signature = signature.clone();
+        transfer.local_version = FileVersion::of(&full_path).await.ok();
+        sync_manager.transfers.push(Arc::new(Mutex::new(transfer)));
 
         debug!("Sending transfer request packet for {}", path);
 
-        let packet = ControlPacket::new(ControlPacketType::TransferRequest, params);
+        let packet = ControlMessage::TransferRequest(TransferRequest {
+            file_path: path,
+            transfer_id,
+            direction: TransferDirection::ToClient,
+            modified_time: None,
+            delta: false,
+            signature,
+            key,
+            offset,
+        })
+        .to_packet(None);
         connection.send_packet(packet).await.unwrap();
     }
 
@@ -53,79 +68,179 @@ impl TransferHandler {
         let transfer_id = sync_manager.next_transfer_id;
         sync_manager.next_transfer_id += 1;
 
-        sync_manager
-            .transfers
-            .push(Arc::new(Mutex::new(Transfer::new(
-                TransferDirection::ToServer,
-                transfer_id,
-                path.clone(),
-            ))));
+        let full_path = sync_manager.target.clone() + "/" + &path;
 
-        let mut params = HashMap::new();
-        params.insert("file_path".to_string(), path.clone());
-        params.insert("transfer_id".to_string(), transfer_id.to_string());
-        params.insert("direction".to_string(), "toServer".to_string());
+        let mut transfer = Transfer::new(TransferDirection::ToServer, transfer_id, path.clone());
+        transfer.local_version = FileVersion::of(&full_path).await.ok();
+        sync_manager.transfers.push(Arc::new(Mutex::new(transfer)));
 
         // Get modified time
-        let full_path = sync_manager.target.clone() + "/" + &path;
-        let modified_time = fs::metadata(&full_path)
-            .await
-            .expect("AAAA")
+        let metadata = fs::metadata(&full_path).await.expect("AAAA");
+        let modified_time = metadata
             .modified()
             .unwrap()
             .duration_since(std::time::UNIX_EPOCH)
             .unwrap()
             .as_secs();
 
-        params.insert("modified_time".to_string(), modified_time.to_string());
-
-        let packet = ControlPacket::new(ControlPacketType::TransferRequest, params);
+        // The server continues a partial upload of the same version
+        let key = transfer_key(&path, modified_time, metadata.len());
+
+        let packet = ControlMessage::TransferRequest(TransferRequest {
+            file_path: path,
+            transfer_id,
+            direction: TransferDirection::ToServer,
+            modified_time: Some(modified_time),
+            delta: true,
+            signature: None,
+            key: Some(key),
+            offset: 0,
+        })
+        .to_packet(None);
         connection.send_packet(packet).await.unwrap();
     }
 
+    /// Sends the file of an accepted upload next to the other uploads, so packets keep being handled meanwhile
     pub async fn start_sending_file(
         &self,
         sync_manager: &mut SyncManager,
+        transfer_arc: Arc<Mutex<Transfer>>,
         transfer: &Transfer,
         connection: &Connection,
     ) {
         // Get the file path from the job
         let file_path = transfer.file_path.clone();
         let full_path = format!("{}/{}", sync_manager.target, file_path);
+        let job_id = transfer.job_id;
+        let local_version = transfer.local_version;
+        let compression = connection.compression();
+
+        // Open da file, only sending the changes if the server has a copy or the rest if it has a partial one
+        let iterator: Box<dyn Iterator<Item = DataPacket> + Send> = match &transfer.signature {
+            Some(signature) => {
+                debug!("Sending {} as a delta", file_path);
+                Box::new(DeltaPacketGenerator::new(job_id, full_path.clone(), signature.clone()).with_compression(compression).iter())
+            }
+            None => Box::new(
+                DataPacketGenerator::new(job_id, full_path.clone())
+                    .with_offset(transfer.offset)
+                    .with_compression(compression)
+                    .iter(),
+            ),
+        };
+
+        let sender = connection.get_sender().expect("Connection is not connected").clone();
+        let checklist = sync_manager.checklist.clone();
+
+        tokio::spawn(async move {
+            // Wait for a free transfer slot of the connection
+            let stream = match sender.open_stream().await {
+                Ok(stream) => stream,
+                Err(error) => {
+                    warn!("Failed to send {}: {}", file_path, error);
+                    return;
+                }
+            };
+
+            debug!("Sending file loop {}", file_path);
+
+            for packet in iterator {
+                // Give up if the file changed since it was requested, the poller picks up the new version
+                if FileVersion::of(&full_path).await.ok() != local_version {
+                    info!("Canceling {} ({})", file_path, TransferCancelReason::SourceChanged);
+
+                    let packet = ControlMessage::TransferCancel(TransferCancel {
+                        job_id,
+                        reason: TransferCancelReason::SourceChanged,
+                    })
+                    .to_packet(None);
+                    if let Err(error) = sender.send_control(packet) {
+                        warn!("Failed to cancel {}: {}", file_path, error);
+                    }
+
+                    transfer_arc.lock().await.status = TransferStatus::Canceled;
+                    checklist.fail(file_path).await;
+                    return;
+                }
+
+                if let Err(error) = stream.send(packet).await {
+                    warn!("Failed to send {}: {}", file_path, error);
+                    checklist.fail(file_path).await;
+                    return;
+                }
+            }
 
-        // Open da file
-        let packet_generator = DataPacketGenerator::new(transfer.job_id, full_path);
-        let iterator = packet_generator.iter();
+            // Only done once the server has all of it
+            if let Err(error) = stream.finish().await {
+                warn!("Failed to send {}: {}", file_path, error);
+                checklist.fail(file_path).await;
+                return;
+            }
 
-        // Get connection read_stream
-        let write_stream_arc = connection.write_stream.clone();
+            transfer_arc.lock().await.status = TransferStatus::Completed;
 
-        debug!("Sending file loop {}", file_path);
+            // Update sync
+            checklist.write_off(file_path).await;
+        });
+    }
 
-        for packet in iterator {
-            let mut option_write_stream = write_stream_arc.lock().await;
+    /// Whether the local file is no longer the version the transfer started with
+    pub async fn local_file_changed(&self, transfer: &Transfer, full_path: &str) -> bool {
+        FileVersion::of(full_path).await.ok() != transfer.local_version
+    }
 
-            if option_write_stream.is_none() {
-                panic!("Write stream is none");
-            }
+    /// Gives up a transfer and tells the server, a download drops what it received so far
+    pub async fn cancel_transfer(
+        &self,
+        sync_manager: &mut SyncManager,
+        transfer: &mut Transfer,
+        connection: &Connection,
+        reason: TransferCancelReason,
+    ) {
+        info!("Canceling {} ({})", transfer.file_path, reason);
 
-            let write_stream = option_write_stream.as_mut().unwrap();
+        let packet = ControlMessage::TransferCancel(TransferCancel {
+            job_id: transfer.job_id,
+            reason,
+        })
+        .to_packet(None);
+        connection.send_packet(packet).await.unwrap();
 
-            let buffer = &packet.to_buffer();
-            write_stream
-                .write_all(buffer)
-                .await
-                .expect("Failed to write to stream")
+        self.drop_transfer(sync_manager, transfer).await;
+    }
+
+    /// Marks a transfer as canceled and removes its partial file if it was a download
+    pub async fn drop_transfer(&self, sync_manager: &mut SyncManager, transfer: &mut Transfer) {
+        transfer.status = TransferStatus::Canceled;
+        transfer.open_file = None;
+        transfer.delta = None;
+
+        if matches!(transfer.direction, TransferDirection::ToClient) {
+            let full_path = sync_manager.target.clone() + "/" + &transfer.file_path;
+            if let Err(error) = PartialTransfer::new(&full_path).discard().await {
+                warn!("Failed to remove partial download of {}: {}", transfer.file_path, error);
+            }
         }
 
-        connection
-            .flush()
-            .await
-            .expect("Failed to flush connection");
+        // Canceled transfers are picked up again by the next run
+        sync_manager.checklist.fail(transfer.file_path.clone()).await;
+    }
+
+    /// Signature of the local copy of a file, None when it is missing or too small for a delta
+    async fn local_signature(&self, full_path: &str) -> Option<Signature> {
+        let metadata = fs::metadata(full_path).await.ok()?;
+
+        if !metadata.is_file() || metadata.len() < DELTA_MIN_SIZE {
+            return None;
+        }
 
-        // Update sync
-        sync_manager
-            .write_off_sync_update_checklist(transfer.file_path.clone())
-            .await;
+        let path = full_path.to_string();
+        match tokio::task::spawn_blocking(move || Signature::generate(&path)).await {
+            Ok(Ok(signature)) => Some(signature),
+            _ => {
+                warn!("Failed to read {}, downloading it whole", full_path);
+                None
+            }
+        }
     }
 }
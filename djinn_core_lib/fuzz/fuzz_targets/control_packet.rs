@@ -0,0 +1,28 @@
+#![no_main]
+
+use std::collections::HashMap;
+
+use djinn_core_lib::data::{
+    messages::ControlMessage,
+    packets::{packet::Packet, ControlPacket, ControlPacketType},
+};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let mut packet = ControlPacket::new(ControlPacketType::None, HashMap::new());
+
+    if packet.fill_from_buffer(data).is_err() {
+        return;
+    }
+
+    // A packet which decoded once has to survive a round trip unchanged
+    let buffer = packet.to_buffer();
+    let mut decoded = ControlPacket::new(ControlPacketType::None, HashMap::new());
+    decoded.fill_from_buffer(&buffer).expect("re-encoded control packet does not decode");
+
+    assert_eq!(decoded.control_packet_type, packet.control_packet_type);
+    assert_eq!(decoded.job_id, packet.job_id);
+    assert_eq!(decoded.params, packet.params);
+
+    let _ = ControlMessage::from_packet(&packet);
+});
@@ -0,0 +1,22 @@
+#![no_main]
+
+use djinn_core_lib::data::packets::{packet::Packet, DataPacket};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let mut packet = DataPacket::new(0, Vec::new(), 0);
+
+    if packet.fill_from_buffer(data).is_err() {
+        return;
+    }
+
+    // A packet which decoded once has to survive a round trip unchanged
+    let buffer = packet.to_buffer();
+    let mut decoded = DataPacket::new(0, Vec::new(), 0);
+    decoded.fill_from_buffer(&buffer).expect("re-encoded data packet does not decode");
+
+    assert_eq!(decoded.job_id, packet.job_id);
+    assert_eq!(decoded.packet_number, packet.packet_number);
+    assert_eq!(decoded.data, packet.data);
+    assert_eq!(decoded.has_data, packet.has_data);
+});
@@ -0,0 +1,11 @@
+#![no_main]
+
+use djinn_core_lib::data::packets::packet::deserialize_packet;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(packet) = deserialize_packet(data) {
+        // Whatever decodes has to encode again
+        let _ = packet.to_buffer();
+    }
+});
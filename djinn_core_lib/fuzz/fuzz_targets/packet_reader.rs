@@ -0,0 +1,65 @@
+#![no_main]
+
+use std::{
+    collections::VecDeque,
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use djinn_core_lib::data::packets::PacketReader;
+use libfuzzer_sys::fuzz_target;
+use tokio::io::{AsyncRead, BufReader, ReadBuf};
+
+/// Hands out the stream in the chunk sizes picked by the fuzzer, like a socket would
+struct ChunkedStream {
+    chunks: VecDeque<Vec<u8>>,
+}
+
+impl AsyncRead for ChunkedStream {
+    fn poll_read(mut self: Pin<&mut Self>, _cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        if let Some(mut chunk) = self.chunks.pop_front() {
+            let length = chunk.len().min(buf.remaining());
+            buf.put_slice(&chunk[..length]);
+
+            if length < chunk.len() {
+                self.chunks.push_front(chunk.split_off(length));
+            }
+        }
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+// The first byte picks the chunk size and the second one the max packets per read,
+// the rest is the byte stream coming from the peer
+fuzz_target!(|data: &[u8]| {
+    if data.len() < 2 {
+        return;
+    }
+
+    let chunk_size = data[0] as usize + 1;
+    let max_packets = match data[1] {
+        0 => None,
+        max_packets => Some(max_packets as usize),
+    };
+    let chunks = data[2..].chunks(chunk_size).map(|chunk| chunk.to_vec()).collect();
+
+    let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+
+    runtime.block_on(async {
+        let mut reader = BufReader::new(ChunkedStream { chunks });
+        let mut packet_reader = PacketReader::new();
+
+        // Keep reading until the stream ends or turns out to be garbage
+        while let Ok(packets) = packet_reader.read(&mut reader, max_packets).await {
+            if packets.is_empty() {
+                break;
+            }
+
+            for packet in packets {
+                let _ = packet.to_buffer();
+            }
+        }
+    });
+});
@@ -0,0 +1,5 @@
+mod stream;
+pub use stream::BoxedStream;
+pub use stream::Stream;
+
+pub mod tls;
@@ -0,0 +1,8 @@
+use tokio::io::{AsyncRead, AsyncWrite};
+
+/// Connection to a peer, either plain TCP or TLS on top of it
+pub trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Stream for T {}
+
+pub type BoxedStream = Box<dyn Stream>;
@@ -0,0 +1,273 @@
+use std::{fs::File, io, io::BufReader, sync::Arc};
+
+use sha2::{Digest, Sha256};
+use tokio::net::TcpStream;
+use tokio_rustls::{
+    rustls::{
+        client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+        crypto::{ring, verify_tls12_signature, verify_tls13_signature, CryptoProvider},
+        pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime},
+        DigitallySignedStruct, Error, RootCertStore, SignatureScheme,
+    },
+    TlsAcceptor, TlsConnector,
+};
+
+use super::BoxedStream;
+
+pub use tokio_rustls::rustls::{ClientConfig, ServerConfig};
+
+const FINGERPRINT_SIZE: usize = 32;
+
+/// Server side TLS with the certificate chain and private key from the given PEM files
+pub fn server_config(certificate_path: &str, key_path: &str) -> io::Result<Arc<ServerConfig>> {
+    let certificates = load_certificates(certificate_path)?;
+    let key = load_private_key(key_path)?;
+
+    let config = ServerConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()
+        .map_err(invalid_data)?
+        .with_no_client_auth()
+        .with_single_cert(certificates, key)
+        .map_err(invalid_data)?;
+
+    Ok(Arc::new(config))
+}
+
+/// Client side TLS which trusts servers with a certificate signed by one of the CAs in the PEM file
+pub fn client_config_with_ca(ca_path: &str) -> io::Result<Arc<ClientConfig>> {
+    let mut roots = RootCertStore::empty();
+    for certificate in load_certificates(ca_path)? {
+        roots.add(certificate).map_err(invalid_data)?;
+    }
+
+    let config = ClientConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()
+        .map_err(invalid_data)?
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+
+    Ok(Arc::new(config))
+}
+
+/// Client side TLS which only trusts the server certificate with the given SHA-256 fingerprint,
+/// for self-signed certificates
+pub fn client_config_with_fingerprint(fingerprint: &str) -> io::Result<Arc<ClientConfig>> {
+    let provider = provider();
+    let verifier = PinnedCertificate {
+        fingerprint: parse_fingerprint(fingerprint)?,
+        provider: provider.clone(),
+    };
+
+    let config = ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .map_err(invalid_data)?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(verifier))
+        .with_no_client_auth();
+
+    Ok(Arc::new(config))
+}
+
+pub async fn connect(config: Arc<ClientConfig>, host: &str, stream: TcpStream) -> io::Result<BoxedStream> {
+    let server_name = ServerName::try_from(host.to_string()).map_err(invalid_data)?;
+    let stream = TlsConnector::from(config).connect(server_name, stream).await?;
+
+    Ok(Box::new(stream))
+}
+
+pub async fn accept(config: Arc<ServerConfig>, stream: TcpStream) -> io::Result<BoxedStream> {
+    let stream = TlsAcceptor::from(config).accept(stream).await?;
+
+    Ok(Box::new(stream))
+}
+
+/// SHA-256 of the certificate like `openssl x509 -fingerprint -sha256` prints it
+pub fn certificate_fingerprint(certificate: &[u8]) -> String {
+    Sha256::digest(certificate)
+        .iter()
+        .map(|byte| format!("{:02X}", byte))
+        .collect::<Vec<String>>()
+        .join(":")
+}
+
+/// Accepts the fingerprint with or without colons, in either case
+fn parse_fingerprint(fingerprint: &str) -> io::Result<[u8; FINGERPRINT_SIZE]> {
+    let hex: String = fingerprint.chars().filter(|c| *c != ':').collect();
+    let invalid = || invalid_data(format!("{} is not a SHA-256 fingerprint", fingerprint));
+
+    if hex.len() != FINGERPRINT_SIZE * 2 || !hex.is_ascii() {
+        return Err(invalid());
+    }
+
+    let mut bytes = [0; FINGERPRINT_SIZE];
+    for (index, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[index * 2..index * 2 + 2], 16).map_err(|_| invalid())?;
+    }
+
+    Ok(bytes)
+}
+
+fn load_certificates(path: &str) -> io::Result<Vec<CertificateDer<'static>>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let certificates = rustls_pemfile::certs(&mut reader).collect::<io::Result<Vec<_>>>()?;
+
+    if certificates.is_empty() {
+        return Err(invalid_data(format!("No certificates found in {}", path)));
+    }
+
+    Ok(certificates)
+}
+
+fn load_private_key(path: &str) -> io::Result<PrivateKeyDer<'static>> {
+    let mut reader = BufReader::new(File::open(path)?);
+
+    rustls_pemfile::private_key(&mut reader)?
+        .ok_or_else(|| invalid_data(format!("No private key found in {}", path)))
+}
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(ring::default_provider())
+}
+
+fn invalid_data(error: impl ToString) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error.to_string())
+}
+
+/// Trusts exactly one certificate instead of a CA, the handshake signatures are still checked
+#[derive(Debug)]
+struct PinnedCertificate {
+    fingerprint: [u8; FINGERPRINT_SIZE],
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for PinnedCertificate {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, Error> {
+        if Sha256::digest(end_entity.as_ref()).as_slice() != self.fingerprint {
+            return Err(Error::General(format!(
+                "server certificate {} does not match the pinned fingerprint",
+                certificate_fingerprint(end_entity.as_ref())
+            )));
+        }
+
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        certificate: &CertificateDer<'_>,
+        signature: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, Error> {
+        verify_tls12_signature(message, certificate, signature, &self.provider.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        certificate: &CertificateDer<'_>,
+        signature: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, Error> {
+        verify_tls13_signature(message, certificate, signature, &self.provider.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::*;
+
+    /// Writes a self-signed certificate for localhost and its key, returning their paths and the fingerprint
+    fn self_signed(name: &str) -> (String, String, String) {
+        let certified_key = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let directory = std::env::temp_dir();
+        let certificate_path: PathBuf = directory.join(format!("djinn_test_tls_{}.crt", name));
+        let key_path: PathBuf = directory.join(format!("djinn_test_tls_{}.key", name));
+
+        std::fs::write(&certificate_path, certified_key.cert.pem()).unwrap();
+        std::fs::write(&key_path, certified_key.key_pair.serialize_pem()).unwrap();
+
+        (
+            certificate_path.to_str().unwrap().to_string(),
+            key_path.to_str().unwrap().to_string(),
+            certificate_fingerprint(certified_key.cert.der()),
+        )
+    }
+
+    /// Echoes one message over TLS, returning what the client got back
+    async fn echo(server: Arc<ServerConfig>, client: Arc<ClientConfig>) -> io::Result<Vec<u8>> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let address = listener.local_addr()?;
+
+        tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            if let Ok(mut stream) = accept(server, socket).await {
+                let mut buffer = [0; 5];
+                stream.read_exact(&mut buffer).await.unwrap();
+                stream.write_all(&buffer).await.unwrap();
+                stream.flush().await.unwrap();
+            }
+        });
+
+        let mut stream = connect(client, "localhost", TcpStream::connect(address).await?).await?;
+        stream.write_all(b"djinn").await?;
+        stream.flush().await?;
+
+        let mut buffer = vec![0; 5];
+        stream.read_exact(&mut buffer).await?;
+        Ok(buffer)
+    }
+
+    #[test]
+    fn test_parse_fingerprint() {
+        let fingerprint = certificate_fingerprint(b"certificate");
+        let expected: [u8; FINGERPRINT_SIZE] = Sha256::digest(b"certificate").into();
+
+        assert_eq!(parse_fingerprint(&fingerprint).unwrap(), expected);
+        assert_eq!(parse_fingerprint(&fingerprint.replace(':', "").to_lowercase()).unwrap(), expected);
+        assert!(parse_fingerprint("AB:CD").is_err());
+        assert!(parse_fingerprint(&"zz".repeat(FINGERPRINT_SIZE)).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_pinned_fingerprint() {
+        let (certificate, key, fingerprint) = self_signed("pinned");
+        let server = server_config(&certificate, &key).unwrap();
+
+        let client = client_config_with_fingerprint(&fingerprint).unwrap();
+        assert_eq!(echo(server.clone(), client).await.unwrap(), b"djinn");
+
+        let other = client_config_with_fingerprint(&certificate_fingerprint(b"other")).unwrap();
+        assert!(echo(server, other).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_ca_certificate() {
+        let (certificate, key, _) = self_signed("ca");
+        let server = server_config(&certificate, &key).unwrap();
+
+        // A self-signed certificate is its own CA
+        let client = client_config_with_ca(&certificate).unwrap();
+        assert_eq!(echo(server.clone(), client).await.unwrap(), b"djinn");
+
+        let (other_certificate, _, _) = self_signed("ca_other");
+        let other = client_config_with_ca(&other_certificate).unwrap();
+        assert!(echo(server, other).await.is_err());
+    }
+}
@@ -0,0 +1,150 @@
+use std::io::{self, SeekFrom};
+
+use tokio::{
+    fs::File,
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+};
+
+use crate::data::packets::TransferChecksum;
+
+use super::{DeltaDecoder, DeltaOp};
+
+const COPY_BUFFER_SIZE: usize = 65536;
+
+/// Rebuilds a file on the receiving side from delta ops and the existing copy the signature
+/// was made of
+pub struct DeltaApplier {
+    basis: File,
+    basis_size: u64,
+    block_size: usize,
+    decoder: DeltaDecoder,
+}
+
+impl DeltaApplier {
+    pub async fn open(basis_path: &str, block_size: usize) -> io::Result<DeltaApplier> {
+        let basis = File::open(basis_path).await?;
+        let basis_size = basis.metadata().await?.len();
+
+        Ok(DeltaApplier {
+            basis,
+            basis_size,
+            block_size,
+            decoder: DeltaDecoder::new(),
+        })
+    }
+
+    /// Writes the file contents described by the data of one packet, adding them to the transfer
+    /// checksum so the end packet can be verified as usual
+    pub async fn apply(&mut self, data: &[u8], output: &mut File, checksum: &mut TransferChecksum) -> io::Result<()> {
+        self.decoder.feed(data);
+
+        while let Some(op) = self.decoder.next_op()? {
+            match op {
+                DeltaOp::Literal(data) => {
+                    output.write_all(&data).await?;
+                    checksum.update(&data);
+                }
+                DeltaOp::Copy { start_block, count } => {
+                    self.copy_blocks(start_block, count, output, checksum).await?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Whether the delta ended on an op boundary
+    pub fn is_complete(&self) -> bool {
+        self.decoder.is_empty()
+    }
+
+    async fn copy_blocks(
+        &mut self,
+        start_block: u32,
+        count: u32,
+        output: &mut File,
+        checksum: &mut TransferChecksum,
+    ) -> io::Result<()> {
+        let start = start_block as u64 * self.block_size as u64;
+        let len = count as u64 * self.block_size as u64;
+
+        if start + len > self.basis_size {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "delta copies past the end of the file"));
+        }
+
+        self.basis.seek(SeekFrom::Start(start)).await?;
+
+        let mut buffer = vec![0; COPY_BUFFER_SIZE];
+        let mut remaining = len as usize;
+
+        while remaining > 0 {
+            let chunk = &mut buffer[..remaining.min(COPY_BUFFER_SIZE)];
+            self.basis.read_exact(chunk).await?;
+            output.write_all(chunk).await?;
+            checksum.update(chunk);
+
+            remaining -= chunk.len();
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use crate::data::{
+        delta::{DeltaPacketGeneratorIterator, Signature},
+        packets::DataPacket,
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_apply() {
+        let dir = env::temp_dir();
+        let basis_path = dir.join("delta_applier_basis.txt");
+        let output_path = dir.join("delta_applier_output.txt");
+
+        let basis: Vec<u8> = (0..20_000).map(|i| (i * 7 % 251) as u8).collect();
+        let mut file = basis.clone();
+        file[9_000] = 0;
+        file.extend([1, 2, 3]);
+        tokio::fs::write(&basis_path, &basis).await.unwrap();
+
+        let signature = Signature::from_reader(basis.as_slice(), 2048).unwrap();
+        let packets: Vec<DataPacket> = DeltaPacketGeneratorIterator::new(1, file.as_slice(), &signature).collect();
+
+        let mut applier = DeltaApplier::open(basis_path.to_str().unwrap(), signature.block_size).await.unwrap();
+        let mut output = File::create(&output_path).await.unwrap();
+        let mut checksum = TransferChecksum::new();
+
+        for packet in &packets {
+            if packet.has_data {
+                assert!(checksum.verify_chunk(packet));
+                applier.apply(&packet.data, &mut output, &mut checksum).await.unwrap();
+            } else {
+                assert!(checksum.verify(packet));
+            }
+        }
+        output.flush().await.unwrap();
+
+        assert!(applier.is_complete());
+        assert_eq!(tokio::fs::read(&output_path).await.unwrap(), file);
+    }
+
+    #[tokio::test]
+    async fn test_copy_past_end() {
+        let basis_path = env::temp_dir().join("delta_applier_short_basis.txt");
+        tokio::fs::write(&basis_path, [0; 4096]).await.unwrap();
+
+        let mut data = vec![];
+        DeltaOp::encode_copy(1, 2, &mut data);
+
+        let mut applier = DeltaApplier::open(basis_path.to_str().unwrap(), 2048).await.unwrap();
+        let mut output = File::create(env::temp_dir().join("delta_applier_short_output.txt")).await.unwrap();
+
+        assert!(applier.apply(&data, &mut output, &mut TransferChecksum::new()).await.is_err());
+    }
+}
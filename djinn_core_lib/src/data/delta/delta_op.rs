@@ -0,0 +1,133 @@
+use std::io;
+
+const LITERAL: u8 = 0;
+const COPY: u8 = 1;
+
+// Op type plus length
+const LITERAL_HEADER_SIZE: usize = 5;
+// Op type plus first block and block count
+const COPY_SIZE: usize = 9;
+
+/// Largest literal the sender emits, which keeps the receiver's buffer around one data packet
+pub const MAX_LITERAL_SIZE: usize = 60000;
+
+/// Instruction in a delta stream. Literals carry new data, copies take whole blocks
+/// from the receiver's existing copy of the file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DeltaOp {
+    Literal(Vec<u8>),
+    Copy { start_block: u32, count: u32 },
+}
+
+impl DeltaOp {
+    pub fn encode_literal(data: &[u8], buffer: &mut Vec<u8>) {
+        buffer.push(LITERAL);
+        buffer.extend((data.len() as u32).to_be_bytes());
+        buffer.extend(data);
+    }
+
+    pub fn encode_copy(start_block: u32, count: u32, buffer: &mut Vec<u8>) {
+        buffer.push(COPY);
+        buffer.extend(start_block.to_be_bytes());
+        buffer.extend(count.to_be_bytes());
+    }
+}
+
+/// Splits the delta stream back into ops, which may be cut across data packets
+#[derive(Default)]
+pub struct DeltaDecoder {
+    buffer: Vec<u8>,
+}
+
+impl DeltaDecoder {
+    pub fn new() -> DeltaDecoder {
+        DeltaDecoder::default()
+    }
+
+    pub fn feed(&mut self, data: &[u8]) {
+        self.buffer.extend_from_slice(data);
+    }
+
+    /// Whether every op fed so far has been read
+    pub fn is_empty(&self) -> bool {
+        self.buffer.is_empty()
+    }
+
+    /// Next complete op, or None if more data is needed
+    pub fn next_op(&mut self) -> io::Result<Option<DeltaOp>> {
+        let Some(&op_type) = self.buffer.first() else {
+            return Ok(None);
+        };
+
+        match op_type {
+            LITERAL => {
+                if self.buffer.len() < LITERAL_HEADER_SIZE {
+                    return Ok(None);
+                }
+
+                let len = u32::from_be_bytes([self.buffer[1], self.buffer[2], self.buffer[3], self.buffer[4]]) as usize;
+                if len > MAX_LITERAL_SIZE {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, "delta literal too large"));
+                }
+
+                if self.buffer.len() < LITERAL_HEADER_SIZE + len {
+                    return Ok(None);
+                }
+
+                let data = self.buffer[LITERAL_HEADER_SIZE..LITERAL_HEADER_SIZE + len].to_vec();
+                self.buffer.drain(..LITERAL_HEADER_SIZE + len);
+
+                Ok(Some(DeltaOp::Literal(data)))
+            }
+            COPY => {
+                if self.buffer.len() < COPY_SIZE {
+                    return Ok(None);
+                }
+
+                let start_block = u32::from_be_bytes([self.buffer[1], self.buffer[2], self.buffer[3], self.buffer[4]]);
+                let count = u32::from_be_bytes([self.buffer[5], self.buffer[6], self.buffer[7], self.buffer[8]]);
+                self.buffer.drain(..COPY_SIZE);
+
+                Ok(Some(DeltaOp::Copy { start_block, count }))
+            }
+            _ => Err(io::Error::new(io::ErrorKind::InvalidData, format!("unknown delta op {}", op_type))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_split_ops() {
+        let mut stream = vec![];
+        DeltaOp::encode_literal(&[1, 2, 3], &mut stream);
+        DeltaOp::encode_copy(4, 2, &mut stream);
+
+        let mut decoder = DeltaDecoder::new();
+        let mut ops = vec![];
+
+        // Feed one byte at a time, ops only come out once complete
+        for byte in stream {
+            decoder.feed(&[byte]);
+            while let Some(op) = decoder.next_op().unwrap() {
+                ops.push(op);
+            }
+        }
+
+        assert_eq!(ops, vec![DeltaOp::Literal(vec![1, 2, 3]), DeltaOp::Copy { start_block: 4, count: 2 }]);
+        assert!(decoder.is_empty());
+    }
+
+    #[test]
+    fn test_decode_invalid() {
+        let mut decoder = DeltaDecoder::new();
+        decoder.feed(&[7, 0, 0]);
+        assert!(decoder.next_op().is_err());
+
+        let mut decoder = DeltaDecoder::new();
+        decoder.feed(&[LITERAL, 255, 255, 255, 255]);
+        assert!(decoder.next_op().is_err());
+    }
+}
@@ -0,0 +1,362 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufReader, Read},
+};
+
+use crate::data::packets::{DataPacket, TransferChecksum};
+
+use super::{strong_checksum, DeltaOp, RollingChecksum, Signature, StrongChecksum, MAX_LITERAL_SIZE};
+
+const PACKET_DATA_SIZE: usize = 60000;
+const READ_SIZE: usize = 65536;
+
+/// Sends a file as a delta against the receiver's signature. The packets carry the delta ops,
+/// the end packet the checksum of the whole file like a normal transfer.
+pub struct DeltaPacketGenerator {
+    job_id: u32,
+    path: String,
+    signature: Signature,
+    compress: bool,
+}
+
+impl DeltaPacketGenerator {
+    pub fn new(job_id: u32, path: String, signature: Signature) -> Self {
+        DeltaPacketGenerator { job_id, path, signature, compress: false }
+    }
+
+    /// Compresses the packets when the receiver agreed to it
+    pub fn with_compression(mut self, compress: bool) -> Self {
+        self.compress = compress;
+        self
+    }
+
+    pub fn iter(&self) -> DeltaPacketGeneratorIterator<BufReader<File>> {
+        let file = File::open(self.path.clone()).unwrap();
+        let buf_reader = BufReader::new(file);
+        let mut iterator = DeltaPacketGeneratorIterator::new(self.job_id, buf_reader, &self.signature);
+        iterator.compress = self.compress;
+        iterator
+    }
+}
+
+pub struct DeltaPacketGeneratorIterator<R: Read> {
+    job_id: u32,
+    reader: R,
+    block_size: usize,
+    /// Weak checksum -> index and strong checksum of the blocks the receiver has
+    blocks: HashMap<u32, Vec<(u32, StrongChecksum)>>,
+    /// Unsent part of the file, the window starts at `position`
+    buffer: Vec<u8>,
+    position: usize,
+    literal_start: usize,
+    rolling: Option<RollingChecksum>,
+    read_all: bool,
+    /// Encoded ops waiting to be sent
+    ops: Vec<u8>,
+    pending_copy: Option<(u32, u32)>,
+    packet_count: usize,
+    ended: bool,
+    checksum: TransferChecksum,
+    compress: bool,
+}
+
+impl<R: Read> DeltaPacketGeneratorIterator<R> {
+    pub fn new(job_id: u32, reader: R, signature: &Signature) -> Self {
+        let mut blocks: HashMap<u32, Vec<(u32, StrongChecksum)>> = HashMap::new();
+        for (index, block) in signature.blocks.iter().enumerate() {
+            blocks.entry(block.weak).or_default().push((index as u32, block.strong));
+        }
+
+        DeltaPacketGeneratorIterator {
+            job_id,
+            reader,
+            block_size: signature.block_size,
+            blocks,
+            buffer: Vec::with_capacity(READ_SIZE * 2),
+            position: 0,
+            literal_start: 0,
+            rolling: None,
+            read_all: false,
+            ops: Vec::with_capacity(PACKET_DATA_SIZE),
+            pending_copy: None,
+            packet_count: 0,
+            ended: false,
+            checksum: TransferChecksum::new(),
+            compress: false,
+        }
+    }
+
+    /// Reads until the window and the byte after it are buffered, or the file ends
+    fn fill_buffer(&mut self) {
+        // Everything before the pending literal has been sent
+        let sent = self.literal_start.min(self.position);
+        if sent > 0 {
+            self.buffer.drain(..sent);
+            self.position -= sent;
+            self.literal_start -= sent;
+        }
+
+        while !self.read_all && self.buffer.len() <= self.position + self.block_size {
+            let mut temp_buffer = [0; READ_SIZE];
+            let bytes_read = self.reader.read(&mut temp_buffer).unwrap();
+
+            if bytes_read == 0 {
+                self.read_all = true;
+            }
+
+            self.buffer.extend_from_slice(&temp_buffer[0..bytes_read]);
+        }
+    }
+
+    /// Works through the file until a packet worth of ops is ready or the file is done
+    fn generate_ops(&mut self) {
+        while self.ops.len() < PACKET_DATA_SIZE {
+            if self.buffer.len() <= self.position + self.block_size {
+                self.fill_buffer();
+            }
+
+            let window_end = self.position + self.block_size;
+
+            // Less than a block left, the rest is sent as data
+            if window_end > self.buffer.len() {
+                self.flush_literal(self.buffer.len());
+                self.flush_copy();
+                self.position = self.buffer.len();
+                return;
+            }
+
+            let rolling = *self
+                .rolling
+                .get_or_insert_with(|| RollingChecksum::new(&self.buffer[self.position..window_end]));
+
+            if let Some(index) = self.find_block(rolling.value(), window_end) {
+                self.flush_literal(self.position);
+                self.add_copy(index);
+
+                self.checksum.update(&self.buffer[self.position..window_end]);
+                self.position = window_end;
+                self.literal_start = window_end;
+                self.rolling = None;
+                continue;
+            }
+
+            if self.position - self.literal_start >= MAX_LITERAL_SIZE {
+                self.flush_literal(self.position);
+            }
+
+            if window_end == self.buffer.len() {
+                // Last window of the file without a match
+                self.flush_literal(self.buffer.len());
+                self.flush_copy();
+                self.position = self.buffer.len();
+                return;
+            }
+
+            let mut rolling = rolling;
+            rolling.roll(self.buffer[self.position], self.buffer[window_end]);
+            self.rolling = Some(rolling);
+            self.position += 1;
+        }
+    }
+
+    fn find_block(&self, weak: u32, window_end: usize) -> Option<u32> {
+        let candidates = self.blocks.get(&weak)?;
+        let strong = strong_checksum(&self.buffer[self.position..window_end]);
+
+        candidates
+            .iter()
+            .find(|(_, candidate)| *candidate == strong)
+            .map(|(index, _)| *index)
+    }
+
+    fn add_copy(&mut self, index: u32) {
+        // Consecutive blocks become a single copy
+        if let Some((start_block, count)) = self.pending_copy.as_mut() {
+            if *start_block + *count == index {
+                *count += 1;
+                return;
+            }
+        }
+
+        self.flush_copy();
+        self.pending_copy = Some((index, 1));
+    }
+
+    fn flush_copy(&mut self) {
+        if let Some((start_block, count)) = self.pending_copy.take() {
+            DeltaOp::encode_copy(start_block, count, &mut self.ops);
+        }
+    }
+
+    fn flush_literal(&mut self, end: usize) {
+        if end <= self.literal_start {
+            return;
+        }
+
+        self.flush_copy();
+
+        for chunk in self.buffer[self.literal_start..end].chunks(MAX_LITERAL_SIZE) {
+            DeltaOp::encode_literal(chunk, &mut self.ops);
+            self.checksum.update(chunk);
+        }
+
+        self.literal_start = end;
+    }
+
+    fn is_done(&self) -> bool {
+        self.read_all && self.position >= self.buffer.len() && self.literal_start >= self.buffer.len()
+    }
+}
+
+impl<R: Read> Iterator for DeltaPacketGeneratorIterator<R> {
+    type Item = DataPacket;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if !self.is_done() {
+            self.generate_ops();
+        }
+
+        if !self.ops.is_empty() {
+            let data = std::mem::take(&mut self.ops);
+            self.packet_count += 1;
+
+            return Some(DataPacket::new(self.job_id, data, self.packet_count as u32).with_compression(self.compress));
+        }
+
+        // Send end packet
+        if !self.ended {
+            self.ended = true;
+            return Some(DataPacket::new_end(
+                self.job_id,
+                (self.packet_count + 1) as u32,
+                self.checksum.finalize(),
+            ));
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use crate::data::{delta::DeltaDecoder, packets::chunk_checksum};
+
+    use super::*;
+
+    fn test_data(len: usize, seed: u32) -> Vec<u8> {
+        let mut state = seed;
+        (0..len)
+            .map(|_| {
+                state = state.wrapping_mul(1103515245).wrapping_add(12345);
+                (state >> 16) as u8
+            })
+            .collect()
+    }
+
+    /// Applies the delta in memory, as the receiver does with its files
+    fn apply(basis: &[u8], block_size: usize, packets: &[DataPacket]) -> Vec<u8> {
+        let mut decoder = DeltaDecoder::new();
+        let mut output = vec![];
+
+        for packet in packets {
+            decoder.feed(&packet.data);
+
+            while let Some(op) = decoder.next_op().unwrap() {
+                match op {
+                    DeltaOp::Literal(data) => output.extend(data),
+                    DeltaOp::Copy { start_block, count } => {
+                        let start = start_block as usize * block_size;
+                        output.extend(&basis[start..start + count as usize * block_size]);
+                    }
+                }
+            }
+        }
+
+        assert!(decoder.is_empty());
+        output
+    }
+
+    fn delta(basis: &[u8], file: &[u8]) -> (Vec<DataPacket>, usize) {
+        let signature = Signature::from_reader(basis, 2048).unwrap();
+        let packets = DeltaPacketGeneratorIterator::new(1, file, &signature).collect();
+
+        (packets, signature.block_size)
+    }
+
+    #[test]
+    fn test_small_change() {
+        let basis = test_data(200_000, 1);
+        let mut file = basis.clone();
+        file[100_000] ^= 1;
+        file.splice(50_000..50_000, test_data(10, 2));
+
+        let (packets, block_size) = delta(&basis, &file);
+
+        // Only the two changed blocks and the short last block travel, plus a few copy instructions
+        let sent: usize = packets.iter().map(|packet| packet.data.len()).sum();
+        assert!(sent < 3 * 2048 + 100, "sent {} bytes", sent);
+
+        assert_eq!(apply(&basis, block_size, &packets), file);
+
+        let end = packets.last().unwrap();
+        assert!(!end.has_data);
+        assert_eq!(end.checksum, chunk_checksum(&file));
+    }
+
+    #[test]
+    fn test_no_common_blocks() {
+        let basis = test_data(10_000, 1);
+        let file = test_data(150_000, 2);
+
+        let (packets, block_size) = delta(&basis, &file);
+
+        assert!(packets.iter().all(|packet| packet.data.len() <= PACKET_DATA_SIZE + MAX_LITERAL_SIZE));
+        assert_eq!(apply(&basis, block_size, &packets), file);
+        assert_eq!(packets.last().unwrap().checksum, chunk_checksum(&file));
+    }
+
+    #[test]
+    fn test_empty_file() {
+        let (packets, block_size) = delta(&test_data(10_000, 1), &[]);
+
+        assert_eq!(packets.len(), 1);
+        assert_eq!(apply(&[], block_size, &packets), Vec::<u8>::new());
+        assert_eq!(packets[0].checksum, chunk_checksum(&[]));
+    }
+
+    #[test]
+    fn test_reordered_blocks() {
+        let basis = test_data(8192, 1);
+        let mut file = basis[4096..].to_vec();
+        file.extend(&basis[..4096]);
+
+        let (packets, block_size) = delta(&basis, &file);
+
+        assert_eq!(apply(&basis, block_size, &packets), file);
+        assert_eq!(packets.iter().map(|packet| packet.data.len()).sum::<usize>(), 18);
+    }
+
+    proptest! {
+        #[test]
+        fn prop_delta_rebuilds_file(
+            basis in prop::collection::vec(any::<u8>(), 0..20_000),
+            edits in prop::collection::vec((any::<prop::sample::Index>(), 0..3000usize, prop::collection::vec(any::<u8>(), 0..100)), 0..5),
+        ) {
+            // Cut ranges out of the basis and put random bytes in their place
+            let mut file = basis.clone();
+            for (index, len, replacement) in edits {
+                let start = index.index(file.len() + 1);
+                let end = (start + len).min(file.len());
+                file.splice(start..end, replacement);
+            }
+
+            let (packets, block_size) = delta(&basis, &file);
+
+            prop_assert_eq!(apply(&basis, block_size, &packets), file.clone());
+            prop_assert_eq!(packets.last().unwrap().checksum, chunk_checksum(&file));
+        }
+    }
+}
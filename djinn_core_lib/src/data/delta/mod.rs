@@ -0,0 +1,22 @@
+mod rolling_checksum;
+pub use rolling_checksum::RollingChecksum;
+
+mod signature;
+pub use signature::strong_checksum;
+pub use signature::BlockSignature;
+pub use signature::Signature;
+pub use signature::StrongChecksum;
+pub use signature::DELTA_MIN_SIZE;
+pub use signature::STRONG_CHECKSUM_SIZE;
+
+mod delta_op;
+pub use delta_op::DeltaDecoder;
+pub use delta_op::DeltaOp;
+pub use delta_op::MAX_LITERAL_SIZE;
+
+mod delta_packet_generator;
+pub use delta_packet_generator::DeltaPacketGenerator;
+pub use delta_packet_generator::DeltaPacketGeneratorIterator;
+
+mod delta_applier;
+pub use delta_applier::DeltaApplier;
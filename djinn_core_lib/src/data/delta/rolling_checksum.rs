@@ -0,0 +1,57 @@
+/// Weak checksum from rsync, which can be moved along a file one byte at a time
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct RollingChecksum {
+    a: u32,
+    b: u32,
+    len: u32,
+}
+
+impl RollingChecksum {
+    pub fn new(data: &[u8]) -> RollingChecksum {
+        let len = data.len() as u32;
+        let mut a: u32 = 0;
+        let mut b: u32 = 0;
+
+        for (i, &byte) in data.iter().enumerate() {
+            a = a.wrapping_add(byte as u32);
+            b = b.wrapping_add((len - i as u32).wrapping_mul(byte as u32));
+        }
+
+        RollingChecksum {
+            a: a & 0xffff,
+            b: b & 0xffff,
+            len,
+        }
+    }
+
+    pub fn value(&self) -> u32 {
+        self.a | (self.b << 16)
+    }
+
+    /// Moves the window one byte forward, dropping `out` at the front and adding `next` at the end
+    pub fn roll(&mut self, out: u8, next: u8) {
+        self.a = self.a.wrapping_sub(out as u32).wrapping_add(next as u32) & 0xffff;
+        self.b = self
+            .b
+            .wrapping_sub(self.len.wrapping_mul(out as u32))
+            .wrapping_add(self.a)
+            & 0xffff;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_roll_matches_new() {
+        let data: Vec<u8> = (0..200).map(|i| (i * 37 % 251) as u8).collect();
+        let window = 16;
+
+        let mut rolling = RollingChecksum::new(&data[..window]);
+        for start in 1..data.len() - window {
+            rolling.roll(data[start - 1], data[start + window - 1]);
+            assert_eq!(rolling, RollingChecksum::new(&data[start..start + window]), "{}", start);
+        }
+    }
+}
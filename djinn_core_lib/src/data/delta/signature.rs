@@ -0,0 +1,167 @@
+use std::{
+    fmt,
+    fs::File,
+    io::{self, BufReader, Read},
+    str::FromStr,
+};
+
+use super::RollingChecksum;
+
+/// Files smaller than this are always sent whole, a delta would not save enough to be worth it
+pub const DELTA_MIN_SIZE: u64 = 64 * 1024;
+
+pub const STRONG_CHECKSUM_SIZE: usize = 16;
+
+pub type StrongChecksum = [u8; STRONG_CHECKSUM_SIZE];
+
+const MIN_BLOCK_SIZE: usize = 2048;
+
+// Keeps the signature of big files within a few hundred kilobytes
+const MAX_BLOCKS: u64 = 16384;
+
+// Weak and strong checksum in hex
+const BLOCK_SIGNATURE_HEX_SIZE: usize = 8 + STRONG_CHECKSUM_SIZE * 2;
+
+pub fn strong_checksum(data: &[u8]) -> StrongChecksum {
+    let mut checksum = [0; STRONG_CHECKSUM_SIZE];
+    checksum.copy_from_slice(&blake3::hash(data).as_bytes()[..STRONG_CHECKSUM_SIZE]);
+    checksum
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct BlockSignature {
+    pub weak: u32,
+    pub strong: StrongChecksum,
+}
+
+/// Checksums of every block of the receiver's copy of a file, from which the sender works out
+/// which parts it does not have to send
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Signature {
+    pub block_size: usize,
+    pub blocks: Vec<BlockSignature>,
+}
+
+impl Signature {
+    pub fn block_size_for(file_size: u64) -> usize {
+        let block_size = file_size.div_ceil(MAX_BLOCKS).next_power_of_two() as usize;
+        block_size.max(MIN_BLOCK_SIZE)
+    }
+
+    /// Reads the whole file, so call it from a blocking context
+    pub fn generate(path: &str) -> io::Result<Signature> {
+        let file = File::open(path)?;
+        let block_size = Signature::block_size_for(file.metadata()?.len());
+
+        Signature::from_reader(BufReader::new(file), block_size)
+    }
+
+    pub fn from_reader<R: Read>(mut reader: R, block_size: usize) -> io::Result<Signature> {
+        let mut blocks = vec![];
+        let mut block = vec![0; block_size];
+
+        loop {
+            // Fill a whole block unless the file ends
+            let mut filled = 0;
+            while filled < block_size {
+                let bytes_read = reader.read(&mut block[filled..])?;
+                if bytes_read == 0 {
+                    break;
+                }
+                filled += bytes_read;
+            }
+
+            // A short last block is left out, the sender sends that part as data
+            if filled < block_size {
+                break;
+            }
+
+            blocks.push(BlockSignature {
+                weak: RollingChecksum::new(&block).value(),
+                strong: strong_checksum(&block),
+            });
+        }
+
+        Ok(Signature { block_size, blocks })
+    }
+}
+
+/// Wire format is "block_size:" followed by the weak and strong checksum of every block in hex
+impl fmt::Display for Signature {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:", self.block_size)?;
+
+        for block in &self.blocks {
+            write!(f, "{:08x}", block.weak)?;
+            for byte in block.strong {
+                write!(f, "{:02x}", byte)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl FromStr for Signature {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<Signature, ()> {
+        let (block_size, blocks) = value.split_once(':').ok_or(())?;
+        let block_size = block_size.parse::<usize>().map_err(|_| ())?;
+
+        if block_size < MIN_BLOCK_SIZE || !block_size.is_power_of_two() {
+            return Err(());
+        }
+
+        if !blocks.is_ascii() || blocks.len() % BLOCK_SIGNATURE_HEX_SIZE != 0 {
+            return Err(());
+        }
+
+        let blocks = blocks
+            .as_bytes()
+            .chunks(BLOCK_SIGNATURE_HEX_SIZE)
+            .map(|block| {
+                let block = std::str::from_utf8(block).map_err(|_| ())?;
+                let weak = u32::from_str_radix(&block[..8], 16).map_err(|_| ())?;
+
+                let mut strong = [0; STRONG_CHECKSUM_SIZE];
+                for (i, byte) in strong.iter_mut().enumerate() {
+                    *byte = u8::from_str_radix(&block[8 + i * 2..10 + i * 2], 16).map_err(|_| ())?;
+                }
+
+                Ok(BlockSignature { weak, strong })
+            })
+            .collect::<Result<Vec<BlockSignature>, ()>>()?;
+
+        Ok(Signature { block_size, blocks })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_block_size() {
+        assert_eq!(Signature::block_size_for(0), MIN_BLOCK_SIZE);
+        assert_eq!(Signature::block_size_for(1024 * 1024), MIN_BLOCK_SIZE);
+        assert_eq!(Signature::block_size_for(4 * 1024 * 1024 * 1024), 256 * 1024);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let data: Vec<u8> = (0..5000).map(|i| (i % 251) as u8).collect();
+        let signature = Signature::from_reader(data.as_slice(), 2048).unwrap();
+
+        // The last 904 bytes do not fill a block
+        assert_eq!(signature.blocks.len(), 2);
+        assert_eq!(signature.to_string().parse::<Signature>(), Ok(signature));
+    }
+
+    #[test]
+    fn test_parse_invalid() {
+        for value in ["", "2048", "100:", "2048:abc", "2048:zz000000000000000000000000000000000000"] {
+            assert!(value.parse::<Signature>().is_err(), "{}", value);
+        }
+    }
+}
@@ -0,0 +1,28 @@
+use std::fmt;
+
+/// Optional features both sides have to agree on. Everything else a protocol version has is always used.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Capability {
+    /// Data packets may be compressed with lz4
+    Compression,
+}
+
+impl Capability {
+    /// Unknown capabilities (e.g. from a newer peer) are not an error, they are simply ignored
+    pub fn from_string(capability: &str) -> Option<Capability> {
+        match capability {
+            "compression" => Some(Capability::Compression),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Capability {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Capability::Compression => "compression",
+        };
+
+        write!(f, "{}", name)
+    }
+}
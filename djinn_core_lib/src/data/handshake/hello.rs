@@ -0,0 +1,177 @@
+use std::collections::HashMap;
+
+use crate::data::messages::{
+    params::{parse, parse_optional},
+    MessageError,
+};
+
+use super::Capability;
+
+/// Version of the wire format. Bump this for every change to it, and only send what the change adds
+/// when the negotiated version has it.
+pub const PROTOCOL_VERSION: u32 = 13;
+/// Oldest wire format this build can still speak. Only raised when support for an older format is dropped.
+pub const MIN_PROTOCOL_VERSION: u32 = 13;
+pub const SOFTWARE_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Contents of the Hello/HelloAck packets which open every connection
+#[derive(Clone, Debug, PartialEq)]
+pub struct Hello {
+    pub protocol_version: u32,
+    pub min_protocol_version: u32,
+    pub software_version: String,
+    pub capabilities: Vec<Capability>,
+    /// Name of the peer, used to tell users where conflict copies come from
+    pub name: Option<String>,
+}
+
+impl Hello {
+    pub fn new(capabilities: Vec<Capability>) -> Hello {
+        Hello {
+            protocol_version: PROTOCOL_VERSION,
+            min_protocol_version: MIN_PROTOCOL_VERSION,
+            software_version: SOFTWARE_VERSION.to_string(),
+            capabilities,
+            name: None,
+        }
+    }
+
+    pub fn to_params(&self) -> HashMap<String, String> {
+        let mut params = HashMap::new();
+        params.insert("protocol_version".to_string(), self.protocol_version.to_string());
+        params.insert("min_protocol_version".to_string(), self.min_protocol_version.to_string());
+        params.insert("software_version".to_string(), self.software_version.clone());
+
+        let capabilities: Vec<String> = self.capabilities.iter().map(|c| c.to_string()).collect();
+        params.insert("capabilities".to_string(), capabilities.join(","));
+
+        if let Some(name) = &self.name {
+            params.insert("name".to_string(), name.clone());
+        }
+
+        params
+    }
+
+    pub fn from_params(params: &HashMap<String, String>) -> Result<Hello, MessageError> {
+        let protocol_version = parse(params, "protocol_version")?;
+
+        // Peers which don't send a minimum only speak their own version
+        let min_protocol_version = parse_optional(params, "min_protocol_version")?.unwrap_or(protocol_version);
+
+        let software_version = params
+            .get("software_version")
+            .cloned()
+            .unwrap_or_else(|| "unknown".to_string());
+
+        let capabilities = params
+            .get("capabilities")
+            .map(|capabilities| {
+                capabilities
+                    .split(',')
+                    .filter_map(Capability::from_string)
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        Ok(Hello {
+            protocol_version,
+            min_protocol_version,
+            software_version,
+            capabilities,
+            name: params.get("name").cloned(),
+        })
+    }
+
+    /// Agree on the highest protocol version both sides speak and the capabilities both sides have.
+    /// Returns a reason when there is no common protocol version.
+    pub fn negotiate(&self, remote: &Hello) -> Result<Hello, String> {
+        let protocol_version = self.protocol_version.min(remote.protocol_version);
+
+        if protocol_version < self.min_protocol_version || protocol_version < remote.min_protocol_version {
+            return Err(format!(
+                "Incompatible protocol versions: local speaks v{}-v{} ({}), remote speaks v{}-v{} ({})",
+                self.min_protocol_version,
+                self.protocol_version,
+                self.software_version,
+                remote.min_protocol_version,
+                remote.protocol_version,
+                remote.software_version
+            ));
+        }
+
+        let capabilities = self
+            .capabilities
+            .iter()
+            .filter(|capability| remote.capabilities.contains(capability))
+            .copied()
+            .collect();
+
+        Ok(Hello {
+            protocol_version,
+            min_protocol_version: protocol_version,
+            software_version: self.software_version.clone(),
+            capabilities,
+            name: self.name.clone(),
+        })
+    }
+
+    pub fn has_capability(&self, capability: Capability) -> bool {
+        self.capabilities.contains(&capability)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hello_params_round_trip() {
+        let mut hello = Hello::new(vec![Capability::Compression]);
+        hello.name = Some("laptop".to_string());
+        let params = hello.to_params();
+
+        let decoded = Hello::from_params(&params).unwrap();
+
+        assert_eq!(decoded, hello);
+    }
+
+    #[test]
+    fn test_negotiate_downgrades_to_common_version() {
+        let mut local = Hello::new(vec![Capability::Compression]);
+        local.protocol_version = 3;
+        local.min_protocol_version = 1;
+
+        let mut remote = Hello::new(vec![]);
+        remote.protocol_version = 2;
+        remote.min_protocol_version = 2;
+
+        let agreed = local.negotiate(&remote).unwrap();
+
+        assert_eq!(agreed.protocol_version, 2);
+        assert!(agreed.capabilities.is_empty());
+    }
+
+    #[test]
+    fn test_negotiate_rejects_incompatible_versions() {
+        let mut local = Hello::new(vec![]);
+        local.protocol_version = 3;
+        local.min_protocol_version = 3;
+
+        let mut remote = Hello::new(vec![]);
+        remote.protocol_version = 2;
+        remote.min_protocol_version = 1;
+
+        assert!(local.negotiate(&remote).is_err());
+        assert!(remote.negotiate(&local).is_err());
+    }
+
+    #[test]
+    fn test_unknown_capabilities_are_ignored() {
+        let mut params = Hello::new(vec![]).to_params();
+        params.insert("capabilities".to_string(), "compression,teleport".to_string());
+
+        let decoded = Hello::from_params(&params).unwrap();
+
+        assert_eq!(decoded.capabilities, vec![Capability::Compression]);
+    }
+}
@@ -0,0 +1,8 @@
+mod capability;
+pub use capability::Capability;
+
+mod hello;
+pub use hello::Hello;
+pub use hello::MIN_PROTOCOL_VERSION;
+pub use hello::PROTOCOL_VERSION;
+pub use hello::SOFTWARE_VERSION;
@@ -0,0 +1,67 @@
+use std::collections::HashMap;
+
+use super::{params::required, MessageError};
+
+/// Sent by the client right after the handshake, nothing else is accepted before it succeeded.
+/// Only safe on a TLS connection since the password is sent as it is.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Authenticate {
+    pub user: String,
+    pub password: String,
+}
+
+impl Authenticate {
+    pub fn to_params(&self) -> HashMap<String, String> {
+        let mut params = HashMap::new();
+        params.insert("user".to_string(), self.user.clone());
+        params.insert("password".to_string(), self.password.clone());
+        params
+    }
+
+    pub fn from_params(params: &HashMap<String, String>) -> Result<Authenticate, MessageError> {
+        Ok(Authenticate {
+            user: required(params, "user")?.clone(),
+            password: required(params, "password")?.clone(),
+        })
+    }
+}
+
+/// Identity the server knows the client by from now on
+#[derive(Clone, Debug, PartialEq)]
+pub struct AuthenticateAck {
+    pub user: String,
+}
+
+impl AuthenticateAck {
+    pub fn to_params(&self) -> HashMap<String, String> {
+        let mut params = HashMap::new();
+        params.insert("user".to_string(), self.user.clone());
+        params
+    }
+
+    pub fn from_params(params: &HashMap<String, String>) -> Result<AuthenticateAck, MessageError> {
+        Ok(AuthenticateAck {
+            user: required(params, "user")?.clone(),
+        })
+    }
+}
+
+/// Sent for wrong credentials, and for any command of a client which did not authenticate yet
+#[derive(Clone, Debug, PartialEq)]
+pub struct AuthenticateDeny {
+    pub reason: String,
+}
+
+impl AuthenticateDeny {
+    pub fn to_params(&self) -> HashMap<String, String> {
+        let mut params = HashMap::new();
+        params.insert("reason".to_string(), self.reason.clone());
+        params
+    }
+
+    pub fn from_params(params: &HashMap<String, String>) -> Result<AuthenticateDeny, MessageError> {
+        Ok(AuthenticateDeny {
+            reason: required(params, "reason")?.clone(),
+        })
+    }
+}
@@ -0,0 +1,295 @@
+use std::collections::HashMap;
+
+use crate::data::{
+    handshake::Hello,
+    packets::{ControlPacket, ControlPacketType},
+};
+
+use super::{
+    Authenticate, AuthenticateAck, AuthenticateDeny, HelloDeny, MessageError, SyncAck, SyncDeny, SyncIndex, SyncRequest, SyncUpdate, TransferAck, TransferCancel,
+    TransferDeny, TransferRequest, TransferStart,
+};
+
+/// Typed contents of a control packet. The job id stays on the packet itself.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ControlMessage {
+    EchoRequest,
+    EchoReply,
+    TransferRequest(TransferRequest),
+    TransferAck(TransferAck),
+    TransferDeny(TransferDeny),
+    TransferStart(TransferStart),
+    TransferCancel(TransferCancel),
+    SyncRequest(SyncRequest),
+    SyncAck(SyncAck),
+    SyncDeny(SyncDeny),
+    SyncUpdate(SyncUpdate),
+    SyncIndexRequest,
+    SyncIndexResponse(SyncIndex),
+    SyncIndexUpdate(SyncIndex),
+    Hello(Hello),
+    HelloAck(Hello),
+    HelloDeny(HelloDeny),
+    Authenticate(Authenticate),
+    AuthenticateAck(AuthenticateAck),
+    AuthenticateDeny(AuthenticateDeny),
+}
+
+impl ControlMessage {
+    pub fn control_packet_type(&self) -> ControlPacketType {
+        match self {
+            ControlMessage::EchoRequest => ControlPacketType::EchoRequest,
+            ControlMessage::EchoReply => ControlPacketType::EchoReply,
+            ControlMessage::TransferRequest(_) => ControlPacketType::TransferRequest,
+            ControlMessage::TransferAck(_) => ControlPacketType::TransferAck,
+            ControlMessage::TransferDeny(_) => ControlPacketType::TransferDeny,
+            ControlMessage::TransferStart(_) => ControlPacketType::TransferStart,
+            ControlMessage::TransferCancel(_) => ControlPacketType::TransferCancel,
+            ControlMessage::SyncRequest(_) => ControlPacketType::SyncRequest,
+            ControlMessage::SyncAck(_) => ControlPacketType::SyncAck,
+            ControlMessage::SyncDeny(_) => ControlPacketType::SyncDeny,
+            ControlMessage::SyncUpdate(_) => ControlPacketType::SyncUpdate,
+            ControlMessage::SyncIndexRequest => ControlPacketType::SyncIndexRequest,
+            ControlMessage::SyncIndexResponse(_) => ControlPacketType::SyncIndexResponse,
+            ControlMessage::SyncIndexUpdate(_) => ControlPacketType::SyncIndexUpdate,
+            ControlMessage::Hello(_) => ControlPacketType::Hello,
+            ControlMessage::HelloAck(_) => ControlPacketType::HelloAck,
+            ControlMessage::HelloDeny(_) => ControlPacketType::HelloDeny,
+            ControlMessage::Authenticate(_) => ControlPacketType::Authenticate,
+            ControlMessage::AuthenticateAck(_) => ControlPacketType::AuthenticateAck,
+            ControlMessage::AuthenticateDeny(_) => ControlPacketType::AuthenticateDeny,
+        }
+    }
+
+    pub fn to_params(&self) -> HashMap<String, String> {
+        match self {
+            ControlMessage::EchoRequest | ControlMessage::EchoReply | ControlMessage::SyncIndexRequest => HashMap::new(),
+            ControlMessage::TransferRequest(message) => message.to_params(),
+            ControlMessage::TransferAck(message) => message.to_params(),
+            ControlMessage::TransferDeny(message) => message.to_params(),
+            ControlMessage::TransferStart(message) => message.to_params(),
+            ControlMessage::TransferCancel(message) => message.to_params(),
+            ControlMessage::SyncRequest(message) => message.to_params(),
+            ControlMessage::SyncAck(message) => message.to_params(),
+            ControlMessage::SyncDeny(message) => message.to_params(),
+            ControlMessage::SyncUpdate(message) => message.to_params(),
+            ControlMessage::SyncIndexResponse(message) | ControlMessage::SyncIndexUpdate(message) => message.to_params(),
+            ControlMessage::Hello(message) | ControlMessage::HelloAck(message) => message.to_params(),
+            ControlMessage::HelloDeny(message) => message.to_params(),
+            ControlMessage::Authenticate(message) => message.to_params(),
+            ControlMessage::AuthenticateAck(message) => message.to_params(),
+            ControlMessage::AuthenticateDeny(message) => message.to_params(),
+        }
+    }
+
+    pub fn to_packet(&self, job_id: Option<u32>) -> ControlPacket {
+        let mut packet = ControlPacket::new(self.control_packet_type(), self.to_params());
+        packet.job_id = job_id;
+        packet
+    }
+
+    pub fn from_packet(packet: &ControlPacket) -> Result<ControlMessage, MessageError> {
+        let params = &packet.params;
+
+        let message = match packet.control_packet_type {
+            ControlPacketType::EchoRequest => ControlMessage::EchoRequest,
+            ControlPacketType::EchoReply => ControlMessage::EchoReply,
+            ControlPacketType::TransferRequest => ControlMessage::TransferRequest(TransferRequest::from_params(params)?),
+            ControlPacketType::TransferAck => ControlMessage::TransferAck(TransferAck::from_params(params)?),
+            ControlPacketType::TransferDeny => ControlMessage::TransferDeny(TransferDeny::from_params(params)?),
+            ControlPacketType::TransferStart => ControlMessage::TransferStart(TransferStart::from_params(params)?),
+            ControlPacketType::TransferCancel => ControlMessage::TransferCancel(TransferCancel::from_params(params)?),
+            ControlPacketType::SyncRequest => ControlMessage::SyncRequest(SyncRequest::from_params(params)?),
+            ControlPacketType::SyncAck => ControlMessage::SyncAck(SyncAck::from_params(params)?),
+            ControlPacketType::SyncDeny => ControlMessage::SyncDeny(SyncDeny::from_params(params)?),
+            ControlPacketType::SyncUpdate => ControlMessage::SyncUpdate(SyncUpdate::from_params(params)?),
+            ControlPacketType::SyncIndexRequest => ControlMessage::SyncIndexRequest,
+            ControlPacketType::SyncIndexResponse => ControlMessage::SyncIndexResponse(SyncIndex::from_params(params)?),
+            ControlPacketType::SyncIndexUpdate => ControlMessage::SyncIndexUpdate(SyncIndex::from_params(params)?),
+            ControlPacketType::Hello => ControlMessage::Hello(Hello::from_params(params)?),
+            ControlPacketType::HelloAck => ControlMessage::HelloAck(Hello::from_params(params)?),
+            ControlPacketType::HelloDeny => ControlMessage::HelloDeny(HelloDeny::from_params(params)?),
+            ControlPacketType::Authenticate => ControlMessage::Authenticate(Authenticate::from_params(params)?),
+            ControlPacketType::AuthenticateAck => ControlMessage::AuthenticateAck(AuthenticateAck::from_params(params)?),
+            ControlPacketType::AuthenticateDeny => ControlMessage::AuthenticateDeny(AuthenticateDeny::from_params(params)?),
+            ControlPacketType::None => return Err(MessageError::UnexpectedType(ControlPacketType::None as u8)),
+        };
+
+        Ok(message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::data::{
+        delta::Signature,
+        messages::TransferDirection,
+        packets::{packet::Packet, TransferCancelReason, TransferDenyReason, CHECKSUM_SIZE},
+        syncing::{IndexEntry, SyncAction, SyncPlan, SyncReason},
+    };
+
+    use super::*;
+
+    fn round_trip(message: ControlMessage, job_id: Option<u32>) {
+        let buffer = message.to_packet(job_id).to_buffer();
+
+        let mut packet = ControlPacket::new(ControlPacketType::None, HashMap::new());
+        packet.fill_from_buffer(&buffer).unwrap();
+
+        assert_eq!(packet.job_id, job_id);
+        assert_eq!(ControlMessage::from_packet(&packet).unwrap(), message);
+    }
+
+    #[test]
+    fn test_messages_round_trip() {
+        let mut index = HashMap::new();
+        index.insert("/a;b=c.txt".to_string(), IndexEntry::new(1680000000, 12, Some([3; CHECKSUM_SIZE])));
+        index.insert("/deleted.txt".to_string(), IndexEntry::deleted());
+        index.insert("#timestamp".to_string(), IndexEntry::new(1680000001, 0, None));
+
+        // Base entries share paths with the index
+        let mut base = HashMap::new();
+        base.insert("/a;b=c.txt".to_string(), IndexEntry::new(1670000000, 10, Some([4; CHECKSUM_SIZE])));
+
+        let mut plan = SyncPlan::new();
+        plan.insert("/a.txt".to_string(), SyncAction::Get, SyncReason::ChangedOnServer, Some(IndexEntry::new(1680000000, 12, None)));
+        plan.insert("/b.txt".to_string(), SyncAction::Delete, SyncReason::DeletedOnServer, None);
+        plan.insert("/new;dir/c.txt".to_string(), SyncAction::Move, SyncReason::MovedOnServer, None);
+        plan.entries.get_mut("/new;dir/c.txt").unwrap().from = Some("/old:dir/c.txt".to_string());
+
+        round_trip(ControlMessage::EchoRequest, None);
+        round_trip(
+            ControlMessage::TransferRequest(TransferRequest {
+                file_path: "/a;b=c.txt".to_string(),
+                transfer_id: 7,
+                direction: TransferDirection::ToServer,
+                modified_time: Some(1680000000),
+                delta: true,
+                signature: None,
+                key: Some("0123abcd".to_string()),
+                offset: 0,
+            }),
+            None,
+        );
+        round_trip(
+            ControlMessage::TransferRequest(TransferRequest {
+                file_path: "/a.txt".to_string(),
+                transfer_id: 8,
+                direction: TransferDirection::ToClient,
+                modified_time: None,
+                delta: false,
+                signature: Some(Signature::from_reader([5; 5000].as_slice(), 2048).unwrap()),
+                key: Some("0123abcd".to_string()),
+                offset: 120000,
+            }),
+            None,
+        );
+        round_trip(
+            ControlMessage::TransferAck(TransferAck {
+                transfer_id: 8,
+                job_id: 2,
+                modified_time: Some(1680000000),
+                delta: true,
+                signature: None,
+                key: None,
+                offset: 60000,
+            }),
+            None,
+        );
+        round_trip(
+            ControlMessage::TransferDeny(TransferDeny {
+                transfer_id: 7,
+                reason: TransferDenyReason::FileWriteLock,
+            }),
+            None,
+        );
+        round_trip(
+            ControlMessage::TransferCancel(TransferCancel {
+                job_id: 4,
+                reason: TransferCancelReason::SourceChanged,
+            }),
+            None,
+        );
+        round_trip(
+            ControlMessage::TransferDeny(TransferDeny {
+                transfer_id: 9,
+                reason: TransferDenyReason::PermissionDenied,
+            }),
+            None,
+        );
+        round_trip(ControlMessage::SyncRequest(SyncRequest { path: "/".to_string(), dry_run: false }), None);
+        round_trip(ControlMessage::SyncRequest(SyncRequest { path: "/docs".to_string(), dry_run: true }), None);
+        round_trip(ControlMessage::SyncUpdate(SyncUpdate { plan }), Some(3));
+        round_trip(ControlMessage::SyncIndexUpdate(SyncIndex { index, base }), Some(3));
+        round_trip(ControlMessage::SyncIndexRequest, Some(3));
+        round_trip(
+            ControlMessage::Authenticate(Authenticate {
+                user: "alice".to_string(),
+                password: "correct horse;battery=staple".to_string(),
+            }),
+            None,
+        );
+        round_trip(ControlMessage::AuthenticateAck(AuthenticateAck { user: "alice".to_string() }), None);
+        round_trip(ControlMessage::AuthenticateDeny(AuthenticateDeny { reason: "Wrong password".to_string() }), None);
+    }
+
+    #[test]
+    fn test_missing_param() {
+        let packet = ControlPacket::new(ControlPacketType::TransferStart, HashMap::new());
+
+        assert_eq!(
+            ControlMessage::from_packet(&packet),
+            Err(MessageError::MissingParam("job_id"))
+        );
+    }
+
+    #[test]
+    fn test_invalid_param() {
+        let mut params = TransferRequest {
+            file_path: "/a.txt".to_string(),
+            transfer_id: 1,
+            direction: TransferDirection::ToServer,
+            modified_time: None,
+            delta: false,
+            signature: None,
+            key: None,
+            offset: 0,
+        }
+        .to_params();
+        params.insert("transfer_id".to_string(), "one".to_string());
+
+        let packet = ControlPacket::new(ControlPacketType::TransferRequest, params);
+
+        assert_eq!(
+            ControlMessage::from_packet(&packet),
+            Err(MessageError::InvalidParam {
+                name: "transfer_id",
+                value: "one".to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn test_invalid_enum_param() {
+        let mut params = HashMap::new();
+        params.insert("transfer_id".to_string(), "1".to_string());
+        params.insert("reason".to_string(), "Bored".to_string());
+
+        let packet = ControlPacket::new(ControlPacketType::TransferDeny, params);
+
+        assert!(ControlMessage::from_packet(&packet).is_err());
+    }
+
+    #[test]
+    fn test_sync_request_share() {
+        let request = |path: &str| SyncRequest { path: path.to_string(), dry_run: false };
+
+        assert_eq!(request("projects:/docs").share(), Some("projects"));
+        assert_eq!(request("projects:/docs").share_path(), "/docs");
+        assert_eq!(request("assets:").share_path(), "");
+        assert_eq!(request("/docs").share(), None);
+        assert_eq!(request("/docs").share_path(), "/docs");
+        assert_eq!(request("/a:b").share(), None);
+        assert_eq!(request(":/docs").share(), None);
+    }
+}
@@ -0,0 +1,27 @@
+use std::collections::HashMap;
+
+use crate::data::handshake::Hello;
+
+use super::{params::required, MessageError};
+
+/// Sent instead of a HelloAck when there is no protocol version both sides speak
+#[derive(Clone, Debug, PartialEq)]
+pub struct HelloDeny {
+    pub hello: Hello,
+    pub reason: String,
+}
+
+impl HelloDeny {
+    pub fn to_params(&self) -> HashMap<String, String> {
+        let mut params = self.hello.to_params();
+        params.insert("reason".to_string(), self.reason.clone());
+        params
+    }
+
+    pub fn from_params(params: &HashMap<String, String>) -> Result<HelloDeny, MessageError> {
+        Ok(HelloDeny {
+            hello: Hello::from_params(params)?,
+            reason: required(params, "reason")?.clone(),
+        })
+    }
+}
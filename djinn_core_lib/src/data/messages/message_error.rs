@@ -0,0 +1,22 @@
+use std::{error::Error, fmt};
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum MessageError {
+    MissingParam(&'static str),
+    InvalidParam { name: &'static str, value: String },
+    UnexpectedType(u8),
+}
+
+impl fmt::Display for MessageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MessageError::MissingParam(name) => write!(f, "missing param '{}'", name),
+            MessageError::InvalidParam { name, value } => write!(f, "invalid value '{}' for param '{}'", value, name),
+            MessageError::UnexpectedType(control_packet_type) => {
+                write!(f, "control packet type {} does not carry a message", control_packet_type)
+            }
+        }
+    }
+}
+
+impl Error for MessageError {}
@@ -0,0 +1,30 @@
+mod message_error;
+pub use message_error::MessageError;
+
+pub(crate) mod params;
+
+mod control_message;
+pub use control_message::ControlMessage;
+
+mod transfer_messages;
+pub use transfer_messages::TransferAck;
+pub use transfer_messages::TransferCancel;
+pub use transfer_messages::TransferDeny;
+pub use transfer_messages::TransferDirection;
+pub use transfer_messages::TransferRequest;
+pub use transfer_messages::TransferStart;
+
+mod sync_messages;
+pub use sync_messages::SyncAck;
+pub use sync_messages::SyncDeny;
+pub use sync_messages::SyncIndex;
+pub use sync_messages::SyncRequest;
+pub use sync_messages::SyncUpdate;
+
+mod hello_messages;
+pub use hello_messages::HelloDeny;
+
+mod auth_messages;
+pub use auth_messages::Authenticate;
+pub use auth_messages::AuthenticateAck;
+pub use auth_messages::AuthenticateDeny;
@@ -0,0 +1,24 @@
+use std::{collections::HashMap, str::FromStr};
+
+use super::MessageError;
+
+pub fn required<'a>(params: &'a HashMap<String, String>, name: &'static str) -> Result<&'a String, MessageError> {
+    params.get(name).ok_or(MessageError::MissingParam(name))
+}
+
+pub fn parse<T: FromStr>(params: &HashMap<String, String>, name: &'static str) -> Result<T, MessageError> {
+    let value = required(params, name)?;
+
+    value.parse::<T>().map_err(|_| MessageError::InvalidParam {
+        name,
+        value: value.clone(),
+    })
+}
+
+pub fn parse_optional<T: FromStr>(params: &HashMap<String, String>, name: &'static str) -> Result<Option<T>, MessageError> {
+    if !params.contains_key(name) {
+        return Ok(None);
+    }
+
+    parse(params, name).map(Some)
+}
@@ -0,0 +1,180 @@
+use std::collections::HashMap;
+
+use crate::data::{packets::TransferDenyReason, syncing::{IndexEntry, SyncPlan, SyncPlanEntry}};
+
+use super::{
+    params::{parse, parse_optional, required},
+    MessageError,
+};
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct SyncRequest {
+    /// Directory to sync, `share:path` for a directory in a named share
+    pub path: String,
+    /// Only plan the sync, neither side may change any files
+    pub dry_run: bool,
+}
+
+impl SyncRequest {
+    pub fn to_params(&self) -> HashMap<String, String> {
+        let mut params = HashMap::new();
+        params.insert("path".to_string(), self.path.clone());
+        if self.dry_run {
+            params.insert("dry_run".to_string(), self.dry_run.to_string());
+        }
+        params
+    }
+
+    pub fn from_params(params: &HashMap<String, String>) -> Result<SyncRequest, MessageError> {
+        Ok(SyncRequest {
+            path: required(params, "path")?.clone(),
+            dry_run: parse_optional(params, "dry_run")?.unwrap_or(false),
+        })
+    }
+
+    /// Name of the share the path is in, None for the default share
+    pub fn share(&self) -> Option<&str> {
+        self.split_share().0
+    }
+
+    /// Path of the directory inside its share
+    pub fn share_path(&self) -> &str {
+        self.split_share().1
+    }
+
+    /// A share name never contains a slash, so `/a:b` is a path in the default share
+    fn split_share(&self) -> (Option<&str>, &str) {
+        match self.path.split_once(':') {
+            Some((share, path)) if !share.is_empty() && !share.contains('/') => (Some(share), path),
+            _ => (None, &self.path),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct SyncAck {
+    pub job_id: u32,
+}
+
+impl SyncAck {
+    pub fn to_params(&self) -> HashMap<String, String> {
+        let mut params = HashMap::new();
+        params.insert("job_id".to_string(), self.job_id.to_string());
+        params
+    }
+
+    pub fn from_params(params: &HashMap<String, String>) -> Result<SyncAck, MessageError> {
+        Ok(SyncAck {
+            job_id: parse(params, "job_id")?,
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct SyncDeny {
+    pub reason: TransferDenyReason,
+}
+
+impl SyncDeny {
+    pub fn to_params(&self) -> HashMap<String, String> {
+        let mut params = HashMap::new();
+        params.insert("reason".to_string(), self.reason.to_string());
+        params
+    }
+
+    pub fn from_params(params: &HashMap<String, String>) -> Result<SyncDeny, MessageError> {
+        Ok(SyncDeny {
+            reason: TransferDenyReason::from_string(required(params, "reason")?)?,
+        })
+    }
+}
+
+const FROM_PREFIX: &str = "#from:";
+
+/// PATH -> planned action, see SyncPlanEntry for the value format. Moves add "#from:PATH" -> old path.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SyncUpdate {
+    pub plan: SyncPlan,
+}
+
+impl SyncUpdate {
+    pub fn to_params(&self) -> HashMap<String, String> {
+        let mut params = HashMap::new();
+
+        for (path, plan_entry) in self.plan.iter() {
+            params.insert(path.clone(), plan_entry.to_string());
+
+            if let Some(from) = &plan_entry.from {
+                params.insert(FROM_PREFIX.to_string() + path, from.clone());
+            }
+        }
+
+        params
+    }
+
+    pub fn from_params(params: &HashMap<String, String>) -> Result<SyncUpdate, MessageError> {
+        let mut plan = SyncPlan::new();
+
+        for (path, plan_entry) in params {
+            if !path.starts_with(FROM_PREFIX) {
+                plan.entries.insert(path.clone(), plan_entry.parse::<SyncPlanEntry>()?);
+            }
+        }
+
+        for (path, from) in params {
+            if let Some(path) = path.strip_prefix(FROM_PREFIX) {
+                let plan_entry = plan.entries.get_mut(path).ok_or(MessageError::InvalidParam {
+                    name: "from",
+                    value: from.clone(),
+                })?;
+                plan_entry.from = Some(from.clone());
+            }
+        }
+
+        Ok(SyncUpdate { plan })
+    }
+}
+
+const BASE_PREFIX: &str = "#base:";
+
+/// PATH -> index entry, used by SyncIndexResponse and SyncIndexUpdate
+#[derive(Clone, Debug, PartialEq)]
+pub struct SyncIndex {
+    pub index: HashMap<String, IndexEntry>,
+    /// Last synced version of the files the client changed since, used to detect conflicts
+    pub base: HashMap<String, IndexEntry>,
+}
+
+impl SyncIndex {
+    pub fn to_params(&self) -> HashMap<String, String> {
+        let index = self
+            .index
+            .iter()
+            .map(|(path, entry)| (path.clone(), entry.to_string()));
+        let base = self
+            .base
+            .iter()
+            .map(|(path, entry)| (BASE_PREFIX.to_string() + path, entry.to_string()));
+
+        index.chain(base).collect()
+    }
+
+    pub fn from_params(params: &HashMap<String, String>) -> Result<SyncIndex, MessageError> {
+        let mut index = HashMap::new();
+        let mut base = HashMap::new();
+
+        for (path, entry) in params {
+            let entry = entry.parse::<IndexEntry>().map_err(|_| MessageError::InvalidParam {
+                name: "index_entry",
+                value: entry.clone(),
+            })?;
+
+            match path.strip_prefix(BASE_PREFIX) {
+                Some(path) => base.insert(path.to_string(), entry),
+                None => index.insert(path.clone(), entry),
+            };
+        }
+
+        Ok(SyncIndex { index, base })
+    }
+}
@@ -0,0 +1,218 @@
+use std::{collections::HashMap, fmt};
+
+use crate::data::{delta::Signature, packets::{TransferCancelReason, TransferDenyReason}};
+
+use super::{
+    params::{parse, parse_optional, required},
+    MessageError,
+};
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum TransferDirection {
+    ToServer,
+    ToClient,
+}
+
+impl TransferDirection {
+    pub fn from_string(direction: &str) -> Result<TransferDirection, MessageError> {
+        match direction {
+            "toServer" => Ok(TransferDirection::ToServer),
+            "toClient" => Ok(TransferDirection::ToClient),
+            _ => Err(MessageError::InvalidParam {
+                name: "direction",
+                value: direction.to_string(),
+            }),
+        }
+    }
+}
+
+impl fmt::Display for TransferDirection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TransferDirection::ToServer => write!(f, "toServer"),
+            TransferDirection::ToClient => write!(f, "toClient"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct TransferRequest {
+    pub file_path: String,
+    pub transfer_id: u32,
+    pub direction: TransferDirection,
+    /// Only known up front for uploads, downloads get it in the ack
+    pub modified_time: Option<u64>,
+    /// Uploads: whether the client can send a delta against the server's copy
+    pub delta: bool,
+    /// Downloads: signature of the client's copy, so the server can send a delta
+    pub signature: Option<Signature>,
+    /// Uploads: key of the file version being sent. Downloads: key of the partial file the client has.
+    pub key: Option<String>,
+    /// Downloads: how much of the partial file the client has
+    pub offset: u64,
+}
+
+impl TransferRequest {
+    pub fn to_params(&self) -> HashMap<String, String> {
+        let mut params = HashMap::new();
+        params.insert("file_path".to_string(), self.file_path.clone());
+        params.insert("transfer_id".to_string(), self.transfer_id.to_string());
+        params.insert("direction".to_string(), self.direction.to_string());
+
+        if let Some(modified_time) = self.modified_time {
+            params.insert("modified_time".to_string(), modified_time.to_string());
+        }
+
+        if self.delta {
+            params.insert("delta".to_string(), self.delta.to_string());
+        }
+
+        if let Some(signature) = &self.signature {
+            params.insert("signature".to_string(), signature.to_string());
+        }
+
+        if let Some(key) = &self.key {
+            params.insert("key".to_string(), key.clone());
+        }
+
+        if self.offset > 0 {
+            params.insert("offset".to_string(), self.offset.to_string());
+        }
+
+        params
+    }
+
+    pub fn from_params(params: &HashMap<String, String>) -> Result<TransferRequest, MessageError> {
+        Ok(TransferRequest {
+            file_path: required(params, "file_path")?.clone(),
+            transfer_id: parse(params, "transfer_id")?,
+            direction: TransferDirection::from_string(required(params, "direction")?)?,
+            modified_time: parse_optional(params, "modified_time")?,
+            delta: parse_optional(params, "delta")?.unwrap_or(false),
+            signature: parse_optional(params, "signature")?,
+            key: params.get("key").cloned(),
+            offset: parse_optional(params, "offset")?.unwrap_or(0),
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct TransferAck {
+    pub transfer_id: u32,
+    pub job_id: u32,
+    pub modified_time: Option<u64>,
+    /// Downloads: whether the server sends a delta against the requested signature
+    pub delta: bool,
+    /// Uploads: signature of the server's copy, the client sends a delta against it
+    pub signature: Option<Signature>,
+    /// Downloads: key of the file version the server sends
+    pub key: Option<String>,
+    /// Where the transfer continues, 0 unless the receiver kept a partial file of the same version
+    pub offset: u64,
+}
+
+impl TransferAck {
+    pub fn to_params(&self) -> HashMap<String, String> {
+        let mut params = HashMap::new();
+        params.insert("transfer_id".to_string(), self.transfer_id.to_string());
+        params.insert("job_id".to_string(), self.job_id.to_string());
+
+        if let Some(modified_time) = self.modified_time {
+            params.insert("modified_time".to_string(), modified_time.to_string());
+        }
+
+        if self.delta {
+            params.insert("delta".to_string(), self.delta.to_string());
+        }
+
+        if let Some(signature) = &self.signature {
+            params.insert("signature".to_string(), signature.to_string());
+        }
+
+        if let Some(key) = &self.key {
+            params.insert("key".to_string(), key.clone());
+        }
+
+        if self.offset > 0 {
+            params.insert("offset".to_string(), self.offset.to_string());
+        }
+
+        params
+    }
+
+    pub fn from_params(params: &HashMap<String, String>) -> Result<TransferAck, MessageError> {
+        Ok(TransferAck {
+            transfer_id: parse(params, "transfer_id")?,
+            job_id: parse(params, "job_id")?,
+            modified_time: parse_optional(params, "modified_time")?,
+            delta: parse_optional(params, "delta")?.unwrap_or(false),
+            signature: parse_optional(params, "signature")?,
+            key: params.get("key").cloned(),
+            offset: parse_optional(params, "offset")?.unwrap_or(0),
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct TransferDeny {
+    pub transfer_id: u32,
+    pub reason: TransferDenyReason,
+}
+
+impl TransferDeny {
+    pub fn to_params(&self) -> HashMap<String, String> {
+        let mut params = HashMap::new();
+        params.insert("transfer_id".to_string(), self.transfer_id.to_string());
+        params.insert("reason".to_string(), self.reason.to_string());
+        params
+    }
+
+    pub fn from_params(params: &HashMap<String, String>) -> Result<TransferDeny, MessageError> {
+        Ok(TransferDeny {
+            transfer_id: parse(params, "transfer_id")?,
+            reason: TransferDenyReason::from_string(required(params, "reason")?)?,
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct TransferStart {
+    pub job_id: u32,
+}
+
+impl TransferStart {
+    pub fn to_params(&self) -> HashMap<String, String> {
+        let mut params = HashMap::new();
+        params.insert("job_id".to_string(), self.job_id.to_string());
+        params
+    }
+
+    pub fn from_params(params: &HashMap<String, String>) -> Result<TransferStart, MessageError> {
+        Ok(TransferStart {
+            job_id: parse(params, "job_id")?,
+        })
+    }
+}
+
+/// Either side giving up a running transfer, the receiver drops what it got so far
+#[derive(Clone, Debug, PartialEq)]
+pub struct TransferCancel {
+    pub job_id: u32,
+    pub reason: TransferCancelReason,
+}
+
+impl TransferCancel {
+    pub fn to_params(&self) -> HashMap<String, String> {
+        let mut params = HashMap::new();
+        params.insert("job_id".to_string(), self.job_id.to_string());
+        params.insert("reason".to_string(), self.reason.to_string());
+        params
+    }
+
+    pub fn from_params(params: &HashMap<String, String>) -> Result<TransferCancel, MessageError> {
+        Ok(TransferCancel {
+            job_id: parse(params, "job_id")?,
+            reason: TransferCancelReason::from_string(required(params, "reason")?)?,
+        })
+    }
+}
@@ -1,2 +1,5 @@
+pub mod delta;
+pub mod handshake;
+pub mod messages;
 pub mod packets;
 pub mod syncing;
@@ -0,0 +1,93 @@
+use super::DataPacket;
+
+pub const CHECKSUM_SIZE: usize = 32;
+
+/// BLAKE3 hash of a chunk or of a whole file
+pub type Checksum = [u8; CHECKSUM_SIZE];
+
+pub fn chunk_checksum(data: &[u8]) -> Checksum {
+    *blake3::hash(data).as_bytes()
+}
+
+/// Running checksum over every chunk of a transfer, used by the sender to fill in the
+/// end packet and by the receiver to check it
+#[derive(Clone, Default)]
+pub struct TransferChecksum {
+    hasher: blake3::Hasher,
+}
+
+impl TransferChecksum {
+    pub fn new() -> TransferChecksum {
+        TransferChecksum {
+            hasher: blake3::Hasher::new(),
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        self.hasher.update(data);
+    }
+
+    pub fn finalize(&self) -> Checksum {
+        *self.hasher.finalize().as_bytes()
+    }
+
+    /// Checks a received packet and adds its data to the running checksum. Chunks are checked
+    /// against their own checksum, the end packet against the checksum of everything before it.
+    pub fn verify(&mut self, packet: &DataPacket) -> bool {
+        if !packet.has_data {
+            return self.finalize() == packet.checksum;
+        }
+
+        if chunk_checksum(&packet.data) != packet.checksum {
+            return false;
+        }
+
+        self.update(&packet.data);
+        true
+    }
+
+    /// Checks a chunk without adding it, for transfers where the file is not the data itself
+    pub fn verify_chunk(&self, packet: &DataPacket) -> bool {
+        chunk_checksum(&packet.data) == packet.checksum
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_verify_transfer() {
+        let chunks = [vec![1, 2, 3], vec![4, 5, 6]];
+
+        let mut sender = TransferChecksum::new();
+        let mut packets: Vec<DataPacket> = chunks
+            .iter()
+            .enumerate()
+            .map(|(i, chunk)| {
+                sender.update(chunk);
+                DataPacket::new(1, chunk.clone(), i as u32 + 1)
+            })
+            .collect();
+        packets.push(DataPacket::new_end(1, 3, sender.finalize()));
+
+        let mut receiver = TransferChecksum::new();
+        assert!(packets.iter().all(|packet| receiver.verify(packet)));
+    }
+
+    #[test]
+    fn test_verify_corrupted_chunk() {
+        let mut packet = DataPacket::new(1, vec![1, 2, 3], 1);
+        packet.data[1] = 9;
+
+        assert!(!TransferChecksum::new().verify(&packet));
+    }
+
+    #[test]
+    fn test_verify_wrong_file_checksum() {
+        let mut receiver = TransferChecksum::new();
+
+        assert!(receiver.verify(&DataPacket::new(1, vec![1, 2, 3], 1)));
+        assert!(!receiver.verify(&DataPacket::new_end(1, 2, chunk_checksum(&[1, 2]))));
+    }
+}
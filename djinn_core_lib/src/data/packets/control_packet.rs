@@ -1,8 +1,10 @@
-use std::{collections::HashMap, any::Any};
+use std::{collections::HashMap, any::Any, fmt};
 
-use super::{PacketType, packet::Packet};
+use crate::data::messages::MessageError;
 
-#[derive(Copy, Clone)]
+use super::{PacketType, PacketError, packet::Packet, packet_error::ensure_length, params_codec::{decode_params, encode_params, encoded_params_size}};
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum ControlPacketType {
     EchoRequest,
     EchoReply,
@@ -18,53 +20,103 @@ pub enum ControlPacketType {
     SyncIndexRequest,
     SyncIndexResponse,
     SyncIndexUpdate,
-    None
+    None,
+    Hello,
+    HelloAck,
+    HelloDeny,
+    Authenticate,
+    AuthenticateAck,
+    AuthenticateDeny
 }
 
 impl ControlPacketType {
-    fn from_byte(byte: u8) -> ControlPacketType {
+    fn from_byte(byte: u8) -> Result<ControlPacketType, PacketError> {
         match byte {
-            0 => ControlPacketType::EchoRequest,
-            1 => ControlPacketType::EchoReply,
-            2 => ControlPacketType::TransferRequest,
-            3 => ControlPacketType::TransferAck,
-            4 => ControlPacketType::TransferDeny,
-            5 => ControlPacketType::TransferStart,
-            6 => ControlPacketType::TransferCancel,
-            7 => ControlPacketType::SyncRequest,
-            8 => ControlPacketType::SyncAck,
-            9 => ControlPacketType::SyncDeny,
-            10 => ControlPacketType::SyncUpdate,
-            11 => ControlPacketType::SyncIndexRequest,
-            12 => ControlPacketType::SyncIndexResponse,
-            13 => ControlPacketType::SyncIndexUpdate,
-            14 => ControlPacketType::None,
-            _ => panic!("Invalid control packet type"),
+            0 => Ok(ControlPacketType::EchoRequest),
+            1 => Ok(ControlPacketType::EchoReply),
+            2 => Ok(ControlPacketType::TransferRequest),
+            3 => Ok(ControlPacketType::TransferAck),
+            4 => Ok(ControlPacketType::TransferDeny),
+            5 => Ok(ControlPacketType::TransferStart),
+            6 => Ok(ControlPacketType::TransferCancel),
+            7 => Ok(ControlPacketType::SyncRequest),
+            8 => Ok(ControlPacketType::SyncAck),
+            9 => Ok(ControlPacketType::SyncDeny),
+            10 => Ok(ControlPacketType::SyncUpdate),
+            11 => Ok(ControlPacketType::SyncIndexRequest),
+            12 => Ok(ControlPacketType::SyncIndexResponse),
+            13 => Ok(ControlPacketType::SyncIndexUpdate),
+            14 => Ok(ControlPacketType::None),
+            15 => Ok(ControlPacketType::Hello),
+            16 => Ok(ControlPacketType::HelloAck),
+            17 => Ok(ControlPacketType::HelloDeny),
+            18 => Ok(ControlPacketType::Authenticate),
+            19 => Ok(ControlPacketType::AuthenticateAck),
+            20 => Ok(ControlPacketType::AuthenticateDeny),
+            _ => Err(PacketError::InvalidControlPacketType(byte)),
         }
     }
 }
 
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum TransferDenyReason {
     FileNotFound,
     FileWriteLock,
-    FileReadLock
+    FileReadLock,
+    ChecksumMismatch,
+    /// The user has no access to the path, or only read access for a write
+    PermissionDenied
 }
 
 impl TransferDenyReason {
-    pub fn from_string(reason: &str) -> TransferDenyReason {
+    pub fn from_string(reason: &str) -> Result<TransferDenyReason, MessageError> {
         match reason {
-            "FileNotFound" => TransferDenyReason::FileNotFound,
-            "FileWriteLock" => TransferDenyReason::FileWriteLock,
-            "FileReadLock" => TransferDenyReason::FileReadLock,
-            _ => panic!("Invalid transfer deny reason"),
+            "FileNotFound" => Ok(TransferDenyReason::FileNotFound),
+            "FileWriteLock" => Ok(TransferDenyReason::FileWriteLock),
+            "FileReadLock" => Ok(TransferDenyReason::FileReadLock),
+            "ChecksumMismatch" => Ok(TransferDenyReason::ChecksumMismatch),
+            "PermissionDenied" => Ok(TransferDenyReason::PermissionDenied),
+            _ => Err(MessageError::InvalidParam { name: "reason", value: reason.to_string() }),
         }
     }
+}
 
-    pub fn to_string(&self) -> String {
+impl fmt::Display for TransferDenyReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            TransferDenyReason::FileNotFound => "FileNotFound".to_string(),
-            TransferDenyReason::FileWriteLock => "FileWriteLock".to_string(),
-            TransferDenyReason::FileReadLock => "FileReadLock".to_string(),
+            TransferDenyReason::FileNotFound => write!(f, "FileNotFound"),
+            TransferDenyReason::FileWriteLock => write!(f, "FileWriteLock"),
+            TransferDenyReason::FileReadLock => write!(f, "FileReadLock"),
+            TransferDenyReason::ChecksumMismatch => write!(f, "ChecksumMismatch"),
+            TransferDenyReason::PermissionDenied => write!(f, "PermissionDenied"),
+        }
+    }
+}
+
+/// Why a running transfer was given up
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum TransferCancelReason {
+    /// The file changed on the sending side while it was being sent
+    SourceChanged,
+    /// The receiver's copy changed while the new version was coming in
+    TargetChanged
+}
+
+impl TransferCancelReason {
+    pub fn from_string(reason: &str) -> Result<TransferCancelReason, MessageError> {
+        match reason {
+            "SourceChanged" => Ok(TransferCancelReason::SourceChanged),
+            "TargetChanged" => Ok(TransferCancelReason::TargetChanged),
+            _ => Err(MessageError::InvalidParam { name: "reason", value: reason.to_string() }),
+        }
+    }
+}
+
+impl fmt::Display for TransferCancelReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TransferCancelReason::SourceChanged => write!(f, "SourceChanged"),
+            TransferCancelReason::TargetChanged => write!(f, "TargetChanged"),
         }
     }
 }
@@ -89,8 +141,10 @@ impl ControlPacket {
 }
 
 impl Packet for ControlPacket {
-    fn fill_from_buffer(&mut self, buffer: &Vec<u8>) {
-        self.control_packet_type = ControlPacketType::from_byte(buffer[5]);
+    fn fill_from_buffer(&mut self, buffer: &[u8]) -> Result<(), PacketError> {
+        ensure_length(buffer, PacketType::Control.header_size())?;
+
+        self.control_packet_type = ControlPacketType::from_byte(buffer[5])?;
         let job_id = u32::from_be_bytes([buffer[6], buffer[7], buffer[8], buffer[9]]);
 
         if job_id != 0 {
@@ -100,23 +154,9 @@ impl Packet for ControlPacket {
         }
 
 
-        self.params = HashMap::new();
+        self.params = decode_params(&buffer[10..])?;
 
-        let buffer = &buffer[10..];
-
-        if buffer.len() > 2 {
-            let params_string = String::from_utf8(buffer.to_vec()).unwrap();
-            let params_string_split = params_string.split(';');
-
-            for param in params_string_split {
-                if param.len() < 3 {
-                    continue;
-                }
-                let param_split = param.split('=');
-                let param_split_vec: Vec<&str> = param_split.collect();
-                self.params.insert(param_split_vec[0].to_string(), param_split_vec[1].to_string());
-            }
-        }
+        Ok(())
     }
 
     fn to_buffer(&self) -> Vec<u8> {
@@ -127,24 +167,13 @@ impl Packet for ControlPacket {
         buffer.push(self.control_packet_type as u8);
         buffer.extend((self.job_id.unwrap_or(0).to_be_bytes()).to_vec());
 
-        for (key, value) in &self.params {
-            buffer.extend(key.as_bytes());
-            buffer.push(b'=');
-            buffer.extend(value.as_bytes());
-            buffer.push(b';');
-        }
+        buffer.extend(encode_params(&self.params));
 
         buffer
     }
 
     fn calculate_packet_size(&self) -> u32 {
-        let mut size: u32 = 10;
-
-        for (key, value) in &self.params {
-            size += (key.len() + value.len() + 2) as u32;
-        }
-
-        size
+        (10 + encoded_params_size(&self.params)) as u32
     }
 
     fn get_packet_type(&self) -> PacketType {
@@ -180,7 +209,7 @@ mod tests {
             job_id: None,
             params: HashMap::new(),
         };
-        control_packet2.fill_from_buffer(&buffer);
+        control_packet2.fill_from_buffer(&buffer).unwrap();
 
 
         assert!(matches!(control_packet2.packet_type, PacketType::Control));
@@ -188,4 +217,19 @@ mod tests {
         assert_eq!(control_packet2.job_id.unwrap(), 10);
         assert_eq!(control_packet2.params.get("a").unwrap(), "b");
     }
+
+    #[test]
+    fn test_control_packet_with_separators_in_params() {
+        let mut params = HashMap::new();
+        params.insert("file_path".to_string(), "/weird;name=with=equals.txt".to_string());
+        let control_packet = ControlPacket::new(ControlPacketType::TransferRequest, params.clone());
+
+        let buffer = control_packet.to_buffer();
+        assert_eq!(buffer.len() as u32, control_packet.calculate_packet_size());
+
+        let mut control_packet2 = ControlPacket::new(ControlPacketType::None, HashMap::new());
+        control_packet2.fill_from_buffer(&buffer).unwrap();
+
+        assert_eq!(control_packet2.params, params);
+    }
 }
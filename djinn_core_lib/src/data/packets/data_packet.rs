@@ -1,6 +1,12 @@
-use std::any::Any;
+use std::{any::Any, borrow::Cow};
 
-use super::{PacketType, packet::Packet};
+use super::{PacketType, PacketError, Checksum, CHECKSUM_SIZE, chunk_checksum, packet::Packet, packet_error::ensure_length};
+
+/// Header flag of a packet whose data is lz4 compressed
+const FLAG_COMPRESSED: u8 = 1;
+
+/// Largest size compressed data may claim to unpack to, well above the 60 KB chunks that are sent
+const MAX_DECOMPRESSED_SIZE: usize = 1024 * 1024;
 
 
 #[derive(Clone)]
@@ -8,8 +14,13 @@ pub struct DataPacket {
     pub packet_type: PacketType,
     pub job_id: u32,
     pub packet_number: u32,
+    /// Checksum of the data, or of the whole file for the empty end packet
+    pub checksum: Checksum,
     pub data: Vec<u8>,
-    pub has_data: bool
+    pub has_data: bool,
+    /// Compress the data on the wire when that makes it smaller. Only set when the peer agreed to compression,
+    /// the data of a received packet is always decompressed already.
+    pub compress: bool
 }
 
 impl DataPacket {
@@ -20,37 +31,87 @@ impl DataPacket {
             packet_type: PacketType::Data,
             job_id,
             packet_number,
+            checksum: chunk_checksum(&data),
             data,
-            has_data
+            has_data,
+            compress: false
         }
     }
+
+    /// Empty packet which ends a transfer, carrying the checksum of the whole file
+    pub fn new_end(job_id: u32, packet_number: u32, file_checksum: Checksum) -> DataPacket {
+        let mut packet = DataPacket::new(job_id, vec![], packet_number);
+        packet.checksum = file_checksum;
+        packet
+    }
+
+    pub fn with_compression(mut self, compress: bool) -> DataPacket {
+        self.compress = compress;
+        self
+    }
+
+    /// Flags and data as they go on the wire. Data which does not shrink, like media, is sent as it is.
+    fn payload(&self) -> (u8, Cow<'_, [u8]>) {
+        if self.compress && self.has_data {
+            let compressed = lz4_flex::block::compress_prepend_size(&self.data);
+            if compressed.len() < self.data.len() {
+                return (FLAG_COMPRESSED, Cow::Owned(compressed));
+            }
+        }
+
+        (0, Cow::Borrowed(&self.data))
+    }
+}
+
+fn decompress(payload: &[u8]) -> Result<Vec<u8>, PacketError> {
+    let (size, compressed) = lz4_flex::block::uncompressed_size(payload)
+        .map_err(|error| PacketError::InvalidCompression(error.to_string()))?;
+
+    if size > MAX_DECOMPRESSED_SIZE {
+        return Err(PacketError::InvalidCompression(format!(
+            "data would unpack to {} bytes, more than the maximum of {} bytes",
+            size, MAX_DECOMPRESSED_SIZE
+        )));
+    }
+
+    lz4_flex::block::decompress(compressed, size).map_err(|error| PacketError::InvalidCompression(error.to_string()))
 }
 
 impl Packet for DataPacket {
-    fn fill_from_buffer(&mut self, buffer: &Vec<u8>){
+    fn fill_from_buffer(&mut self, buffer: &[u8]) -> Result<(), PacketError> {
+        ensure_length(buffer, PacketType::Data.header_size())?;
+
         self.job_id = u32::from_be_bytes([buffer[5], buffer[6], buffer[7], buffer[8]]);
         self.packet_number = u32::from_be_bytes([buffer[9], buffer[10], buffer[11], buffer[12]]);
-        if buffer.len() > 13 {
-            self.has_data = true;
-            self.data = buffer[13..].to_vec();
-        } else {
-            self.data = vec![]
-        }
+        let flags = buffer[13];
+        self.checksum.copy_from_slice(&buffer[14..14 + CHECKSUM_SIZE]);
+
+        let header_size = PacketType::Data.header_size();
+        let payload = &buffer[header_size..];
+        self.compress = flags & FLAG_COMPRESSED != 0;
+        self.data = if self.compress { decompress(payload)? } else { payload.to_vec() };
+        self.has_data = !self.data.is_empty();
+
+        Ok(())
     }
 
     fn to_buffer(&self) -> Vec<u8> {
+        let (flags, payload) = self.payload();
+
         let mut buffer: Vec<u8> = Vec::new();
-        buffer.extend(self.calculate_packet_size().to_be_bytes().to_vec());
+        buffer.extend(((PacketType::Data.header_size() + payload.len()) as u32).to_be_bytes().to_vec());
         buffer.push(self.packet_type as u8);
         buffer.extend(self.job_id.to_be_bytes().to_vec());
         buffer.extend(self.packet_number.to_be_bytes().to_vec());
-        buffer.extend(self.data.to_vec());
+        buffer.push(flags);
+        buffer.extend(self.checksum);
+        buffer.extend(payload.iter());
 
         buffer
     }
 
     fn calculate_packet_size(&self) -> u32 {
-        13 + self.data.len() as u32
+        (PacketType::Data.header_size() + self.payload().1.len()) as u32
     }
 
     fn get_packet_type(&self) -> PacketType {
@@ -73,10 +134,64 @@ mod tests {
         let buffer = data_packet.to_buffer();
 
         let mut data_packet2 = DataPacket::new(0, Vec::new(), 0);
-        data_packet2.fill_from_buffer(&buffer);
+        data_packet2.fill_from_buffer(&buffer).unwrap();
 
         assert_eq!(data_packet.job_id, data_packet2.job_id);
         assert_eq!(data_packet.data, data_packet2.data);
         assert_eq!(data_packet.packet_number, data_packet2.packet_number);
+        assert_eq!(data_packet.checksum, data_packet2.checksum);
+    }
+
+    #[test]
+    fn test_end_packet() {
+        let data_packet = DataPacket::new_end(3, 7, [42; CHECKSUM_SIZE]);
+        let buffer = data_packet.to_buffer();
+
+        let mut data_packet2 = DataPacket::new(0, vec![1], 0);
+        data_packet2.fill_from_buffer(&buffer).unwrap();
+
+        assert!(!data_packet2.has_data);
+        assert_eq!(data_packet2.checksum, [42; CHECKSUM_SIZE]);
+    }
+
+    #[test]
+    fn test_compressed_packet() {
+        let text = b"{\"name\": \"djinn\", \"synced\": true}\n".repeat(1000);
+        let data_packet = DataPacket::new(1, text.clone(), 1).with_compression(true);
+        let buffer = data_packet.to_buffer();
+
+        assert!(buffer.len() < text.len() / 5);
+        assert_eq!(buffer[13], FLAG_COMPRESSED);
+
+        let mut data_packet2 = DataPacket::new(0, Vec::new(), 0);
+        data_packet2.fill_from_buffer(&buffer).unwrap();
+
+        assert_eq!(data_packet2.data, text);
+        assert_eq!(data_packet2.checksum, chunk_checksum(&text));
+    }
+
+    #[test]
+    fn test_incompressible_packet() {
+        // Like media, random bytes do not shrink and are sent as they are
+        let mut state = 7_u32;
+        let noise: Vec<u8> = (0..60000).map(|_| {
+            state = state.wrapping_mul(1103515245).wrapping_add(12345);
+            (state >> 16) as u8
+        }).collect();
+        let buffer = DataPacket::new(1, noise.clone(), 1).with_compression(true).to_buffer();
+
+        assert_eq!(buffer[13], 0);
+        assert_eq!(buffer.len(), PacketType::Data.header_size() + noise.len());
+    }
+
+    #[test]
+    fn test_compression_bomb() {
+        let mut buffer = DataPacket::new(1, vec![0; 10], 1).to_buffer();
+        buffer[13] = FLAG_COMPRESSED;
+        buffer.truncate(PacketType::Data.header_size());
+        buffer.extend(u32::MAX.to_le_bytes());
+
+        let mut data_packet = DataPacket::new(0, Vec::new(), 0);
+        assert!(matches!(data_packet.fill_from_buffer(&buffer), Err(PacketError::InvalidCompression(_))));
     }
 }
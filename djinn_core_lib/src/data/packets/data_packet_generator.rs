@@ -3,22 +3,39 @@ use std::{
     io::{BufReader, Read},
 };
 
-use super::DataPacket;
+use super::{DataPacket, TransferChecksum};
 
 pub struct DataPacketGenerator {
     job_id: u32,
     path: String,
+    offset: u64,
+    compress: bool,
 }
 
 impl DataPacketGenerator {
     pub fn new(job_id: u32, path: String) -> Self {
-        DataPacketGenerator { job_id, path }
+        DataPacketGenerator { job_id, path, offset: 0, compress: false }
+    }
+
+    /// Continues an interrupted transfer, the receiver already has everything before the offset
+    pub fn with_offset(mut self, offset: u64) -> Self {
+        self.offset = offset;
+        self
+    }
+
+    /// Compresses the packets when the receiver agreed to it
+    pub fn with_compression(mut self, compress: bool) -> Self {
+        self.compress = compress;
+        self
     }
 
     pub fn iter(&self) -> DataPacketGeneratorIterator {
         let file = File::open(self.path.clone()).unwrap();
         let buf_reader = BufReader::new(file);
-        DataPacketGeneratorIterator::new(self.job_id, buf_reader)
+        let mut iterator = DataPacketGeneratorIterator::new(self.job_id, buf_reader);
+        iterator.skip_bytes(self.offset);
+        iterator.compress = self.compress;
+        iterator
     }
 }
 
@@ -28,6 +45,8 @@ pub struct DataPacketGeneratorIterator {
     buffer: Vec<u8>,
     packet_count: usize,
     ended: bool,
+    checksum: TransferChecksum,
+    compress: bool,
 }
 
 impl DataPacketGeneratorIterator {
@@ -38,6 +57,8 @@ impl DataPacketGeneratorIterator {
             buf_reader,
             packet_count: 0,
             ended: false,
+            checksum: TransferChecksum::new(),
+            compress: false,
         }
     }
 }
@@ -70,10 +91,10 @@ impl Iterator for DataPacketGeneratorIterator {
         // Send end packet
         if !self.ended {
             self.ended = true;
-            return Some(DataPacket::new(
+            return Some(DataPacket::new_end(
                 self.job_id,
-                vec![],
                 (self.packet_count + 1) as u32,
+                self.checksum.finalize(),
             ));
         }
 
@@ -82,12 +103,33 @@ impl Iterator for DataPacketGeneratorIterator {
 }
 
 impl DataPacketGeneratorIterator {
+    /// Skips the start of the file without sending it. It still counts towards the checksum
+    /// in the end packet, which covers the whole file.
+    pub fn skip_bytes(&mut self, count: u64) {
+        let mut remaining = count as usize;
+        let mut temp_buffer = [0; 60000];
+
+        while remaining > 0 {
+            let bytes_read = self.buf_reader.read(&mut temp_buffer[..remaining.min(60000)]).unwrap();
+
+            if bytes_read == 0 {
+                break;
+            }
+
+            self.checksum.update(&temp_buffer[0..bytes_read]);
+            remaining -= bytes_read;
+        }
+    }
+
     fn generate_packet(&mut self) -> Option<DataPacket> {
+        self.checksum.update(&self.buffer);
+
         let packet = DataPacket::new(
             self.job_id,
             self.buffer.clone(),
             (self.packet_count + 1) as u32,
-        );
+        )
+        .with_compression(self.compress);
 
         Some(packet)
     }
@@ -106,11 +148,11 @@ mod tests {
         let file_path = dir.join("test_file.txt");
         let mut file = File::create(file_path.clone()).unwrap();
 
-        for (_i, _) in (0..60000).enumerate() {
+        for _ in 0..60000 {
             file.write_all(&[1_u8]).unwrap();
         }
 
-        for (_i, _) in (0..60000).enumerate() {
+        for _ in 0..60000 {
             file.write_all(&[2_u8]).unwrap();
         }
 
@@ -120,6 +162,10 @@ mod tests {
         let buf_reader = BufReader::new(file);
         let data_packet_generator = DataPacketGeneratorIterator::new(1, buf_reader);
 
+        let mut expected_checksum = TransferChecksum::new();
+        expected_checksum.update(&[1_u8; 60000]);
+        expected_checksum.update(&[2_u8; 60000]);
+
         let mut packet_count = 0;
 
         // Test packets
@@ -135,9 +181,26 @@ mod tests {
                 assert_eq!(packet.job_id, 1);
                 assert_eq!(packet.packet_number, 3);
                 assert_eq!(packet.data.len(), 0);
+                assert_eq!(packet.checksum, expected_checksum.finalize());
             }
         }
 
         assert_eq!(packet_count, 3);
     }
+
+    #[test]
+    fn test_data_packet_generator_offset() {
+        let dir = env::temp_dir();
+        let file_path = dir.join("test_file_offset.txt");
+        let data: Vec<u8> = (0..100_000).map(|i| (i % 251) as u8).collect();
+        std::fs::write(&file_path, &data).unwrap();
+
+        let generator = DataPacketGenerator::new(1, file_path.to_str().unwrap().to_string()).with_offset(70_000);
+        let packets: Vec<DataPacket> = generator.iter().collect();
+
+        // Only the rest is sent, the end packet covers the whole file
+        assert_eq!(packets.len(), 2);
+        assert_eq!(packets[0].data, data[70_000..]);
+        assert_eq!(packets[1].checksum, crate::data::packets::chunk_checksum(&data));
+    }
 }
@@ -1,8 +1,12 @@
 mod control_packet;
 pub use control_packet::ControlPacket;
 pub use control_packet::ControlPacketType;
+pub use control_packet::TransferCancelReason;
 pub use control_packet::TransferDenyReason;
 pub mod packet;
+mod packet_error;
+pub use packet_error::PacketError;
+pub mod params_codec;
 
 mod packet_type;
 pub use packet_type::PacketType;
@@ -10,9 +14,21 @@ pub use packet_type::PacketType;
 mod data_packet;
 pub use data_packet::DataPacket;
 
+mod checksum;
+pub use checksum::chunk_checksum;
+pub use checksum::Checksum;
+pub use checksum::TransferChecksum;
+pub use checksum::CHECKSUM_SIZE;
+
 mod packet_reader;
 pub use packet_reader::PacketReader;
+pub use packet_reader::DEFAULT_MAX_PACKET_SIZE;
 
 mod data_packet_generator;
 pub use data_packet_generator::DataPacketGeneratorIterator;
 pub use data_packet_generator::DataPacketGenerator;
+
+mod send_scheduler;
+pub use send_scheduler::DataStream;
+pub use send_scheduler::SendScheduler;
+pub use send_scheduler::DEFAULT_MAX_CONCURRENT_TRANSFERS;
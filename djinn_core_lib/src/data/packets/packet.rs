@@ -1,20 +1,20 @@
 use std::{any::Any, collections::HashMap};
 
-use super::{PacketType, ControlPacket, DataPacket, ControlPacketType};
+use super::{PacketType, PacketError, ControlPacket, DataPacket, ControlPacketType, packet_error::ensure_length};
 
 pub trait Packet: Send + Sync {
-    fn fill_from_buffer(&mut self, buffer: &Vec<u8>);
+    fn fill_from_buffer(&mut self, buffer: &[u8]) -> Result<(), PacketError>;
     fn to_buffer(&self) -> Vec<u8>;
     fn get_packet_type(&self) -> PacketType;
     fn calculate_packet_size(&self) -> u32;
     fn as_any(&self) -> &dyn Any;
 }
 
-pub fn get_packet_length(buffer: &Vec<u8>) -> u32 {
+pub fn get_packet_length(buffer: &[u8]) -> u32 {
     u32::from_be_bytes([buffer[0], buffer[1], buffer[2], buffer[3]])
 }
 
-pub fn duplicate_packet(packet: &Box<dyn Packet>) -> Box<dyn Packet> {
+pub fn duplicate_packet(packet: &dyn Packet) -> Box<dyn Packet> {
     let packet_type = packet.get_packet_type();
 
     let temporary_packet: Box<dyn Packet> = match packet_type {
@@ -31,24 +31,26 @@ pub fn duplicate_packet(packet: &Box<dyn Packet>) -> Box<dyn Packet> {
     temporary_packet
 }
 
-pub fn deserialize_packet(buffer: &Vec<u8>) -> Box<dyn Packet> {
+pub fn deserialize_packet(buffer: &[u8]) -> Result<Box<dyn Packet>, PacketError> {
+    ensure_length(buffer, 5)?;
+
     let packet_type_byte = buffer[4];
-    let packet_type = PacketType::from_byte(packet_type_byte);
+    let packet_type = PacketType::from_byte(packet_type_byte)?;
 
     let temporary_packet: Box<dyn Packet> = match packet_type {
         PacketType::Control => {
             let mut control_packet = ControlPacket::new(ControlPacketType::None, HashMap::new());
-            control_packet.fill_from_buffer(buffer);
+            control_packet.fill_from_buffer(buffer)?;
             Box::new(control_packet)
         },
         PacketType::Data => {
             let mut data_packet = DataPacket::new(0, Vec::new(), 0);
-            data_packet.fill_from_buffer(buffer);
+            data_packet.fill_from_buffer(buffer)?;
             Box::new(data_packet)
         }
     };
 
-    temporary_packet
+    Ok(temporary_packet)
 }
 
 #[cfg(test)]
@@ -58,7 +60,7 @@ mod tests {
     #[test]
     fn test_deserialize_data_packet() {
         let buffer: Vec<u8> = DataPacket::new(0, vec![], 0).to_buffer();
-        let boxed_packet = deserialize_packet(&buffer);
+        let boxed_packet = deserialize_packet(&buffer).unwrap();
         let packet_ref: &dyn Packet = boxed_packet.as_ref();
 
         assert!(matches!(packet_ref.get_packet_type(), PacketType::Data));
@@ -67,7 +69,7 @@ mod tests {
     #[test]
     fn test_deserialize_control_packet() {
         let buffer: Vec<u8> = ControlPacket::new(ControlPacketType::None, HashMap::new()).to_buffer();
-        let boxed_packet = deserialize_packet(&buffer);
+        let boxed_packet = deserialize_packet(&buffer).unwrap();
         let packet_ref: &dyn Packet = boxed_packet.as_ref();
 
         assert!(matches!(packet_ref.get_packet_type(), PacketType::Control));
@@ -79,7 +81,7 @@ mod tests {
 
         // Downcast to Box<dyn Packet> to be able to use the duplicate_packet function
         let boxed_packet: Box<dyn Packet> = Box::new(data_packet);
-        let duplicated_packet = duplicate_packet(&boxed_packet);
+        let duplicated_packet = duplicate_packet(boxed_packet.as_ref());
 
         let duplicated_data_packet = duplicated_packet.as_any().downcast_ref::<DataPacket>().unwrap();
 
@@ -89,4 +91,49 @@ mod tests {
         assert_eq!(duplicated_data_packet.packet_number, duplicated_data_packet.packet_number);
     }
 
+    #[test]
+    fn test_deserialize_invalid_packet_type() {
+        let mut buffer: Vec<u8> = DataPacket::new(0, vec![], 0).to_buffer();
+        buffer[4] = 7;
+
+        assert!(matches!(deserialize_packet(&buffer), Err(PacketError::InvalidPacketType(7))));
+    }
+
+    #[test]
+    fn test_deserialize_invalid_control_packet_type() {
+        let mut buffer: Vec<u8> = ControlPacket::new(ControlPacketType::EchoRequest, HashMap::new()).to_buffer();
+        buffer[5] = 200;
+
+        assert!(matches!(deserialize_packet(&buffer), Err(PacketError::InvalidControlPacketType(200))));
+    }
+
+    #[test]
+    fn test_fuzz_crashes() {
+        // Inputs which used to panic, found by the deserialize_packet fuzz target
+        let crashes: [&[u8]; 6] = [
+            &[],
+            &[0, 0, 0, 3],
+            &[0, 0, 0, 5, 7],
+            &[0, 0, 0, 10, 0, 255, 0, 0, 0, 0],
+            &[0, 0, 0, 6, 0, 0],
+            &[0, 0, 0, 9, 1, 0, 0, 0, 1],
+        ];
+
+        for crash in crashes {
+            assert!(deserialize_packet(crash).is_err());
+        }
+    }
+
+    #[test]
+    fn test_deserialize_truncated_packets() {
+        let control_buffer = ControlPacket::new(ControlPacketType::EchoRequest, HashMap::new()).to_buffer();
+        let data_buffer = DataPacket::new(1, vec![], 0).to_buffer();
+
+        for buffer in [control_buffer, data_buffer] {
+            for length in 0..buffer.len() {
+                assert!(matches!(deserialize_packet(&buffer[..length]), Err(PacketError::Truncated { .. })));
+            }
+        }
+    }
+
 }
@@ -0,0 +1,71 @@
+use std::{error::Error, fmt, io};
+
+use super::params_codec::ParamsError;
+
+/// Everything that can go wrong while turning bytes from a peer into packets
+#[derive(Debug)]
+pub enum PacketError {
+    Io(io::Error),
+    InvalidPacketType(u8),
+    InvalidControlPacketType(u8),
+    Truncated { expected: usize, actual: usize },
+    InvalidParams(ParamsError),
+    FrameTooLarge { size: usize, max: usize },
+    FrameTooSmall { size: usize, min: usize },
+    InvalidCompression(String),
+}
+
+impl fmt::Display for PacketError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PacketError::Io(error) => write!(f, "failed to read packet: {}", error),
+            PacketError::InvalidPacketType(byte) => write!(f, "invalid packet type {}", byte),
+            PacketError::InvalidControlPacketType(byte) => write!(f, "invalid control packet type {}", byte),
+            PacketError::Truncated { expected, actual } => {
+                write!(f, "packet is truncated, expected at least {} bytes but got {}", expected, actual)
+            }
+            PacketError::InvalidParams(error) => write!(f, "invalid control packet params: {}", error),
+            PacketError::FrameTooLarge { size, max } => {
+                write!(f, "frame of {} bytes exceeds the maximum of {} bytes", size, max)
+            }
+            PacketError::FrameTooSmall { size, min } => {
+                write!(f, "frame of {} bytes is smaller than the {} byte header", size, min)
+            }
+            PacketError::InvalidCompression(reason) => write!(f, "invalid compressed data: {}", reason),
+        }
+    }
+}
+
+impl Error for PacketError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            PacketError::Io(error) => Some(error),
+            PacketError::InvalidParams(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for PacketError {
+    fn from(error: io::Error) -> Self {
+        PacketError::Io(error)
+    }
+}
+
+impl From<ParamsError> for PacketError {
+    fn from(error: ParamsError) -> Self {
+        PacketError::InvalidParams(error)
+    }
+}
+
+/// Fails with `Truncated` unless the buffer holds at least `expected` bytes
+pub fn ensure_length(buffer: &[u8], expected: usize) -> Result<(), PacketError> {
+    if buffer.len() < expected {
+        return Err(PacketError::Truncated {
+            expected,
+            actual: buffer.len(),
+        });
+    }
+
+    Ok(())
+}
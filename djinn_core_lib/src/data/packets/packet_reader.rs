@@ -2,62 +2,208 @@ use tokio::io::{BufReader, AsyncReadExt, AsyncRead};
 
 use crate::data::packets::packet::{deserialize_packet, get_packet_length};
 
-use super::packet::Packet;
+use super::{packet::Packet, PacketError, PacketType};
+
+/// Largest frame a peer may announce unless configured otherwise (max_packet_size on the server,
+/// --max-packet-size on the client). Sync indexes of big trees are the largest packets, data packets stay around 60 KB.
+pub const DEFAULT_MAX_PACKET_SIZE: usize = 16 * 1024 * 1024;
+
+// Length prefix plus packet type
+const FRAME_HEADER_SIZE: usize = 5;
 
 pub struct PacketReader {
     packets_processed: usize,
+    max_packet_size: usize,
     buffer: Vec<u8>
 }
 
+impl Default for PacketReader {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl PacketReader {
     pub fn new() -> PacketReader {
+        PacketReader::with_max_packet_size(DEFAULT_MAX_PACKET_SIZE)
+    }
+
+    pub fn with_max_packet_size(max_packet_size: usize) -> PacketReader {
         PacketReader {
             packets_processed: 0,
+            max_packet_size,
             buffer: Vec::with_capacity(131072)
         }
     }
 
-    pub async fn read<T>(&mut self, reader: &mut BufReader<T>, max_packets: Option<usize>) -> Vec<Box<dyn Packet>>
+    /// Reads until at least one packet is complete. An empty list means the peer closed the connection,
+    /// an error means the stream can no longer be trusted and should be dropped.
+    pub async fn read<T>(&mut self, reader: &mut BufReader<T>, max_packets: Option<usize>) -> Result<Vec<Box<dyn Packet>>, PacketError>
     where
         T: AsyncRead + Unpin,
     {
         let mut packets: Vec<Box<dyn Packet>> = Vec::with_capacity(10);
 
-        while packets.is_empty() {
+        loop {
+            // Hand out what is already buffered before reading more from the peer
+            self.extract_packets(&mut packets, max_packets)?;
+
+            if !packets.is_empty() {
+                return Ok(packets);
+            }
+
             let mut temp_buffer = [0; 65536];
-            let bytes_read = reader.read(&mut temp_buffer).await.unwrap();
+            let bytes_read = reader.read(&mut temp_buffer).await?;
 
             if bytes_read == 0 {
-                return packets;
+                return Ok(packets);
             }
 
             //Add to self buffer
             self.buffer.extend_from_slice(&temp_buffer[0..bytes_read]);
+        }
+    }
+
+    fn extract_packets(&mut self, packets: &mut Vec<Box<dyn Packet>>, max_packets: Option<usize>) -> Result<(), PacketError> {
+        //If more packets need to be read and the header of the next one is there
+        while self.buffer.len() >= FRAME_HEADER_SIZE && (max_packets.is_none() || packets.len() < max_packets.unwrap()) {
+            let packet_length = self.validate_frame_header()?;
+
+            //Check if packet is complete
+            if packet_length > self.buffer.len() {
+                break;
+            }
+
+            // Deserialize packet
+            let packet = deserialize_packet(&self.buffer[0..packet_length])?;
+
+            //Remove packet from buffer
+            self.buffer.drain(0..packet_length);
+
+            self.packets_processed += 1;
+
+            //Add packet to packets
+            packets.push(packet);
+        }
+
+        Ok(())
+    }
+
+    /// Checks the announced length against the packet type before any of the body is buffered
+    fn validate_frame_header(&self) -> Result<usize, PacketError> {
+        let packet_length = get_packet_length(&self.buffer) as usize;
+        let packet_type = PacketType::from_byte(self.buffer[4])?;
+
+        if packet_length > self.max_packet_size {
+            return Err(PacketError::FrameTooLarge {
+                size: packet_length,
+                max: self.max_packet_size,
+            });
+        }
+
+        if packet_length < packet_type.header_size() {
+            return Err(PacketError::FrameTooSmall {
+                size: packet_length,
+                min: packet_type.header_size(),
+            });
+        }
+
+        Ok(packet_length)
+    }
+}
 
-            //If buffer has more than 4 bytes and more packets need to be read
-            while self.buffer.len() > 4 && (max_packets.is_none() || packets.len() < max_packets.unwrap()) {
-                //Check if packet is complete
-                let packet_length = get_packet_length(&self.buffer) as usize;
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
 
-                //Check if packet can be extracted
-                if packet_length <= self.buffer.len() {
-                    // Deserialize packet
-                    let packet_vec = self.buffer[0..packet_length].to_vec();
-                    let packet = deserialize_packet(&packet_vec);
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
-                    //Remove packet from buffer
-                    self.buffer.drain(0..packet_length);
+    use crate::data::packets::{ControlPacket, ControlPacketType, DataPacket};
 
-                    self.packets_processed += 1;
+    use super::*;
+
+    #[tokio::test]
+    async fn test_read_split_across_calls() {
+        let mut stream = ControlPacket::new(ControlPacketType::EchoRequest, HashMap::new()).to_buffer();
+        stream.extend(DataPacket::new(1, vec![1, 2, 3], 0).to_buffer());
+
+        // Every read only returns a few bytes, so packets arrive in pieces
+        let chunks: Vec<&[u8]> = stream.chunks(3).collect();
+        let mut split_stream: Box<dyn AsyncRead + Unpin> = Box::new(chunks[0]);
+        for chunk in &chunks[1..] {
+            split_stream = Box::new(split_stream.chain(*chunk));
+        }
 
-                    //Add packet to packets
-                    packets.push(packet);
-                } else {
-                    break;
-                }
+        let mut reader = BufReader::new(split_stream);
+        let mut packet_reader = PacketReader::new();
+        let mut packets = Vec::new();
+
+        loop {
+            let read_packets = packet_reader.read(&mut reader, None).await.unwrap();
+
+            if read_packets.is_empty() {
+                break;
             }
+
+            packets.extend(read_packets);
         }
 
-        packets
+        assert_eq!(packets.len(), 2);
+        assert_eq!(packets[1].to_buffer(), DataPacket::new(1, vec![1, 2, 3], 0).to_buffer());
+    }
+
+    #[tokio::test]
+    async fn test_read_zero_length_frame() {
+        // A declared length of 0 used to panic while deserializing the empty frame
+        let stream: &[u8] = &[0, 0, 0, 0, 1];
+        let mut reader = BufReader::new(stream);
+
+        assert!(matches!(
+            PacketReader::new().read(&mut reader, None).await,
+            Err(PacketError::FrameTooSmall { size: 0, min: 46 })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_read_frame_too_large() {
+        // Announces a 4 GB packet, which has to be refused before anything is buffered
+        let stream: &[u8] = &[255, 255, 255, 255, 1, 0, 0];
+        let mut reader = BufReader::new(stream);
+
+        assert!(matches!(
+            PacketReader::with_max_packet_size(1024).read(&mut reader, None).await,
+            Err(PacketError::FrameTooLarge { size: 4294967295, max: 1024 })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_read_frame_at_max_size() {
+        let packet = DataPacket::new(1, vec![7; 978], 0);
+        let stream = packet.to_buffer();
+        let mut reader = BufReader::new(stream.as_slice());
+
+        let packets = PacketReader::with_max_packet_size(1024).read(&mut reader, None).await.unwrap();
+
+        assert_eq!(packets.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_read_returns_buffered_packets_first() {
+        let mut stream = ControlPacket::new(ControlPacketType::EchoRequest, HashMap::new()).to_buffer();
+        stream.extend(ControlPacket::new(ControlPacketType::EchoReply, HashMap::new()).to_buffer());
+
+        // The peer keeps the connection open after the second packet
+        let (mut peer, local) = tokio::io::duplex(1024);
+        peer.write_all(&stream).await.unwrap();
+
+        let mut reader = BufReader::new(local);
+        let mut packet_reader = PacketReader::new();
+
+        let first = packet_reader.read(&mut reader, Some(1)).await.unwrap();
+        let second = packet_reader.read(&mut reader, Some(1)).await.unwrap();
+
+        assert_eq!(first.len(), 1);
+        assert_eq!(second.len(), 1);
+        assert_eq!(second[0].to_buffer(), ControlPacket::new(ControlPacketType::EchoReply, HashMap::new()).to_buffer());
     }
 }
@@ -1,15 +1,25 @@
-#[derive(Copy, Clone)]
+use super::PacketError;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum PacketType {
   Control,
   Data,
 }
 
 impl PacketType {
-  pub fn from_byte(byte: u8) -> PacketType {
+  pub fn from_byte(byte: u8) -> Result<PacketType, PacketError> {
     match byte {
-      0 => PacketType::Control,
-      1 => PacketType::Data,
-      _ => panic!("Invalid packet type"),
+      0 => Ok(PacketType::Control),
+      1 => Ok(PacketType::Data),
+      _ => Err(PacketError::InvalidPacketType(byte)),
+    }
+  }
+
+  /// Size of the fixed part of a packet of this type, the smallest frame it can arrive in
+  pub fn header_size(&self) -> usize {
+    match self {
+      PacketType::Control => 10,
+      PacketType::Data => 46,
     }
   }
 }
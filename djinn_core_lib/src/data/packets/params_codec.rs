@@ -0,0 +1,198 @@
+use std::{collections::HashMap, error::Error, fmt};
+
+// Control packet params are encoded as a list of fields:
+// [key length: u32][key bytes][value length: u32][value bytes], repeated.
+// Nothing in a key or value is interpreted, so paths containing ';', '=' or any other byte survive the trip.
+
+const LENGTH_SIZE: usize = 4;
+
+pub type Field = (Vec<u8>, Vec<u8>);
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ParamsError {
+    Truncated,
+    InvalidUtf8,
+}
+
+impl fmt::Display for ParamsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParamsError::Truncated => write!(f, "params are truncated"),
+            ParamsError::InvalidUtf8 => write!(f, "params are not valid UTF-8"),
+        }
+    }
+}
+
+impl Error for ParamsError {}
+
+pub fn encode_fields<K, V>(fields: &[(K, V)]) -> Vec<u8>
+where
+    K: AsRef<[u8]>,
+    V: AsRef<[u8]>,
+{
+    let mut buffer = Vec::with_capacity(encoded_fields_size(fields));
+
+    for (key, value) in fields {
+        let key = key.as_ref();
+        let value = value.as_ref();
+
+        buffer.extend((key.len() as u32).to_be_bytes());
+        buffer.extend(key);
+        buffer.extend((value.len() as u32).to_be_bytes());
+        buffer.extend(value);
+    }
+
+    buffer
+}
+
+pub fn encoded_fields_size<K, V>(fields: &[(K, V)]) -> usize
+where
+    K: AsRef<[u8]>,
+    V: AsRef<[u8]>,
+{
+    fields
+        .iter()
+        .map(|(key, value)| 2 * LENGTH_SIZE + key.as_ref().len() + value.as_ref().len())
+        .sum()
+}
+
+pub fn decode_fields(buffer: &[u8]) -> Result<Vec<Field>, ParamsError> {
+    let mut fields = Vec::new();
+    let mut rest = buffer;
+
+    while !rest.is_empty() {
+        let key = take_length_prefixed(&mut rest)?;
+        let value = take_length_prefixed(&mut rest)?;
+        fields.push((key.to_vec(), value.to_vec()));
+    }
+
+    Ok(fields)
+}
+
+pub fn encode_params(params: &HashMap<String, String>) -> Vec<u8> {
+    encode_fields(&sorted_params(params))
+}
+
+pub fn encoded_params_size(params: &HashMap<String, String>) -> usize {
+    encoded_fields_size(&sorted_params(params))
+}
+
+pub fn decode_params(buffer: &[u8]) -> Result<HashMap<String, String>, ParamsError> {
+    let mut params = HashMap::new();
+
+    for (key, value) in decode_fields(buffer)? {
+        let key = String::from_utf8(key).map_err(|_| ParamsError::InvalidUtf8)?;
+        let value = String::from_utf8(value).map_err(|_| ParamsError::InvalidUtf8)?;
+        params.insert(key, value);
+    }
+
+    Ok(params)
+}
+
+// Sorted so the same params always produce the same bytes
+fn sorted_params(params: &HashMap<String, String>) -> Vec<(&String, &String)> {
+    let mut fields: Vec<(&String, &String)> = params.iter().collect();
+    fields.sort();
+    fields
+}
+
+fn take_length_prefixed<'a>(rest: &mut &'a [u8]) -> Result<&'a [u8], ParamsError> {
+    if rest.len() < LENGTH_SIZE {
+        return Err(ParamsError::Truncated);
+    }
+
+    let (length_bytes, remainder) = rest.split_at(LENGTH_SIZE);
+    let length = u32::from_be_bytes([length_bytes[0], length_bytes[1], length_bytes[2], length_bytes[3]]) as usize;
+
+    if remainder.len() < length {
+        return Err(ParamsError::Truncated);
+    }
+
+    let (field, remainder) = remainder.split_at(length);
+    *rest = remainder;
+
+    Ok(field)
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+
+    // Filenames built from the characters which broke the old `key=value;` encoding
+    fn hostile_filename() -> impl Strategy<Value = String> {
+        prop::collection::vec(
+            prop_oneof![
+                Just(";".to_string()),
+                Just("=".to_string()),
+                Just("==".to_string()),
+                Just("/".to_string()),
+                Just("..".to_string()),
+                Just("\0".to_string()),
+                Just("\n".to_string()),
+                Just(" ".to_string()),
+                Just("#".to_string()),
+                Just("%3B".to_string()),
+                any::<char>().prop_map(|c| c.to_string()),
+                "[a-zA-Z0-9._-]{1,8}",
+            ],
+            0..16,
+        )
+        .prop_map(|parts| parts.concat())
+    }
+
+    #[test]
+    fn test_params_with_separators() {
+        let mut params = HashMap::new();
+        params.insert("file_path".to_string(), "/a;b=c/d==e;.txt".to_string());
+        params.insert("a=b".to_string(), ";".to_string());
+        params.insert("".to_string(), "".to_string());
+
+        let buffer = encode_params(&params);
+
+        assert_eq!(buffer.len(), encoded_params_size(&params));
+        assert_eq!(decode_params(&buffer).unwrap(), params);
+    }
+
+    #[test]
+    fn test_truncated_params() {
+        let mut params = HashMap::new();
+        params.insert("file_path".to_string(), "/test.txt".to_string());
+        let buffer = encode_params(&params);
+
+        for length in 1..buffer.len() {
+            assert_eq!(decode_params(&buffer[..length]), Err(ParamsError::Truncated));
+        }
+    }
+
+    #[test]
+    fn test_invalid_utf8_params() {
+        let buffer = encode_fields(&[(b"file_path".to_vec(), vec![0xff, 0xfe])]);
+
+        assert_eq!(decode_params(&buffer), Err(ParamsError::InvalidUtf8));
+    }
+
+    proptest! {
+        #[test]
+        fn prop_fields_round_trip(fields in prop::collection::vec((any::<Vec<u8>>(), any::<Vec<u8>>()), 0..16)) {
+            let buffer = encode_fields(&fields);
+
+            prop_assert_eq!(buffer.len(), encoded_fields_size(&fields));
+            prop_assert_eq!(decode_fields(&buffer).unwrap(), fields);
+        }
+
+        #[test]
+        fn prop_hostile_filenames_round_trip(params in prop::collection::hash_map(hostile_filename(), hostile_filename(), 0..16)) {
+            let buffer = encode_params(&params);
+
+            prop_assert_eq!(decode_params(&buffer).unwrap(), params);
+        }
+
+        #[test]
+        fn prop_decode_never_panics(buffer in any::<Vec<u8>>()) {
+            let _ = decode_fields(&buffer);
+            let _ = decode_params(&buffer);
+        }
+    }
+}
@@ -0,0 +1,245 @@
+use std::{collections::VecDeque, future::poll_fn, io, sync::Arc, task::{Context, Poll}};
+
+use log::debug;
+use tokio::{
+    io::{AsyncWrite, AsyncWriteExt},
+    sync::{mpsc, oneshot, OwnedSemaphorePermit, Semaphore},
+};
+
+use super::{packet::Packet, DataPacket};
+
+/// How many transfers send their data at the same time unless configured otherwise
+pub const DEFAULT_MAX_CONCURRENT_TRANSFERS: usize = 4;
+
+// Packets a transfer may have waiting for the writer, enough to keep it busy without reading the whole file ahead
+const STREAM_BUFFER: usize = 4;
+
+enum Outgoing {
+    Packet(Vec<u8>),
+    /// Answered once everything queued before it is written
+    Flush(oneshot::Sender<io::Result<()>>),
+    Shutdown(oneshot::Sender<io::Result<()>>),
+}
+
+enum Next {
+    Control(Outgoing),
+    Stream(mpsc::Receiver<Outgoing>),
+    Data(Outgoing),
+    Closed,
+}
+
+/// Writes the packets of one connection from a single task. Control packets always go first,
+/// the data packets of running transfers take turns so one large file does not hold up the rest.
+#[derive(Clone)]
+pub struct SendScheduler {
+    control: mpsc::UnboundedSender<Outgoing>,
+    streams: mpsc::UnboundedSender<mpsc::Receiver<Outgoing>>,
+    slots: Arc<Semaphore>,
+}
+
+/// Data packets of one transfer. Holds one of the transfer slots of the connection until it is dropped.
+pub struct DataStream {
+    sender: mpsc::Sender<Outgoing>,
+    _slot: OwnedSemaphorePermit,
+}
+
+impl SendScheduler {
+    pub fn new<W>(writer: W, max_concurrent_transfers: usize) -> SendScheduler
+    where
+        W: AsyncWrite + Unpin + Send + 'static,
+    {
+        let (control, control_receiver) = mpsc::unbounded_channel();
+        let (streams, stream_receiver) = mpsc::unbounded_channel();
+
+        tokio::spawn(async move {
+            if let Err(error) = write_packets(writer, control_receiver, stream_receiver).await {
+                debug!("Stopped sending packets: {}", error);
+            }
+        });
+
+        SendScheduler {
+            control,
+            streams,
+            slots: Arc::new(Semaphore::new(max_concurrent_transfers.max(1))),
+        }
+    }
+
+    /// Queues a packet ahead of any waiting data packets
+    pub fn send_control(&self, packet: impl Packet) -> io::Result<()> {
+        self.control.send(Outgoing::Packet(packet.to_buffer())).map_err(|_| closed())
+    }
+
+    /// Waits until the control packets queued so far are written
+    pub async fn flush(&self) -> io::Result<()> {
+        let (done, result) = oneshot::channel();
+        self.control.send(Outgoing::Flush(done)).map_err(|_| closed())?;
+        result.await.map_err(|_| closed())?
+    }
+
+    /// Writes the queued control packets and closes the connection, running transfers are dropped
+    pub async fn shutdown(&self) -> io::Result<()> {
+        let (done, result) = oneshot::channel();
+        self.control.send(Outgoing::Shutdown(done)).map_err(|_| closed())?;
+        result.await.map_err(|_| closed())?
+    }
+
+    /// Waits for a free transfer slot and opens a stream for the data packets of a transfer
+    pub async fn open_stream(&self) -> io::Result<DataStream> {
+        let slot = self.slots.clone().acquire_owned().await.map_err(|_| closed())?;
+        let (sender, receiver) = mpsc::channel(STREAM_BUFFER);
+        self.streams.send(receiver).map_err(|_| closed())?;
+
+        Ok(DataStream { sender, _slot: slot })
+    }
+}
+
+impl DataStream {
+    /// Queues a packet, waiting while the transfer is ahead of the writer
+    pub async fn send(&self, packet: DataPacket) -> io::Result<()> {
+        self.sender.send(Outgoing::Packet(packet.to_buffer())).await.map_err(|_| closed())
+    }
+
+    /// Waits until every packet of the transfer is written
+    pub async fn finish(self) -> io::Result<()> {
+        let (done, result) = oneshot::channel();
+        self.sender.send(Outgoing::Flush(done)).await.map_err(|_| closed())?;
+        result.await.map_err(|_| closed())?
+    }
+}
+
+async fn write_packets<W>(
+    mut writer: W,
+    mut control: mpsc::UnboundedReceiver<Outgoing>,
+    mut new_streams: mpsc::UnboundedReceiver<mpsc::Receiver<Outgoing>>,
+) -> io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    let mut streams = VecDeque::new();
+
+    loop {
+        // Only flush once nothing else is waiting to be written
+        let next = match poll_fn(|cx| Poll::Ready(poll_next(cx, &mut control, &mut new_streams, &mut streams))).await {
+            Poll::Ready(next) => next,
+            Poll::Pending => {
+                writer.flush().await?;
+                poll_fn(|cx| poll_next(cx, &mut control, &mut new_streams, &mut streams)).await
+            }
+        };
+
+        match next {
+            Next::Control(outgoing) | Next::Data(outgoing) => match outgoing {
+                Outgoing::Packet(buffer) => writer.write_all(&buffer).await?,
+                Outgoing::Flush(done) => {
+                    let _ = done.send(writer.flush().await);
+                }
+                Outgoing::Shutdown(done) => {
+                    let _ = done.send(writer.shutdown().await);
+                    return Ok(());
+                }
+            },
+            Next::Stream(stream) => streams.push_back(stream),
+            Next::Closed => return writer.flush().await,
+        }
+    }
+}
+
+fn poll_next(
+    cx: &mut Context<'_>,
+    control: &mut mpsc::UnboundedReceiver<Outgoing>,
+    new_streams: &mut mpsc::UnboundedReceiver<mpsc::Receiver<Outgoing>>,
+    streams: &mut VecDeque<mpsc::Receiver<Outgoing>>,
+) -> Poll<Next> {
+    match control.poll_recv(cx) {
+        Poll::Ready(Some(outgoing)) => return Poll::Ready(Next::Control(outgoing)),
+        Poll::Ready(None) => return Poll::Ready(Next::Closed),
+        Poll::Pending => {}
+    }
+
+    if let Poll::Ready(Some(stream)) = new_streams.poll_recv(cx) {
+        return Poll::Ready(Next::Stream(stream));
+    }
+
+    // A stream which sent a packet goes to the back of the line
+    let mut index = 0;
+    while index < streams.len() {
+        match streams[index].poll_recv(cx) {
+            Poll::Ready(Some(outgoing)) => {
+                let stream = streams.remove(index).unwrap();
+                streams.push_back(stream);
+                return Poll::Ready(Next::Data(outgoing));
+            }
+            Poll::Ready(None) => {
+                streams.remove(index);
+            }
+            Poll::Pending => index += 1,
+        }
+    }
+
+    Poll::Pending
+}
+
+fn closed() -> io::Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, "Connection is closed")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use tokio::io::BufReader;
+
+    use crate::data::packets::{ControlPacket, ControlPacketType, PacketReader, PacketType};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_control_first_then_take_turns() {
+        // A small pipe keeps the writer waiting on the reader
+        let (writer, reader) = tokio::io::duplex(64);
+        let scheduler = SendScheduler::new(writer, 2);
+
+        let first = scheduler.open_stream().await.unwrap();
+        let second = scheduler.open_stream().await.unwrap();
+        for number in 0..3 {
+            first.send(DataPacket::new(1, vec![1; 1000], number)).await.unwrap();
+            second.send(DataPacket::new(2, vec![2; 1000], number)).await.unwrap();
+        }
+        scheduler.send_control(ControlPacket::new(ControlPacketType::EchoRequest, HashMap::new())).unwrap();
+
+        let read = async {
+            let mut reader = BufReader::new(reader);
+            let mut packet_reader = PacketReader::new();
+            let mut order = vec![];
+
+            while order.len() < 7 {
+                for packet in packet_reader.read(&mut reader, None).await.unwrap() {
+                    order.push(match packet.get_packet_type() {
+                        PacketType::Control => 0,
+                        PacketType::Data => packet.as_any().downcast_ref::<DataPacket>().unwrap().job_id,
+                    });
+                }
+            }
+
+            order
+        };
+
+        let (order, first_result, second_result) = tokio::join!(read, first.finish(), second.finish());
+        first_result.unwrap();
+        second_result.unwrap();
+
+        assert_eq!(order, vec![0, 1, 2, 1, 2, 1, 2]);
+    }
+
+    #[tokio::test]
+    async fn test_transfer_slots() {
+        let (writer, _reader) = tokio::io::duplex(64);
+        let scheduler = SendScheduler::new(writer, 1);
+
+        let stream = scheduler.open_stream().await.unwrap();
+        assert!(tokio::time::timeout(std::time::Duration::from_millis(50), scheduler.open_stream()).await.is_err());
+
+        drop(stream);
+        assert!(scheduler.open_stream().await.is_ok());
+    }
+}
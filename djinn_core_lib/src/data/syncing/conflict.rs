@@ -0,0 +1,78 @@
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// Name of the copy which keeps the losing side of a conflict, e.g.
+/// `/docs/report (conflict from laptop 2023-04-01).txt` for `/docs/report.txt`
+pub fn conflict_file_name(path: &str, client: &str, unix_time: u64) -> String {
+    // The client name comes from the remote peer, so it must not be able to add path components
+    let client: String = client
+        .chars()
+        .map(|c| if c.is_alphanumeric() || "-_. ".contains(c) { c } else { '_' })
+        .collect();
+    let suffix = format!(" (conflict from {} {})", client, format_date(unix_time));
+
+    // Only look for an extension in the file name itself, and keep names like ".env" whole
+    let name_start = path.rfind('/').map_or(0, |index| index + 1);
+    match path[name_start..].rfind('.') {
+        Some(dot) if dot > 0 => {
+            let dot = name_start + dot;
+            format!("{}{}{}", &path[..dot], suffix, &path[dot..])
+        }
+        _ => format!("{}{}", path, suffix),
+    }
+}
+
+/// Formats a unix timestamp as a UTC YYYY-MM-DD date
+fn format_date(unix_time: u64) -> String {
+    // Days to civil date, see http://howardhinnant.github.io/date_algorithms.html
+    let days = (unix_time / SECONDS_PER_DAY) as i64 + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days.rem_euclid(146097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_date() {
+        assert_eq!(format_date(0), "1970-01-01");
+        assert_eq!(format_date(951782400), "2000-02-29");
+        assert_eq!(format_date(1680307199), "2023-03-31");
+        assert_eq!(format_date(1680307200), "2023-04-01");
+    }
+
+    #[test]
+    fn test_conflict_file_name() {
+        let time = 1680307200;
+
+        assert_eq!(
+            conflict_file_name("/docs/report.txt", "laptop", time),
+            "/docs/report (conflict from laptop 2023-04-01).txt"
+        );
+        assert_eq!(
+            conflict_file_name("/archive.tar.gz", "laptop", time),
+            "/archive.tar (conflict from laptop 2023-04-01).gz"
+        );
+        assert_eq!(
+            conflict_file_name("/v1.2/Makefile", "laptop", time),
+            "/v1.2/Makefile (conflict from laptop 2023-04-01)"
+        );
+        assert_eq!(
+            conflict_file_name("/.env", "laptop", time),
+            "/.env (conflict from laptop 2023-04-01)"
+        );
+        assert_eq!(
+            conflict_file_name("/a.txt", "../../etc", time),
+            "/a (conflict from .._.._etc 2023-04-01).txt"
+        );
+    }
+}
@@ -0,0 +1,51 @@
+use std::{io, time::SystemTime};
+
+use tokio::fs;
+
+/// Modified time and size of a file, taken when a transfer starts so a change underneath it can be noticed
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct FileVersion {
+    modified_time: SystemTime,
+    size: u64,
+}
+
+impl FileVersion {
+    pub async fn of(path: &str) -> io::Result<FileVersion> {
+        let metadata = fs::metadata(path).await?;
+
+        Ok(FileVersion {
+            modified_time: metadata.modified()?,
+            size: metadata.len(),
+        })
+    }
+
+    /// Whether the file was changed, replaced or removed since this version was taken
+    pub async fn has_changed(&self, path: &str) -> bool {
+        FileVersion::of(path).await.ok().as_ref() != Some(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_has_changed() {
+        let path = std::env::temp_dir().join("djinn_test_file_version.txt");
+        let path_str = path.to_str().unwrap();
+        fs::write(&path, [1, 2, 3]).await.unwrap();
+
+        let version = FileVersion::of(path_str).await.unwrap();
+        assert!(!version.has_changed(path_str).await);
+
+        // Same size, later modified time
+        let modified_time = SystemTime::now() + Duration::from_secs(10);
+        std::fs::File::options().write(true).open(&path).unwrap().set_modified(modified_time).unwrap();
+        assert!(version.has_changed(path_str).await);
+
+        fs::remove_file(&path).await.unwrap();
+        assert!(version.has_changed(path_str).await);
+    }
+}
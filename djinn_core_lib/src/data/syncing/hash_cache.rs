@@ -0,0 +1,111 @@
+use std::{
+    collections::HashMap,
+    fs::{File, Metadata},
+    io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+use crate::data::packets::Checksum;
+
+/// Identifies one version of a file without reading it
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct FileFingerprint {
+    inode: u64,
+    size: u64,
+    modified: SystemTime,
+}
+
+impl FileFingerprint {
+    fn from_metadata(metadata: &Metadata) -> io::Result<FileFingerprint> {
+        #[cfg(unix)]
+        let inode = std::os::unix::fs::MetadataExt::ino(metadata);
+        #[cfg(not(unix))]
+        let inode = 0;
+
+        Ok(FileFingerprint {
+            inode,
+            size: metadata.len(),
+            modified: metadata.modified()?,
+        })
+    }
+}
+
+/// Content hashes of files, reused as long as their (inode, size, mtime) stays the same.
+/// Clones share the same cache, so one cache can outlive many index builds.
+#[derive(Clone, Default)]
+pub struct HashCache {
+    entries: Arc<Mutex<HashMap<PathBuf, (FileFingerprint, Checksum)>>>,
+}
+
+impl HashCache {
+    pub fn new() -> HashCache {
+        HashCache::default()
+    }
+
+    /// Returns the BLAKE3 hash of the file, or None if it could not be read
+    pub async fn hash(&self, path: &Path, metadata: &Metadata) -> Option<Checksum> {
+        let fingerprint = FileFingerprint::from_metadata(metadata).ok()?;
+
+        if let Some((cached_fingerprint, hash)) = self.entries.lock().unwrap().get(path) {
+            if *cached_fingerprint == fingerprint {
+                return Some(*hash);
+            }
+        }
+
+        let owned_path = path.to_path_buf();
+        let hash = tokio::task::spawn_blocking(move || hash_file(&owned_path))
+            .await
+            .ok()?
+            .ok()?;
+
+        self.entries
+            .lock()
+            .unwrap()
+            .insert(path.to_path_buf(), (fingerprint, hash));
+
+        Some(hash)
+    }
+}
+
+fn hash_file(path: &Path) -> io::Result<Checksum> {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update_reader(File::open(path)?)?;
+
+    Ok(*hasher.finalize().as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use crate::data::packets::chunk_checksum;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_hash_is_cached() {
+        let dir = std::env::temp_dir().join("djinn_test_hash_cache");
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("file.txt");
+        fs::write(&path, "test").unwrap();
+
+        let cache = HashCache::new();
+        let metadata = fs::metadata(&path).unwrap();
+        assert_eq!(cache.hash(&path, &metadata).await, Some(chunk_checksum(b"test")));
+
+        // Same size and mtime, so the cached hash is returned without reading the file
+        fs::write(&path, "best").unwrap();
+        File::options().write(true).open(&path).unwrap().set_modified(metadata.modified().unwrap()).unwrap();
+        let metadata = fs::metadata(&path).unwrap();
+        assert_eq!(cache.hash(&path, &metadata).await, Some(chunk_checksum(b"test")));
+
+        // A new mtime invalidates the entry
+        File::options().write(true).open(&path).unwrap().set_modified(SystemTime::UNIX_EPOCH).unwrap();
+        let metadata = fs::metadata(&path).unwrap();
+        assert_eq!(cache.hash(&path, &metadata).await, Some(chunk_checksum(b"best")));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
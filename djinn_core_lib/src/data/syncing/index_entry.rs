@@ -0,0 +1,129 @@
+use std::{fmt, str::FromStr};
+
+use crate::data::packets::{Checksum, CHECKSUM_SIZE};
+
+/// Version of a single file in an index. A modified time of 0 marks a deleted file.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct IndexEntry {
+    pub modified_time: usize,
+    pub size: u64,
+    pub hash: Option<Checksum>,
+}
+
+impl IndexEntry {
+    pub fn new(modified_time: usize, size: u64, hash: Option<Checksum>) -> IndexEntry {
+        IndexEntry {
+            modified_time,
+            size,
+            hash,
+        }
+    }
+
+    pub fn deleted() -> IndexEntry {
+        IndexEntry::default()
+    }
+
+    /// Deleted marker which keeps the last known size and hash, so the other side can tell
+    /// whether its copy changed since
+    pub fn to_deleted(&self) -> IndexEntry {
+        IndexEntry::new(0, self.size, self.hash)
+    }
+
+    pub fn is_deleted(&self) -> bool {
+        self.modified_time == 0
+    }
+
+    /// Whether both entries describe the same contents. Hashes decide when both sides have one,
+    /// so a touched file is unchanged and two edits within the same second are not.
+    pub fn is_same_as(&self, other: &IndexEntry) -> bool {
+        match (self.hash, other.hash) {
+            (Some(hash), Some(other_hash)) => hash == other_hash && self.size == other.size,
+            _ => self.modified_time == other.modified_time,
+        }
+    }
+}
+
+/// Wire format is "mtime:size:hash" with the hash in hex, or "mtime:size" without one
+impl fmt::Display for IndexEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.modified_time, self.size)?;
+
+        if let Some(hash) = self.hash {
+            write!(f, ":{}", blake3::Hash::from(hash).to_hex())?;
+        }
+
+        Ok(())
+    }
+}
+
+impl FromStr for IndexEntry {
+    type Err = ();
+
+    /// Also accepts a bare modified time as sent in older indexes
+    fn from_str(value: &str) -> Result<IndexEntry, ()> {
+        let mut parts = value.split(':');
+
+        let modified_time = parts.next().ok_or(())?.parse::<usize>().map_err(|_| ())?;
+        let size = match parts.next() {
+            Some(size) => size.parse::<u64>().map_err(|_| ())?,
+            None => 0,
+        };
+        let hash = match parts.next() {
+            Some(hash) if hash.len() == CHECKSUM_SIZE * 2 => {
+                Some(*blake3::Hash::from_hex(hash).map_err(|_| ())?.as_bytes())
+            }
+            Some(_) => return Err(()),
+            None => None,
+        };
+
+        if parts.next().is_some() {
+            return Err(());
+        }
+
+        Ok(IndexEntry::new(modified_time, size, hash))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let entries = [
+            IndexEntry::new(123, 4, Some([7; CHECKSUM_SIZE])),
+            IndexEntry::new(123, 4, None),
+            IndexEntry::deleted(),
+        ];
+
+        for entry in entries {
+            assert_eq!(entry.to_string().parse::<IndexEntry>(), Ok(entry));
+        }
+    }
+
+    #[test]
+    fn test_parse_bare_modified_time() {
+        assert_eq!("123".parse::<IndexEntry>(), Ok(IndexEntry::new(123, 0, None)));
+    }
+
+    #[test]
+    fn test_parse_invalid() {
+        for value in ["", "abc", "1:x", "1:2:zz", "1:2:3:4"] {
+            assert!(value.parse::<IndexEntry>().is_err(), "{}", value);
+        }
+    }
+
+    #[test]
+    fn test_is_same_as() {
+        let hash = Some([1; CHECKSUM_SIZE]);
+        let other_hash = Some([2; CHECKSUM_SIZE]);
+
+        // Touched file
+        assert!(IndexEntry::new(100, 4, hash).is_same_as(&IndexEntry::new(200, 4, hash)));
+        // Edited within the same second
+        assert!(!IndexEntry::new(100, 4, hash).is_same_as(&IndexEntry::new(100, 4, other_hash)));
+        // No hash on one side
+        assert!(IndexEntry::new(100, 4, None).is_same_as(&IndexEntry::new(100, 4, hash)));
+        assert!(!IndexEntry::new(100, 4, None).is_same_as(&IndexEntry::new(200, 4, hash)));
+    }
+}
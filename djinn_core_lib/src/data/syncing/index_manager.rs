@@ -3,28 +3,37 @@ use std::{collections::HashMap, time::{SystemTime, UNIX_EPOCH}};
 use async_recursion::async_recursion;
 use tokio::fs;
 
+use super::{is_partial_transfer_file, HashCache, IndexEntry, STATE_DIRECTORY};
+
 pub struct IndexManager {
-    pub index: HashMap<String, usize>,
+    pub index: HashMap<String, IndexEntry>,
     pub root: String,
+    pub hash_cache: HashCache,
 }
 
 impl IndexManager {
     pub fn new(root: String) -> Self {
+        IndexManager::with_hash_cache(root, HashCache::new())
+    }
+
+    /// Reuses the hashes of an earlier build for files which did not change since
+    pub fn with_hash_cache(root: String, hash_cache: HashCache) -> Self {
         IndexManager {
             index: HashMap::new(),
-            root
+            root,
+            hash_cache
         }
     }
 
-    pub fn add(&mut self, key: String, value: usize) {
+    pub fn add(&mut self, key: String, value: IndexEntry) {
         self.index.insert(key, value);
     }
 
-    pub fn get(&self, key: &String) -> Option<&usize> {
+    pub fn get(&self, key: &String) -> Option<&IndexEntry> {
         self.index.get(key)
     }
 
-    pub fn update(&mut self, map: HashMap<String, usize>) {
+    pub fn update(&mut self, map: HashMap<String, IndexEntry>) {
         //Merge the two maps
         for (key, value) in map {
             self.index.insert(key, value);
@@ -41,13 +50,13 @@ impl IndexManager {
         let unix_timestamp = current_time.as_secs();
 
         // Add the index to the index manager
-        index.insert("#timestamp".to_string(), unix_timestamp as usize);
+        index.insert("#timestamp".to_string(), IndexEntry::new(unix_timestamp as usize, 0, None));
 
         self.index = index;
     }
 
     #[async_recursion]
-    pub async fn build_index(&mut self, directory_path: String) -> HashMap<String, usize> {
+    pub async fn build_index(&mut self, directory_path: String) -> HashMap<String, IndexEntry> {
         // Build the index
         let mut index = HashMap::new();
         let mut items = fs::read_dir(directory_path).await.unwrap();
@@ -58,16 +67,24 @@ impl IndexManager {
             let path_str = unwrapped_item.path().to_str().unwrap().to_string();
             let path_without_root = path_str.replace(&self.root, "/");
 
+            // Local state is never synced
+            if unwrapped_item.file_name() == STATE_DIRECTORY {
+                continue;
+            }
+
             if unwrapped_item.path().is_file() {
-                // Check if extension is .djinn_temp
-                if path_str.ends_with(".djinn_temp") {
+                // Skip unfinished transfers
+                if is_partial_transfer_file(&path_str) {
                     continue;
                 }
                 //Get the file size
-                let last_modified = unwrapped_item.metadata().await.unwrap().modified().unwrap();
+                let metadata = unwrapped_item.metadata().await.unwrap();
+                let last_modified = metadata.modified().unwrap();
                 let last_modified_unix = last_modified.duration_since(std::time::UNIX_EPOCH).unwrap().as_secs();
+                let hash = self.hash_cache.hash(&unwrapped_item.path(), &metadata).await;
                 //Add the file name and size to the index
-                index.insert(path_without_root.replace("//", "/"), last_modified_unix as usize);
+                let entry = IndexEntry::new(last_modified_unix as usize, metadata.len(), hash);
+                index.insert(path_without_root.replace("//", "/"), entry);
             } else {
                 //If the path is a directory, recursively call the function
                 let sub_index = self.build_index(path_str).await;
@@ -88,6 +105,8 @@ mod tests {
 
     use tokio::fs;
 
+    use crate::data::packets::chunk_checksum;
+
     use super::*;
 
     #[tokio::test]
@@ -103,6 +122,8 @@ mod tests {
         fs::create_dir_all(test_sub_dir).await.unwrap();
         fs::write(test_file, "test").await.unwrap();
         fs::write(test_sub_file, "test").await.unwrap();
+        fs::create_dir_all("/tmp/test_data/.djinn").await.unwrap();
+        fs::write("/tmp/test_data/.djinn/state", "state").await.unwrap();
 
         //Test
         let mut index_manager = IndexManager::new(test_dir.to_string());
@@ -111,9 +132,11 @@ mod tests {
         let mut expected_index = HashMap::new();
         let current_unix = SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs() as usize;
 
-        expected_index.insert(test_file.replace(test_dir, ""), current_unix);
-        expected_index.insert(test_sub_file.replace(test_dir, ""), current_unix);
-        expected_index.insert("#timestamp".to_string(), current_unix);
+        let expected_entry = IndexEntry::new(current_unix, 4, Some(chunk_checksum(b"test")));
+
+        expected_index.insert(test_file.replace(test_dir, ""), expected_entry.clone());
+        expected_index.insert(test_sub_file.replace(test_dir, ""), expected_entry);
+        expected_index.insert("#timestamp".to_string(), IndexEntry::new(current_unix, 0, None));
 
         assert_eq!(index_manager.index, expected_index);
 
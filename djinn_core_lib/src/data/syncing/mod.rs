@@ -1,2 +1,36 @@
 mod index_manager;
 pub use index_manager::IndexManager;
+
+mod index_entry;
+pub use index_entry::IndexEntry;
+
+mod hash_cache;
+pub use hash_cache::HashCache;
+
+mod conflict;
+pub use conflict::conflict_file_name;
+
+mod state_store;
+pub use state_store::StateStore;
+pub use state_store::STATE_DIRECTORY;
+
+mod sync_plan;
+pub use sync_plan::{SyncAction, SyncPlan, SyncPlanEntry, SyncReason};
+
+mod file_version;
+pub use file_version::FileVersion;
+
+mod partial_transfer;
+pub use partial_transfer::expire_partial_transfers;
+pub use partial_transfer::is_partial_transfer_file;
+pub use partial_transfer::transfer_key;
+pub use partial_transfer::PartialTransfer;
+pub use partial_transfer::KEY_SUFFIX;
+pub use partial_transfer::TEMP_SUFFIX;
+
+mod safe_path;
+pub use safe_path::check_within;
+pub use safe_path::join_path;
+pub use safe_path::normalize_path;
+pub use safe_path::resolve_path;
+pub use safe_path::PathError;
@@ -0,0 +1,245 @@
+use std::{
+    io::{self, SeekFrom},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use async_recursion::async_recursion;
+use log::{info, warn};
+use tokio::{
+    fs::{self, File, OpenOptions},
+    io::{AsyncReadExt, AsyncSeekExt},
+};
+
+use crate::data::packets::TransferChecksum;
+
+use super::STATE_DIRECTORY;
+
+/// Received part of a file, renamed into place once the transfer is done
+pub const TEMP_SUFFIX: &str = ".djinn_temp";
+
+/// Key of the transfer the temp file belongs to
+pub const KEY_SUFFIX: &str = ".djinn_key";
+
+const READ_SIZE: usize = 65536;
+
+/// Stable key of a transfer. The same version of a file always gets the same key, so a later
+/// connection can tell whether a partial file is worth continuing.
+pub fn transfer_key(path: &str, modified_time: u64, size: u64) -> String {
+    let hash = blake3::hash(format!("{}:{}:{}", path, modified_time, size).as_bytes());
+    hash.to_hex()[..32].to_string()
+}
+
+/// Whether a file is a temp file or key of an unfinished transfer
+pub fn is_partial_transfer_file(path: &str) -> bool {
+    path.ends_with(TEMP_SUFFIX) || path.ends_with(KEY_SUFFIX)
+}
+
+/// The temp file of a transfer and the key it was started with, kept next to the file as
+/// `<file>.djinn_temp` and `<file>.djinn_key`
+pub struct PartialTransfer {
+    temp_path: PathBuf,
+    key_path: PathBuf,
+}
+
+impl PartialTransfer {
+    pub fn new(full_path: &str) -> PartialTransfer {
+        PartialTransfer {
+            temp_path: PathBuf::from(full_path.to_string() + TEMP_SUFFIX),
+            key_path: PathBuf::from(full_path.to_string() + KEY_SUFFIX),
+        }
+    }
+
+    pub fn temp_path(&self) -> &Path {
+        &self.temp_path
+    }
+
+    /// Key and received length of the partial file, if there is one which can be continued
+    pub async fn existing(&self) -> Option<(String, u64)> {
+        let key = fs::read_to_string(&self.key_path).await.ok()?;
+        let size = fs::metadata(&self.temp_path).await.ok()?.len();
+
+        Some((key.trim().to_string(), size))
+    }
+
+    /// Where a transfer with the given key can continue, 0 when there is nothing to continue from
+    pub async fn resume_offset(&self, key: Option<&str>) -> u64 {
+        match (key, self.existing().await) {
+            (Some(key), Some((existing_key, size))) if key == existing_key => size,
+            _ => 0,
+        }
+    }
+
+    /// Opens the temp file to continue at the offset, dropping anything after it. The checksum
+    /// is rebuilt from the part already received, so the end packet still covers the whole file.
+    /// Starting at 0 replaces any old partial file.
+    pub async fn open(&self, key: Option<&str>, offset: u64) -> io::Result<(File, TransferChecksum)> {
+        let mut checksum = TransferChecksum::new();
+
+        if offset == 0 {
+            let file = File::create(&self.temp_path).await?;
+
+            match key {
+                Some(key) => fs::write(&self.key_path, key).await?,
+                None => remove_if_exists(&self.key_path).await?,
+            }
+
+            return Ok((file, checksum));
+        }
+
+        let mut file = OpenOptions::new().read(true).write(true).open(&self.temp_path).await?;
+        file.set_len(offset).await?;
+
+        let mut buffer = vec![0; READ_SIZE];
+        let mut remaining = offset as usize;
+        while remaining > 0 {
+            let chunk = &mut buffer[..remaining.min(READ_SIZE)];
+            file.read_exact(chunk).await?;
+            checksum.update(chunk);
+            remaining -= chunk.len();
+        }
+
+        file.seek(SeekFrom::Start(offset)).await?;
+
+        Ok((file, checksum))
+    }
+
+    /// Moves the received file into place
+    pub async fn finish(&self, full_path: &str) -> io::Result<()> {
+        fs::rename(&self.temp_path, full_path).await?;
+        remove_if_exists(&self.key_path).await
+    }
+
+    pub async fn discard(&self) -> io::Result<()> {
+        remove_if_exists(&self.temp_path).await?;
+        remove_if_exists(&self.key_path).await
+    }
+}
+
+/// Removes the leftovers of transfers which were not continued within `max_age`.
+/// Returns how many partial files were removed.
+#[async_recursion]
+pub async fn expire_partial_transfers(directory: &Path, max_age: Duration) -> usize {
+    let mut expired = 0;
+
+    let mut items = match fs::read_dir(directory).await {
+        Ok(items) => items,
+        Err(_) => return 0,
+    };
+
+    while let Ok(Some(item)) = items.next_entry().await {
+        let path = item.path();
+        let Ok(metadata) = item.metadata().await else {
+            continue;
+        };
+
+        if metadata.is_dir() {
+            if item.file_name() != STATE_DIRECTORY {
+                expired += expire_partial_transfers(&path, max_age).await;
+            }
+            continue;
+        }
+
+        let path_str = path.to_string_lossy();
+        if !is_partial_transfer_file(&path_str) {
+            continue;
+        }
+
+        let age = metadata
+            .modified()
+            .ok()
+            .and_then(|modified| SystemTime::now().duration_since(modified).ok())
+            .unwrap_or_default();
+
+        if age <= max_age {
+            continue;
+        }
+
+        match fs::remove_file(&path).await {
+            Ok(()) => {
+                if path_str.ends_with(TEMP_SUFFIX) {
+                    info!("Removed stale partial transfer {}", path_str);
+                    expired += 1;
+                }
+            }
+            Err(error) => warn!("Failed to remove stale partial transfer {}: {}", path_str, error),
+        }
+    }
+
+    expired
+}
+
+async fn remove_if_exists(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path).await {
+        Err(error) if error.kind() != io::ErrorKind::NotFound => Err(error),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncWriteExt;
+
+    use super::*;
+
+    fn test_path(name: &str) -> String {
+        std::env::temp_dir().join(name).to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn test_transfer_key() {
+        assert_eq!(transfer_key("/a.txt", 1, 2), transfer_key("/a.txt", 1, 2));
+        assert_ne!(transfer_key("/a.txt", 1, 2), transfer_key("/a.txt", 1, 3));
+        assert_ne!(transfer_key("/a.txt", 1, 2), transfer_key("/b.txt", 1, 2));
+    }
+
+    #[tokio::test]
+    async fn test_resume() {
+        let full_path = test_path("djinn_test_partial_resume.txt");
+        let partial = PartialTransfer::new(&full_path);
+
+        let (mut file, _) = partial.open(Some("key"), 0).await.unwrap();
+        file.write_all(&[1, 2, 3, 4]).await.unwrap();
+        file.flush().await.unwrap();
+        drop(file);
+
+        assert_eq!(partial.resume_offset(Some("key")).await, 4);
+        assert_eq!(partial.resume_offset(Some("other")).await, 0);
+        assert_eq!(partial.resume_offset(None).await, 0);
+
+        // Continue after the first 3 bytes, the rest is received again
+        let (mut file, checksum) = partial.open(Some("key"), 3).await.unwrap();
+        file.write_all(&[5, 6]).await.unwrap();
+        file.flush().await.unwrap();
+        drop(file);
+
+        let mut expected = TransferChecksum::new();
+        expected.update(&[1, 2, 3]);
+        assert_eq!(checksum.finalize(), expected.finalize());
+
+        partial.finish(&full_path).await.unwrap();
+        assert_eq!(fs::read(&full_path).await.unwrap(), vec![1, 2, 3, 5, 6]);
+        assert!(partial.existing().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_expire() {
+        let root = std::env::temp_dir().join("djinn_test_partial_expire");
+        fs::create_dir_all(root.join("sub")).await.unwrap();
+
+        let stale = root.join("sub/stale.txt").to_str().unwrap().to_string();
+        let fresh = root.join("fresh.txt").to_str().unwrap().to_string();
+        let (stale_partial, fresh_partial) = (PartialTransfer::new(&stale), PartialTransfer::new(&fresh));
+        stale_partial.open(Some("key"), 0).await.unwrap();
+        fresh_partial.open(Some("key"), 0).await.unwrap();
+
+        let old = SystemTime::now() - Duration::from_secs(7200);
+        for path in [&stale_partial.temp_path, &stale_partial.key_path] {
+            std::fs::File::options().write(true).open(path).unwrap().set_modified(old).unwrap();
+        }
+
+        assert_eq!(expire_partial_transfers(&root, Duration::from_secs(3600)).await, 1);
+        assert!(stale_partial.existing().await.is_none());
+        assert!(fresh_partial.existing().await.is_some());
+    }
+}
@@ -0,0 +1,171 @@
+use std::{error::Error, fmt, path::Path};
+
+use tokio::fs;
+
+/// Why a path from the wire was refused
+#[derive(Debug, PartialEq, Eq)]
+pub enum PathError {
+    /// Contains a ".." component
+    ParentDirectory(String),
+    /// Absolute on its own, like a Windows drive, so joining it would ignore the root
+    Absolute(String),
+    /// Contains characters no synced path has, like NUL or a backslash
+    Invalid(String),
+    /// Leads out of the root through a symlink, or the root itself is missing
+    OutsideRoot(String),
+}
+
+impl fmt::Display for PathError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PathError::ParentDirectory(path) => write!(f, "path '{}' refers to a parent directory", path),
+            PathError::Absolute(path) => write!(f, "path '{}' is absolute", path),
+            PathError::Invalid(path) => write!(f, "path '{}' contains invalid characters", path),
+            PathError::OutsideRoot(path) => write!(f, "path '{}' leads outside the synced directory", path),
+        }
+    }
+}
+
+impl Error for PathError {}
+
+/// Normalizes a path from the wire to the form of the index keys, like `/docs/a.txt`.
+/// Wire paths are always relative to the synced root, the leading slash of the index keys included,
+/// so empty and "." components are dropped and anything which could leave the root is refused.
+pub fn normalize_path(path: &str) -> Result<String, PathError> {
+    // Backslashes separate paths on Windows, "..\a" would escape there
+    if path.contains(['\0', '\\']) {
+        return Err(PathError::Invalid(path.to_string()));
+    }
+
+    let mut normalized = String::new();
+    for component in path.split('/') {
+        match component {
+            "" | "." => continue,
+            ".." => return Err(PathError::ParentDirectory(path.to_string())),
+            _ if normalized.is_empty() && is_drive(component) => return Err(PathError::Absolute(path.to_string())),
+            _ => {
+                normalized.push('/');
+                normalized.push_str(component);
+            }
+        }
+    }
+
+    if normalized.is_empty() {
+        normalized.push('/');
+    }
+
+    Ok(normalized)
+}
+
+/// Path of a wire path under the root, only looking at the path itself
+pub fn join_path(root: &str, path: &str) -> Result<String, PathError> {
+    let normalized = normalize_path(path)?;
+    if normalized == "/" {
+        return Ok(root.to_string());
+    }
+
+    Ok(format!("{}{}", root.trim_end_matches('/'), normalized))
+}
+
+/// Checks that a path under the root stays there once symlinks are followed. The path does not
+/// have to exist yet, then its closest existing parent is checked.
+pub async fn check_within(root: &str, full_path: &str) -> Result<(), PathError> {
+    let outside = || PathError::OutsideRoot(full_path.to_string());
+    let root = fs::canonicalize(root).await.map_err(|_| outside())?;
+
+    let mut current = Path::new(full_path);
+    loop {
+        match fs::canonicalize(current).await {
+            Ok(resolved) if resolved.starts_with(&root) => return Ok(()),
+            Ok(_) => return Err(outside()),
+            Err(_) => {
+                // A dangling symlink could point anywhere once a file is written through it
+                if fs::symlink_metadata(current).await.is_ok() {
+                    return Err(outside());
+                }
+
+                current = current.parent().ok_or_else(outside)?;
+            }
+        }
+    }
+}
+
+/// Path of a wire path under the root, refusing anything which leads outside of it
+pub async fn resolve_path(root: &str, path: &str) -> Result<String, PathError> {
+    let full_path = join_path(root, path)?;
+    check_within(root, &full_path).await?;
+
+    Ok(full_path)
+}
+
+/// "C:" and the like
+fn is_drive(component: &str) -> bool {
+    let bytes = component.as_bytes();
+    bytes.len() >= 2 && bytes[0].is_ascii_alphabetic() && bytes[1] == b':'
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::symlink;
+
+    use super::*;
+
+    #[test]
+    fn test_normalize() {
+        assert_eq!(normalize_path("/docs/a.txt").unwrap(), "/docs/a.txt");
+        assert_eq!(normalize_path("docs//./a.txt").unwrap(), "/docs/a.txt");
+        assert_eq!(normalize_path("/docs/").unwrap(), "/docs");
+        assert_eq!(normalize_path("").unwrap(), "/");
+        assert_eq!(normalize_path("/").unwrap(), "/");
+        assert_eq!(normalize_path("/docs/..a.txt").unwrap(), "/docs/..a.txt");
+    }
+
+    #[test]
+    fn test_normalize_rejects_escapes() {
+        assert_eq!(normalize_path("../../etc/passwd"), Err(PathError::ParentDirectory("../../etc/passwd".to_string())));
+        assert_eq!(normalize_path("/docs/../../a.txt"), Err(PathError::ParentDirectory("/docs/../../a.txt".to_string())));
+        assert_eq!(normalize_path("C:/Windows"), Err(PathError::Absolute("C:/Windows".to_string())));
+        assert_eq!(normalize_path("..\\a.txt"), Err(PathError::Invalid("..\\a.txt".to_string())));
+        assert_eq!(normalize_path("a\0.txt"), Err(PathError::Invalid("a\0.txt".to_string())));
+    }
+
+    #[test]
+    fn test_join() {
+        assert_eq!(join_path("/srv/files", "/docs/a.txt").unwrap(), "/srv/files/docs/a.txt");
+        assert_eq!(join_path("/srv/files/", "docs/a.txt").unwrap(), "/srv/files/docs/a.txt");
+        assert_eq!(join_path("/srv/files", "/").unwrap(), "/srv/files");
+        assert_eq!(join_path("/", "/docs").unwrap(), "/docs");
+        // Absolute looking paths stay under the root
+        assert_eq!(join_path("/srv/files", "//etc/passwd").unwrap(), "/srv/files/etc/passwd");
+        assert!(join_path("/srv/files", "/../etc/passwd").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_resolve_symlinks() {
+        let test_dir = "/tmp/test_safe_path";
+        let root = "/tmp/test_safe_path/root";
+        let _ = fs::remove_dir_all(test_dir).await;
+        fs::create_dir_all(format!("{}/docs", root)).await.unwrap();
+        fs::create_dir_all(format!("{}/outside", test_dir)).await.unwrap();
+        fs::write(format!("{}/docs/a.txt", root), "a").await.unwrap();
+
+        symlink(format!("{}/outside", test_dir), format!("{}/escape", root)).unwrap();
+        symlink(format!("{}/docs", root), format!("{}/inside", root)).unwrap();
+        symlink(format!("{}/outside/missing.txt", test_dir), format!("{}/dangling.txt", root)).unwrap();
+
+        assert_eq!(resolve_path(root, "/docs/a.txt").await.unwrap(), format!("{}/docs/a.txt", root));
+        // Files which are about to be created
+        assert_eq!(resolve_path(root, "/new/b.txt").await.unwrap(), format!("{}/new/b.txt", root));
+        assert!(resolve_path(root, "/inside/a.txt").await.is_ok());
+
+        assert_eq!(
+            resolve_path(root, "/escape/b.txt").await,
+            Err(PathError::OutsideRoot(format!("{}/escape/b.txt", root)))
+        );
+        assert!(resolve_path(root, "/escape").await.is_err());
+        assert!(resolve_path(root, "/dangling.txt").await.is_err());
+        assert!(resolve_path("/tmp/test_safe_path/missing", "/a.txt").await.is_err());
+
+        fs::remove_dir_all(test_dir).await.unwrap();
+    }
+}
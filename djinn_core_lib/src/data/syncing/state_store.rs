@@ -0,0 +1,129 @@
+use std::{
+    collections::HashMap,
+    io,
+    path::{Path, PathBuf},
+};
+
+use log::warn;
+use tokio::fs;
+
+use crate::data::packets::params_codec::{decode_params, encode_params};
+
+use super::IndexEntry;
+
+/// Directory inside a synced root which holds local state. It is never indexed or synced.
+pub const STATE_DIRECTORY: &str = ".djinn";
+
+const STATE_FILE: &str = "state";
+const STATE_VERSION: &str = "1";
+
+/// Last synced index of a root, kept in `<root>/.djinn/state` so it survives restarts
+pub struct StateStore {
+    path: PathBuf,
+}
+
+impl StateStore {
+    pub fn new(root: &str) -> StateStore {
+        StateStore {
+            path: Path::new(root).join(STATE_DIRECTORY).join(STATE_FILE),
+        }
+    }
+
+    /// Returns an empty index if nothing was saved yet or the state can not be read
+    pub async fn load(&self) -> HashMap<String, IndexEntry> {
+        let buffer = match fs::read(&self.path).await {
+            Ok(buffer) => buffer,
+            Err(error) => {
+                if error.kind() != io::ErrorKind::NotFound {
+                    warn!("Failed to read {}: {}", self.path.display(), error);
+                }
+                return HashMap::new();
+            }
+        };
+
+        let mut params = match decode_params(&buffer) {
+            Ok(params) => params,
+            Err(error) => {
+                warn!("Ignoring corrupt {}: {}", self.path.display(), error);
+                return HashMap::new();
+            }
+        };
+
+        if params.remove("#version").as_deref() != Some(STATE_VERSION) {
+            warn!("Ignoring {}: unknown version", self.path.display());
+            return HashMap::new();
+        }
+
+        let mut index = HashMap::new();
+        for (path, value) in params {
+            match value.parse::<IndexEntry>() {
+                Ok(entry) => {
+                    index.insert(path, entry);
+                }
+                Err(_) => warn!("Ignoring invalid state entry for {}: {}", path, value),
+            }
+        }
+
+        index
+    }
+
+    /// Replaces the saved index. Written to a temporary file first so a crash never leaves half a state behind.
+    pub async fn save(&self, index: &HashMap<String, IndexEntry>) -> io::Result<()> {
+        let mut params: HashMap<String, String> = index
+            .iter()
+            .filter(|(path, entry)| !path.starts_with('#') && !entry.is_deleted())
+            .map(|(path, entry)| (path.clone(), entry.to_string()))
+            .collect();
+        params.insert("#version".to_string(), STATE_VERSION.to_string());
+
+        fs::create_dir_all(self.path.parent().unwrap()).await?;
+
+        let temp_path = self.path.with_extension("djinn_temp");
+        fs::write(&temp_path, encode_params(&params)).await?;
+        fs::rename(&temp_path, &self.path).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::data::packets::CHECKSUM_SIZE;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_save_and_load() {
+        let root = std::env::temp_dir().join("djinn_test_state_store");
+        let root = root.to_str().unwrap();
+        let store = StateStore::new(root);
+
+        let mut index = HashMap::new();
+        index.insert("/a;b=c.txt".to_string(), IndexEntry::new(123, 4, Some([1; CHECKSUM_SIZE])));
+        index.insert("/sub/b.txt".to_string(), IndexEntry::new(124, 5, None));
+        store.save(&index).await.unwrap();
+
+        // Markers and deleted entries are not part of the state
+        let mut saved_index = index.clone();
+        saved_index.insert("#timestamp".to_string(), IndexEntry::new(125, 0, None));
+        saved_index.insert("/deleted.txt".to_string(), IndexEntry::deleted());
+        store.save(&saved_index).await.unwrap();
+
+        assert_eq!(StateStore::new(root).load().await, index);
+
+        fs::remove_dir_all(root).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_load_missing_or_corrupt() {
+        let root = std::env::temp_dir().join("djinn_test_state_store_corrupt");
+        let root = root.to_str().unwrap();
+        let store = StateStore::new(root);
+
+        assert!(store.load().await.is_empty());
+
+        fs::create_dir_all(Path::new(root).join(STATE_DIRECTORY)).await.unwrap();
+        fs::write(&store.path, [0, 0, 0, 9, 1]).await.unwrap();
+        assert!(store.load().await.is_empty());
+
+        fs::remove_dir_all(root).await.unwrap();
+    }
+}
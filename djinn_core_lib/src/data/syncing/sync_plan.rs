@@ -0,0 +1,318 @@
+use std::{collections::HashMap, fmt, str::FromStr};
+
+use crate::data::messages::MessageError;
+
+use super::IndexEntry;
+
+/// What the receiving side of a sync update should do with a path. The SELF_ actions are
+/// carried out by the server itself and never reach a client.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SyncAction {
+    Get,
+    Put,
+    Delete,
+    SelfDelete,
+    Conflict,
+    SelfConflict,
+    Move,
+    SelfMove,
+}
+
+impl SyncAction {
+    pub fn from_string(action: &str) -> Result<SyncAction, MessageError> {
+        match action {
+            "GET" => Ok(SyncAction::Get),
+            "PUT" => Ok(SyncAction::Put),
+            "DELETE" => Ok(SyncAction::Delete),
+            "SELF_DELETE" => Ok(SyncAction::SelfDelete),
+            "CONFLICT" => Ok(SyncAction::Conflict),
+            "SELF_CONFLICT" => Ok(SyncAction::SelfConflict),
+            "MOVE" => Ok(SyncAction::Move),
+            "SELF_MOVE" => Ok(SyncAction::SelfMove),
+            _ => Err(MessageError::InvalidParam { name: "action", value: action.to_string() }),
+        }
+    }
+}
+
+impl fmt::Display for SyncAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SyncAction::Get => write!(f, "GET"),
+            SyncAction::Put => write!(f, "PUT"),
+            SyncAction::Delete => write!(f, "DELETE"),
+            SyncAction::SelfDelete => write!(f, "SELF_DELETE"),
+            SyncAction::Conflict => write!(f, "CONFLICT"),
+            SyncAction::SelfConflict => write!(f, "SELF_CONFLICT"),
+            SyncAction::Move => write!(f, "MOVE"),
+            SyncAction::SelfMove => write!(f, "SELF_MOVE"),
+        }
+    }
+}
+
+/// Why the comparer picked an action
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SyncReason {
+    MissingOnClient,
+    MissingOnServer,
+    ChangedOnClient,
+    ChangedOnServer,
+    DeletedOnClient,
+    DeletedOnServer,
+    ChangedOnBoth,
+    ConflictCopy,
+    MovedOnClient,
+    MovedOnServer,
+}
+
+impl SyncReason {
+    pub fn from_string(reason: &str) -> Result<SyncReason, MessageError> {
+        match reason {
+            "MissingOnClient" => Ok(SyncReason::MissingOnClient),
+            "MissingOnServer" => Ok(SyncReason::MissingOnServer),
+            "ChangedOnClient" => Ok(SyncReason::ChangedOnClient),
+            "ChangedOnServer" => Ok(SyncReason::ChangedOnServer),
+            "DeletedOnClient" => Ok(SyncReason::DeletedOnClient),
+            "DeletedOnServer" => Ok(SyncReason::DeletedOnServer),
+            "ChangedOnBoth" => Ok(SyncReason::ChangedOnBoth),
+            "ConflictCopy" => Ok(SyncReason::ConflictCopy),
+            "MovedOnClient" => Ok(SyncReason::MovedOnClient),
+            "MovedOnServer" => Ok(SyncReason::MovedOnServer),
+            _ => Err(MessageError::InvalidParam { name: "reason", value: reason.to_string() }),
+        }
+    }
+}
+
+impl fmt::Display for SyncReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SyncReason::MissingOnClient => write!(f, "MissingOnClient"),
+            SyncReason::MissingOnServer => write!(f, "MissingOnServer"),
+            SyncReason::ChangedOnClient => write!(f, "ChangedOnClient"),
+            SyncReason::ChangedOnServer => write!(f, "ChangedOnServer"),
+            SyncReason::DeletedOnClient => write!(f, "DeletedOnClient"),
+            SyncReason::DeletedOnServer => write!(f, "DeletedOnServer"),
+            SyncReason::ChangedOnBoth => write!(f, "ChangedOnBoth"),
+            SyncReason::ConflictCopy => write!(f, "ConflictCopy"),
+            SyncReason::MovedOnClient => write!(f, "MovedOnClient"),
+            SyncReason::MovedOnServer => write!(f, "MovedOnServer"),
+        }
+    }
+}
+
+/// Planned action for one path, with the version of the file it applies to when known
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SyncPlanEntry {
+    pub action: SyncAction,
+    pub reason: SyncReason,
+    pub entry: Option<IndexEntry>,
+    /// Old path of a moved file, the entry itself is keyed by the new path
+    pub from: Option<String>,
+}
+
+impl SyncPlanEntry {
+    pub fn new(action: SyncAction, reason: SyncReason, entry: Option<IndexEntry>) -> SyncPlanEntry {
+        SyncPlanEntry { action, reason, entry, from: None }
+    }
+}
+
+/// Wire format is "ACTION:REASON" followed by ":" and the index entry when there is one.
+/// The old path of a move travels separately, as it may contain any character.
+impl fmt::Display for SyncPlanEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.action, self.reason)?;
+
+        if let Some(entry) = &self.entry {
+            write!(f, ":{}", entry)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl FromStr for SyncPlanEntry {
+    type Err = MessageError;
+
+    fn from_str(value: &str) -> Result<SyncPlanEntry, MessageError> {
+        let mut parts = value.splitn(3, ':');
+
+        let action = SyncAction::from_string(parts.next().unwrap_or_default())?;
+        let reason = SyncReason::from_string(parts.next().unwrap_or_default())?;
+        let entry = match parts.next() {
+            Some(entry) => Some(entry.parse::<IndexEntry>().map_err(|_| MessageError::InvalidParam {
+                name: "index_entry",
+                value: entry.to_string(),
+            })?),
+            None => None,
+        };
+
+        Ok(SyncPlanEntry::new(action, reason, entry))
+    }
+}
+
+/// Result of comparing a client index with the server, PATH -> planned action
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SyncPlan {
+    pub entries: HashMap<String, SyncPlanEntry>,
+}
+
+impl SyncPlan {
+    pub fn new() -> SyncPlan {
+        SyncPlan::default()
+    }
+
+    pub fn insert(&mut self, path: String, action: SyncAction, reason: SyncReason, entry: Option<IndexEntry>) {
+        self.entries.insert(path, SyncPlanEntry::new(action, reason, entry));
+    }
+
+    pub fn get(&self, path: &str) -> Option<&SyncPlanEntry> {
+        self.entries.get(path)
+    }
+
+    pub fn remove(&mut self, path: &str) -> Option<SyncPlanEntry> {
+        self.entries.remove(path)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &SyncPlanEntry)> {
+        self.entries.iter()
+    }
+
+    /// Turns a removal and an addition of a new path with the same contents into a single move,
+    /// so renamed files are not transferred again. Removals need the removed version as entry.
+    pub fn pair_moves(&mut self, removal: SyncAction, addition: SyncAction, moved: SyncAction, reason: SyncReason) {
+        let mut removals: Vec<(String, IndexEntry)> = self
+            .entries
+            .iter()
+            .filter(|(_, plan_entry)| plan_entry.action == removal)
+            .filter_map(|(path, plan_entry)| Some((path.clone(), plan_entry.entry.clone()?)))
+            .filter(|(_, entry)| entry.hash.is_some())
+            .collect();
+        removals.sort_by(|a, b| a.0.cmp(&b.0));
+
+        let mut additions: Vec<String> = self
+            .entries
+            .iter()
+            .filter(|(_, plan_entry)| {
+                plan_entry.action == addition
+                    && matches!(plan_entry.reason, SyncReason::MissingOnClient | SyncReason::MissingOnServer)
+            })
+            .map(|(path, _)| path.clone())
+            .collect();
+        additions.sort();
+
+        for path in additions {
+            let plan_entry = self.entries.get_mut(&path).unwrap();
+            let Some(entry) = plan_entry.entry.clone() else {
+                continue;
+            };
+
+            let Some(position) = removals
+                .iter()
+                .position(|(_, removed)| removed.hash == entry.hash && removed.size == entry.size)
+            else {
+                continue;
+            };
+            let (from, _) = removals.remove(position);
+
+            plan_entry.action = moved;
+            plan_entry.reason = reason;
+            plan_entry.from = Some(from.clone());
+            self.entries.remove(&from);
+        }
+    }
+}
+
+/// One line per path, sorted so plans can be compared in logs
+impl fmt::Display for SyncPlan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut paths: Vec<&String> = self.entries.keys().collect();
+        paths.sort();
+
+        for path in paths {
+            let plan_entry = &self.entries[path];
+            match &plan_entry.from {
+                Some(from) => write!(f, "\n  {} {} -> {} ({})", plan_entry.action, from, path, plan_entry.reason)?,
+                None => write!(f, "\n  {} {} ({})", plan_entry.action, path, plan_entry.reason)?,
+            }
+
+            if let Some(entry) = &plan_entry.entry {
+                write!(f, " {} bytes, modified {}", entry.size, entry.modified_time)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::data::packets::CHECKSUM_SIZE;
+
+    use super::*;
+
+    #[test]
+    fn test_entry_round_trip() {
+        let entries = [
+            SyncPlanEntry::new(SyncAction::Get, SyncReason::ChangedOnServer, Some(IndexEntry::new(12, 3, Some([1; CHECKSUM_SIZE])))),
+            SyncPlanEntry::new(SyncAction::Put, SyncReason::MissingOnServer, Some(IndexEntry::new(12, 3, None))),
+            SyncPlanEntry::new(SyncAction::Delete, SyncReason::DeletedOnServer, None),
+        ];
+
+        for entry in entries {
+            assert_eq!(entry.to_string().parse::<SyncPlanEntry>(), Ok(entry));
+        }
+    }
+
+    #[test]
+    fn test_entry_parse_invalid() {
+        for value in ["", "GET", "FETCH:ChangedOnServer", "GET:Because", "GET:ChangedOnServer:x"] {
+            assert!(value.parse::<SyncPlanEntry>().is_err(), "{}", value);
+        }
+    }
+
+    #[test]
+    fn test_pair_moves() {
+        let moved = IndexEntry::new(12, 3, Some([1; CHECKSUM_SIZE]));
+        let other = IndexEntry::new(12, 3, Some([2; CHECKSUM_SIZE]));
+
+        let mut plan = SyncPlan::new();
+        plan.insert("/old/a.txt".to_string(), SyncAction::Delete, SyncReason::DeletedOnServer, Some(moved.clone()));
+        plan.insert("/new/a.txt".to_string(), SyncAction::Get, SyncReason::MissingOnClient, Some(moved.clone()));
+        plan.insert("/old/b.txt".to_string(), SyncAction::Delete, SyncReason::DeletedOnServer, Some(moved.clone()));
+        plan.insert("/changed.txt".to_string(), SyncAction::Get, SyncReason::ChangedOnServer, Some(moved.clone()));
+        plan.insert("/other.txt".to_string(), SyncAction::Get, SyncReason::MissingOnClient, Some(other));
+
+        plan.pair_moves(SyncAction::Delete, SyncAction::Get, SyncAction::Move, SyncReason::MovedOnServer);
+
+        let entry = plan.get("/new/a.txt").unwrap();
+        assert_eq!(entry.action, SyncAction::Move);
+        assert_eq!(entry.reason, SyncReason::MovedOnServer);
+        assert_eq!(entry.from.as_deref(), Some("/old/a.txt"));
+        assert!(plan.get("/old/a.txt").is_none());
+
+        // Only new paths are paired, one removal each
+        assert_eq!(plan.get("/old/b.txt").unwrap().action, SyncAction::Delete);
+        assert_eq!(plan.get("/changed.txt").unwrap().action, SyncAction::Get);
+        assert_eq!(plan.get("/other.txt").unwrap().action, SyncAction::Get);
+        assert_eq!(plan.len(), 4);
+    }
+
+    #[test]
+    fn test_display() {
+        let mut plan = SyncPlan::new();
+        plan.insert("/b.txt".to_string(), SyncAction::Delete, SyncReason::DeletedOnServer, None);
+        plan.insert("/a.txt".to_string(), SyncAction::Get, SyncReason::MissingOnClient, Some(IndexEntry::new(12, 3, None)));
+
+        assert_eq!(
+            plan.to_string(),
+            "\n  GET /a.txt (MissingOnClient) 3 bytes, modified 12\n  DELETE /b.txt (DeletedOnServer)"
+        );
+    }
+}
@@ -2,6 +2,8 @@ use std::collections::HashMap;
 
 use tokio::fs::File;
 
+use crate::data::{delta::DeltaApplier, packets::TransferChecksum};
+
 
 pub enum JobType {
     Transfer,
@@ -21,5 +23,8 @@ pub struct Job {
     pub job_type: JobType,
     pub status: JobStatus,
     pub params: HashMap<String, String>,
-    pub open_file: Option<File>
+    pub open_file: Option<File>,
+    pub checksum: TransferChecksum,
+    /// Set when the file arrives as a delta against the existing copy
+    pub delta: Option<DeltaApplier>
 }
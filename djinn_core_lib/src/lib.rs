@@ -1,2 +1,3 @@
+pub mod connectivity;
 pub mod data;
 pub mod jobs;
@@ -0,0 +1,111 @@
+use crate::configuration::{acl_rule::{AclRule, Access}, user_config::UserConfig};
+
+/// Access of a user to a path relative to the serving directory. The most specific rule for the user
+/// or one of their groups decides, without any rule there is no access.
+pub fn resolve_access(rules: &[AclRule], users: &[UserConfig], user: &str, path: &str) -> Access {
+    let path = components(path);
+
+    rules_of(rules, users, user)
+        .filter_map(|rule| {
+            let prefix = components(&rule.path);
+            path.starts_with(&prefix).then_some((prefix.len(), rule.access))
+        })
+        .max()
+        .map_or(Access::None, |(_, access)| access)
+}
+
+/// Whether the user may read the path or anything below it, like a directory only some of whose
+/// subdirectories are shared with them
+pub fn readable_below(rules: &[AclRule], users: &[UserConfig], user: &str, path: &str) -> bool {
+    let prefix = components(path);
+
+    resolve_access(rules, users, user, path) != Access::None
+        || rules_of(rules, users, user)
+            .any(|rule| rule.access != Access::None && components(&rule.path).starts_with(&prefix))
+}
+
+/// Rules naming the user or one of their groups
+fn rules_of<'a>(rules: &'a [AclRule], users: &'a [UserConfig], user: &'a str) -> impl Iterator<Item = &'a AclRule> {
+    let groups = users
+        .iter()
+        .find(|config| config.name == user)
+        .map(|config| config.groups.as_slice())
+        .unwrap_or_default();
+
+    rules.iter().filter(move |rule| {
+        rule.user.as_deref() == Some(user) || rule.group.as_ref().is_some_and(|group| groups.contains(group))
+    })
+}
+
+/// "/docs/", "docs" and "/docs/./" all name the same directory
+fn components(path: &str) -> Vec<&str> {
+    path.split('/').filter(|component| !component.is_empty() && *component != ".").collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(user: Option<&str>, group: Option<&str>, path: &str, access: Access) -> AclRule {
+        AclRule {
+            user: user.map(str::to_string),
+            group: group.map(str::to_string),
+            path: path.to_string(),
+            access,
+        }
+    }
+
+    #[test]
+    fn test_resolve_access() {
+        let users = vec![UserConfig {
+            name: "alice".to_string(),
+            password_hash: String::new(),
+            groups: vec!["team".to_string()],
+        }];
+        let rules = vec![
+            rule(None, Some("team"), "/", Access::ReadOnly),
+            rule(Some("alice"), None, "/projects", Access::ReadWrite),
+            rule(None, Some("team"), "/projects/secret", Access::None),
+            rule(Some("bob"), None, "/", Access::ReadWrite),
+        ];
+
+        assert_eq!(resolve_access(&rules, &users, "alice", "/"), Access::ReadOnly);
+        assert_eq!(resolve_access(&rules, &users, "alice", "/docs/a.txt"), Access::ReadOnly);
+        assert_eq!(resolve_access(&rules, &users, "alice", "projects/a.txt"), Access::ReadWrite);
+        assert_eq!(resolve_access(&rules, &users, "alice", "/projects-old/a.txt"), Access::ReadOnly);
+        assert_eq!(resolve_access(&rules, &users, "alice", "/projects/secret/a.txt"), Access::None);
+        assert_eq!(resolve_access(&rules, &users, "bob", "/projects/secret"), Access::ReadWrite);
+        assert_eq!(resolve_access(&rules, &users, "carol", "/"), Access::None);
+    }
+
+    #[test]
+    fn test_equally_specific_rules() {
+        let users = vec![UserConfig {
+            name: "alice".to_string(),
+            password_hash: String::new(),
+            groups: vec!["readers".to_string(), "writers".to_string()],
+        }];
+        let rules = vec![
+            rule(None, Some("readers"), "/docs", Access::ReadOnly),
+            rule(None, Some("writers"), "/docs/", Access::ReadWrite),
+        ];
+
+        assert_eq!(resolve_access(&rules, &users, "alice", "/docs/a.txt"), Access::ReadWrite);
+    }
+
+    #[test]
+    fn test_readable_below() {
+        let users = vec![];
+        let rules = vec![
+            rule(Some("alice"), None, "/projects/shared", Access::ReadOnly),
+            rule(Some("alice"), None, "/projects/shared/secret", Access::None),
+            rule(Some("alice"), None, "/archive", Access::None),
+        ];
+
+        assert!(readable_below(&rules, &users, "alice", "/"));
+        assert!(readable_below(&rules, &users, "alice", "/projects"));
+        assert!(!readable_below(&rules, &users, "alice", "/projects/shared/secret"));
+        assert!(!readable_below(&rules, &users, "alice", "/archive"));
+        assert!(!readable_below(&rules, &users, "bob", "/"));
+    }
+}
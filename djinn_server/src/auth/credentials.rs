@@ -0,0 +1,56 @@
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use lazy_static::lazy_static;
+
+use crate::configuration::user_config::UserConfig;
+
+/// Identity of clients which connect without credentials when the server allows it
+pub const ANONYMOUS_USER: &str = "anonymous";
+
+lazy_static! {
+    // Checked for unknown users, so they take as long to refuse as a wrong password
+    static ref DUMMY_HASH: String = hash_password("djinn");
+}
+
+pub fn hash_password(password: &str) -> String {
+    let salt = SaltString::generate(&mut OsRng);
+
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .expect("Failed to hash password")
+        .to_string()
+}
+
+/// Whether one of the users has this name and password. Slow on purpose, run it off the async threads.
+pub fn verify_credentials(users: &[UserConfig], name: &str, password: &str) -> bool {
+    let user = users.iter().find(|user| user.name == name);
+    let password_hash = user.map(|user| user.password_hash.as_str()).unwrap_or(&DUMMY_HASH);
+
+    let Ok(parsed_hash) = PasswordHash::new(password_hash) else {
+        warn!("Invalid password hash configured for {}", name);
+        return false;
+    };
+
+    let valid = Argon2::default().verify_password(password.as_bytes(), &parsed_hash).is_ok();
+    valid && user.is_some()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_verify_credentials() {
+        let users = vec![
+            UserConfig { name: "alice".to_string(), password_hash: hash_password("secret"), groups: vec![] },
+            UserConfig { name: "bob".to_string(), password_hash: "not a hash".to_string(), groups: vec![] },
+        ];
+
+        assert!(verify_credentials(&users, "alice", "secret"));
+        assert!(!verify_credentials(&users, "alice", "Secret"));
+        assert!(!verify_credentials(&users, "bob", "not a hash"));
+        assert!(!verify_credentials(&users, "carol", "djinn"));
+    }
+}
@@ -0,0 +1,7 @@
+mod credentials;
+pub use credentials::hash_password;
+pub use credentials::verify_credentials;
+pub use credentials::ANONYMOUS_USER;
+mod access;
+pub use access::readable_below;
+pub use access::resolve_access;
@@ -0,0 +1,20 @@
+use serde::{Serialize, Deserialize};
+
+/// What a user may do with the files under a path, ordered from least to most
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Access {
+    None,
+    ReadOnly,
+    ReadWrite
+}
+
+/// Gives a user, or every member of a group, access to a path under the serving directory
+/// and everything below it. The rule with the longest matching path wins.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct AclRule {
+    pub user: Option<String>,
+    pub group: Option<String>,
+    pub path: String,
+    pub access: Access
+}
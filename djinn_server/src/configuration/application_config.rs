@@ -1,26 +1,72 @@
+use djinn_core_lib::data::packets::{DEFAULT_MAX_CONCURRENT_TRANSFERS, DEFAULT_MAX_PACKET_SIZE};
 use serde::{Serialize, Deserialize};
 
+use super::{acl_rule::AclRule, share_config::{ShareConfig, SyncPolicy, DEFAULT_SHARE}, user_config::UserConfig};
+
 #[derive(Debug, PartialEq, Serialize, Deserialize)]
 pub struct ApplicationConfig {
     pub host: Option<String>,
     pub port: Option<u16>,
     pub amount_of_threads: Option<usize>,
-    pub serving_directory: Option<String>
+    pub serving_directory: Option<String>,
+    /// Largest packet in bytes a client may send before it gets disconnected
+    pub max_packet_size: Option<usize>,
+    /// How long the server remembers deleted files, so stale clients can not bring them back
+    pub tombstone_retention_days: Option<u64>,
+    /// How long the partial file of an interrupted upload is kept for the client to continue it
+    pub partial_transfer_retention_hours: Option<u64>,
+    /// How many files are sent to a client at the same time, the rest wait for a free slot
+    pub max_concurrent_transfers: Option<usize>,
+    /// PEM file with the certificate chain clients are shown during the TLS handshake
+    pub tls_certificate: Option<String>,
+    /// PEM file with the private key of the certificate
+    pub tls_key: Option<String>,
+    /// Accept unencrypted connections when no certificate is configured, file contents and paths go over the network readable
+    pub allow_plaintext: Option<bool>,
+    /// Who may connect, a client has to authenticate as one of them before anything else
+    pub users: Option<Vec<UserConfig>>,
+    /// Let clients without credentials in as the anonymous user
+    pub allow_anonymous: Option<bool>,
+    /// Which paths the users may read or write, nothing is accessible without a rule
+    pub acl: Option<Vec<AclRule>>,
+    /// Directories clients can sync by name. Without any, serving_directory and acl form the default share.
+    pub shares: Option<Vec<ShareConfig>>
 }
 
 impl ApplicationConfig {
     pub fn build() -> ApplicationConfig {
         let defaults = ApplicationConfig::get_defaults();
         let yaml_result = std::fs::read_to_string("config.yaml");
-        let yaml = match yaml_result {
-            Ok(yaml) => yaml,
-            Err(_) => return defaults,
+        let config = match yaml_result {
+            Ok(yaml) => {
+                let user_config: ApplicationConfig = serde_yaml::from_str(&yaml).unwrap();
+                defaults.merge(user_config)
+            }
+            Err(_) => defaults,
         };
 
-        let user_config: ApplicationConfig = serde_yaml::from_str(&yaml).unwrap();
-        
+        config.with_default_share()
+    }
+
+    /// The share a sync path names, the default share when it names none
+    pub fn share(&self, name: Option<&str>) -> Option<&ShareConfig> {
+        let name = name.unwrap_or(DEFAULT_SHARE);
+        self.shares.as_ref().unwrap().iter().find(|share| share.name == name)
+    }
+
+    fn with_default_share(mut self) -> Self {
+        let shares = self.shares.get_or_insert_with(Vec::new);
+        if shares.is_empty() {
+            shares.push(ShareConfig {
+                name: DEFAULT_SHARE.to_string(),
+                path: self.serving_directory.clone().unwrap(),
+                acl: self.acl.clone().unwrap(),
+                policy: SyncPolicy::TwoWay,
+                tombstone_retention_days: None
+            });
+        }
 
-        defaults.merge(user_config)
+        self
     }
 
     fn merge(&self, other: ApplicationConfig) -> Self {
@@ -28,7 +74,18 @@ impl ApplicationConfig {
             host: other.host.or(self.host.clone()),
             port: other.port.or(self.port),
             amount_of_threads: other.amount_of_threads.or(self.amount_of_threads),
-            serving_directory: other.serving_directory.or(self.serving_directory.clone())
+            serving_directory: other.serving_directory.or(self.serving_directory.clone()),
+            max_packet_size: other.max_packet_size.or(self.max_packet_size),
+            tombstone_retention_days: other.tombstone_retention_days.or(self.tombstone_retention_days),
+            partial_transfer_retention_hours: other.partial_transfer_retention_hours.or(self.partial_transfer_retention_hours),
+            max_concurrent_transfers: other.max_concurrent_transfers.or(self.max_concurrent_transfers),
+            tls_certificate: other.tls_certificate.or(self.tls_certificate.clone()),
+            tls_key: other.tls_key.or(self.tls_key.clone()),
+            allow_plaintext: other.allow_plaintext.or(self.allow_plaintext),
+            users: other.users.or(self.users.clone()),
+            allow_anonymous: other.allow_anonymous.or(self.allow_anonymous),
+            acl: other.acl.or(self.acl.clone()),
+            shares: other.shares.or(self.shares.clone())
         }
     }
 
@@ -37,7 +94,18 @@ impl ApplicationConfig {
             host: Some("0.0.0.0".to_string()),
             port: Some(7777),
             amount_of_threads: Some(4),
-            serving_directory: Some("./files".to_string())
+            serving_directory: Some("./files".to_string()),
+            max_packet_size: Some(DEFAULT_MAX_PACKET_SIZE),
+            tombstone_retention_days: Some(30),
+            partial_transfer_retention_hours: Some(24),
+            max_concurrent_transfers: Some(DEFAULT_MAX_CONCURRENT_TRANSFERS),
+            tls_certificate: None,
+            tls_key: None,
+            allow_plaintext: Some(false),
+            users: Some(vec![]),
+            allow_anonymous: Some(false),
+            acl: Some(vec![]),
+            shares: Some(vec![])
         }
     }
 }
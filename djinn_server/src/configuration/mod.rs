@@ -1 +1,4 @@
 pub mod application_config;
+pub mod user_config;
+pub mod acl_rule;
+pub mod share_config;
@@ -0,0 +1,32 @@
+use serde::{Serialize, Deserialize};
+
+use super::acl_rule::AclRule;
+
+/// Share of sync paths without a share name, and of servers which only configure `serving_directory`
+pub const DEFAULT_SHARE: &str = "default";
+
+/// Which way changes flow between the clients and a share
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SyncPolicy {
+    /// Clients get the changes of the share and change it themselves
+    #[default]
+    TwoWay,
+    /// Clients only get the changes of the share, like everyone had read-only access
+    DownloadOnly
+}
+
+/// Directory the server exposes under a name, clients sync it as `name:path`
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ShareConfig {
+    pub name: String,
+    /// Root directory of the share, paths in the share are relative to it
+    pub path: String,
+    /// Which paths of the share the users may read or write, nothing is accessible without a rule
+    #[serde(default)]
+    pub acl: Vec<AclRule>,
+    #[serde(default)]
+    pub policy: SyncPolicy,
+    /// How long deleted files of the share are remembered, the server wide setting when not set
+    pub tombstone_retention_days: Option<u64>
+}
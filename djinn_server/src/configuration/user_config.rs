@@ -0,0 +1,11 @@
+use serde::{Serialize, Deserialize};
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct UserConfig {
+    pub name: String,
+    /// Argon2 hash of the password, as printed by `djinn_server hash-password`
+    pub password_hash: String,
+    /// Groups the user is in, for the ACL rules which name a group
+    #[serde(default)]
+    pub groups: Vec<String>
+}
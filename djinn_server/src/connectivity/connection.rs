@@ -1,20 +1,24 @@
-use std::{error::Error, sync::Arc, hash::Hash, collections::HashMap};
+use std::{error::Error, sync::Arc, time::Duration};
 
 use super::{ConnectionData, ConnectionUpdate, ConnectionUpdateType};
-use crate::{
-    processing::PacketHandler,
-    syncing::{ClientIndexHandler, SourceOfTruth},
-};
+use crate::{configuration::acl_rule::Access, processing::PacketHandler, syncing::{unlock_uploads_of, SyncScope}};
 use djinn_core_lib::{
-    data::packets::{packet::Packet, PacketReader, ControlPacket, ControlPacketType},
+    data::{
+        handshake::{Capability, Hello},
+        messages::{ControlMessage, HelloDeny, SyncRequest, SyncUpdate},
+        packets::{packet::Packet, PacketReader, ControlPacket, SendScheduler},
+        syncing::{SyncAction, SyncPlan, SyncReason},
+    },
     jobs::{Job, JobType},
 };
 use tokio::{
-    io::{AsyncWriteExt, BufReader, WriteHalf},
-    net::TcpStream,
+    io::BufReader,
     sync::Mutex,
+    time::timeout,
 };
-use uuid::{Uuid, timestamp};
+use uuid::Uuid;
+
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
 
 pub struct Connection {
     pub uuid: Uuid,
@@ -45,12 +49,95 @@ impl Connection {
         None
     }
 
+    /// Whether both sides agreed to compress data packets
+    pub async fn compression(&self) -> bool {
+        let data = self.data.lock().await;
+        data.handshake.as_ref().is_some_and(|handshake| handshake.has_capability(Capability::Compression))
+    }
+
+    /// Who the client authenticated as, None until it did
+    pub async fn user(&self) -> Option<String> {
+        let data = self.data.lock().await;
+        data.user.clone()
+    }
+
+    /// What the client syncs, None until it sent a sync request
+    pub async fn sync_scope(&self) -> Option<SyncScope> {
+        let data = self.data.lock().await;
+        data.sync_scope.clone()
+    }
+
+    /// What the user may do with a path of the sync
+    pub async fn access_to(&self, scope: &SyncScope, path: &str) -> Access {
+        match self.user().await {
+            Some(user) => scope.access(&user, path),
+            None => Access::None,
+        }
+    }
+
     pub async fn add_job(&mut self, job: Job) {
         let mut data = self.data.lock().await;
         data.jobs.push(Arc::new(Mutex::new(job)));
     }
 
-    pub async fn listen(&mut self) {
+    pub async fn handshake(&mut self, packet_reader: &mut PacketReader) -> Result<(), Box<dyn Error>> {
+        let data = self.data.lock().await;
+        let read_stream_arc = data.read_stream.clone();
+        drop(data);
+
+        // The first packet on every connection has to be a Hello
+        let mut read_stream = read_stream_arc.lock().await;
+        let mut reader = BufReader::new(read_stream.as_mut().ok_or("Connection closed before Hello")?);
+        let packets = timeout(HANDSHAKE_TIMEOUT, packet_reader.read(&mut reader, Some(1)))
+            .await
+            .map_err(|_| "Timed out waiting for Hello")??;
+        drop(read_stream);
+
+        let packet = packets.first().ok_or("Connection closed before Hello")?;
+        let control_packet = packet
+            .as_any()
+            .downcast_ref::<ControlPacket>()
+            .ok_or("Expected Hello but received a data packet")?;
+
+        let client_hello = match ControlMessage::from_packet(control_packet) {
+            Ok(ControlMessage::Hello(client_hello)) => client_hello,
+            Ok(_) => return Err("Expected Hello as first packet".into()),
+            Err(error) => return Err(format!("Malformed Hello: {}", error).into()),
+        };
+        let server_hello = Hello::new(vec![Capability::Compression]);
+
+        match server_hello.negotiate(&client_hello) {
+            Ok(agreed) => {
+                self.send_packet(ControlMessage::HelloAck(agreed.clone()).to_packet(None)).await?;
+                self.flush().await;
+
+                info!(
+                    "Handshake with {} done: protocol v{}, client {}, capabilities {:?}",
+                    self.uuid, agreed.protocol_version, client_hello.software_version, agreed.capabilities
+                );
+
+                let mut data = self.data.lock().await;
+                data.handshake = Some(agreed);
+                if let Some(name) = client_hello.name {
+                    data.client_name = name;
+                }
+
+                Ok(())
+            }
+            Err(reason) => {
+                let response = ControlMessage::HelloDeny(HelloDeny {
+                    hello: server_hello,
+                    reason: reason.clone(),
+                });
+                self.send_packet(response.to_packet(None)).await?;
+                self.flush().await;
+
+                Err(reason.into())
+            }
+        }
+    }
+
+    pub async fn listen(&mut self, mut packet_reader: PacketReader) {
         let data_arc = self.data.clone();
         let data = data_arc.lock().await;
         let connection_uuid = data.uuid;
@@ -66,11 +153,21 @@ impl Connection {
         info!("Listening for packets on connection {}", connection_uuid);
 
         // Handle incoming streams
-        let mut packet_reader = PacketReader::new();
         loop {
             let mut read_stream = read_stream_arc.lock().await;
-            let mut reader = BufReader::new(&mut *read_stream);
-            let packets = packet_reader.read(&mut reader, None).await;
+            let Some(stream) = read_stream.as_mut() else {
+                break;
+            };
+            let mut reader = BufReader::new(stream);
+            let packets = match packet_reader.read(&mut reader, None).await {
+                Ok(packets) => packets,
+                Err(error) => {
+                    warn!("Dropping connection {}: {}", connection_uuid, error);
+                    drop(read_stream);
+                    self.shutdown().await;
+                    break;
+                }
+            };
 
             if packets.is_empty() {
                 // Connection closed
@@ -79,34 +176,65 @@ impl Connection {
             }
 
             for packet in packets {
+                // Whatever else the client sent along is not handled once it was given up on
+                if self.is_closed().await {
+                    break;
+                }
+
                 let packet_handler = PacketHandler {};
                 packet_handler.handle_boxed_packet(packet, self).await;
             }
+
+            if self.is_closed().await {
+                info!("Closed connection {}", connection_uuid);
+                *read_stream = None;
+                break;
+            }
         }
+
+        // Also stops the updates for the client
+        self.data.lock().await.closed = true;
+
+        // Interrupted uploads can be continued by the next connection
+        unlock_uploads_of(connection_uuid).await;
     }
 
+    /// Waits until the packets sent so far are written
     pub async fn flush(&mut self) {
-        let data = self.data.lock().await;
-        let write_stream_arc = data.write_stream.clone();
-        let mut write_stream = write_stream_arc.lock().await;
-        write_stream.flush().await.unwrap();
+        let sender = self.get_sender().await;
+        if let Err(error) = sender.flush().await {
+            debug!("Failed to flush connection {}: {}", self.uuid, error);
+        }
     }
 
+    pub async fn shutdown(&mut self) {
+        let sender = self.get_sender().await;
+        if let Err(error) = sender.shutdown().await {
+            debug!("Failed to shut down connection {}: {}", self.uuid, error);
+        }
+    }
+
+    /// Gives up on the client, the connection stops reading once the current packet is handled
+    pub async fn close(&mut self) {
+        self.data.lock().await.closed = true;
+        self.shutdown().await;
+    }
+
+    pub async fn is_closed(&self) -> bool {
+        self.data.lock().await.closed
+    }
+
+    /// Queues a control packet, it is written ahead of the data of running transfers
     pub async fn send_packet(&mut self, packet: impl Packet) -> Result<(), Box<dyn Error>> {
-        // Convert packet to buffer
-        let buffer = packet.to_buffer();
-        // Write buffer to stream
-        let data = self.data.lock().await;
-        let write_stream_arc = data.write_stream.clone();
-        let mut write_stream = write_stream_arc.lock().await;
-        write_stream.write_all(&buffer).await?;
+        let sender = self.get_sender().await;
+        sender.send_control(packet)?;
 
         Ok(())
     }
 
-    pub async fn get_write_stream(&mut self) -> Arc<Mutex<WriteHalf<TcpStream>>> {
+    pub async fn get_sender(&mut self) -> SendScheduler {
         let data = self.data.lock().await;
-        data.write_stream.clone()
+        data.sender.clone()
     }
 
     pub async fn listen_for_broadcasts(&mut self) {
@@ -120,9 +248,17 @@ impl Connection {
             let broadcast = broadcast_receiver.recv().await;
             drop(broadcast_receiver);
 
+            // Nothing is sent to a client which is gone
+            if self.is_closed().await {
+                break;
+            }
+
             match broadcast {
                 Ok(broadcast) => {
-                    self.handle_connection_update(broadcast).await;
+                    if let Err(error) = self.handle_connection_update(broadcast).await {
+                        warn!("Stopping updates for connection {}: {}", self.uuid, error);
+                        break;
+                    }
                 }
                 Err(_) => {
                     // Connection closed
@@ -133,14 +269,19 @@ impl Connection {
         }
     }
 
-    pub async fn handle_connection_update(&mut self, connection_update: ConnectionUpdate) {
+    pub async fn handle_connection_update(&mut self, connection_update: ConnectionUpdate) -> Result<(), Box<dyn Error>> {
         match connection_update.update_type {
             ConnectionUpdateType::ServerIndexUpdated => {
                 let mut data = self.data.lock().await;
                 if data.uuid == connection_update.connection_uuid {
                     // Ignore own broadcast
-                    return;
+                    return Ok(());
                 }
+                // Changes of other shares are none of the client's business
+                let Some(scope) = data.sync_scope.clone().filter(|scope| scope.share.name == connection_update.share) else {
+                    return Ok(());
+                };
+                let user = data.user.clone().unwrap_or_default();
                 //Find active sync job
                 let mut arc_sync_job = None;
                 for job in &mut data.jobs {
@@ -154,42 +295,69 @@ impl Connection {
                 let last_index = data.last_index.clone();
                 drop(data);
 
-                let mut changes: HashMap<String, String> = HashMap::new();
+                // Without a sync job there is no one to tell
+                let Some(arc_sync_job) = arc_sync_job else {
+                    return Ok(());
+                };
 
-                for (path, timestamp) in connection_update.data {
-                    let last_timestamp = last_index.get(&path);
+                // Dry runs only get their own plan, not changes made by other clients
+                {
+                    let sync_job = arc_sync_job.lock().await;
+                    if SyncRequest::from_params(&sync_job.params).is_ok_and(|request| request.dry_run) {
+                        return Ok(());
+                    }
+                }
+
+                let mut changes = SyncPlan::new();
+
+                for (share_path, entry) in connection_update.data {
+                    // Only changes in the synced directory which the user may read, as paths of the sync
+                    let Some(path) = scope.rebase(&share_path) else {
+                        continue;
+                    };
+                    if scope.access(&user, &path) == Access::None {
+                        continue;
+                    }
+
+                    let last_entry = last_index.get(&path);
 
                     // If files is created/updates
-                    if timestamp != 0 {
-                        // File not in client index
-                        if last_timestamp.is_none() {
-                            changes.insert(path, "GET".to_owned());
-                        } else { // File in client index
-                            let last_timestamp = last_timestamp.unwrap();
-
-                            // File has been updated
-                            if last_timestamp < &timestamp {
-                                changes.insert(path, "GET".to_owned());
-                            } else {
-                                // Skip because client will push themselves
+                    if !entry.is_deleted() {
+                        match last_entry {
+                            // File not in client index
+                            None => {
+                                changes.insert(path, SyncAction::Get, SyncReason::MissingOnClient, Some(entry));
+                            }
+                            // File in client index
+                            Some(last_entry) => {
+                                // File has been updated
+                                if !last_entry.is_same_as(&entry) && last_entry.modified_time < entry.modified_time {
+                                    changes.insert(path, SyncAction::Get, SyncReason::ChangedOnServer, Some(entry));
+                                } else {
+                                    // Skip because client will push themselves
+                                }
                             }
                         }
                     } else { // File is deleted
                         // File not in client index
-                        if last_timestamp.is_none() {
+                        if last_entry.is_none() {
                             // Skip because client doesn't have file
-                        } else { // File in client index
+                        } else if let Some(last_entry) = last_entry { // File in client index
                             // Delete file
-                            changes.insert(path, "DELETE".to_owned());
+                            changes.insert(path, SyncAction::Delete, SyncReason::DeletedOnServer, Some(last_entry.clone()));
                         }
                     }
+                }
+
+                // A move arrives as a delete and an add in the same update
+                changes.pair_moves(SyncAction::Delete, SyncAction::Get, SyncAction::Move, SyncReason::MovedOnServer);
 
+                if !changes.is_empty() {
                     // Send sync update to client
-                    let mut response = ControlPacket::new(ControlPacketType::SyncUpdate, changes.clone());
-                    let sync_job = arc_sync_job.as_ref().unwrap().lock().await;
-                    response.job_id = Some(sync_job.id);
+                    let sync_job_id = arc_sync_job.lock().await.id;
+                    let response = ControlMessage::SyncUpdate(SyncUpdate { plan: changes }).to_packet(Some(sync_job_id));
                     // Send packet
-                    self.send_packet(response).await.unwrap();
+                    self.send_packet(response).await?;
                     self.flush().await;
                 }
 
@@ -204,5 +372,7 @@ impl Connection {
                 // }
             }
         }
+
+        Ok(())
     }
 }
@@ -1,34 +1,54 @@
 use std::{sync::{Arc}, collections::HashMap};
 
-use djinn_core_lib::jobs::Job;
-use tokio::{net::TcpStream, io::{ReadHalf, WriteHalf}, sync::{Mutex, broadcast::{Receiver, Sender}}};
+use djinn_core_lib::{connectivity::BoxedStream, data::{handshake::Hello, packets::SendScheduler, syncing::IndexEntry}, jobs::Job};
+use tokio::{io::ReadHalf, sync::{Mutex, broadcast::{Receiver, Sender}}};
 use uuid::Uuid;
 
+use crate::syncing::SyncScope;
+
 use super::ConnectionUpdate;
 
 pub struct ConnectionData {
-    pub read_stream: Arc<Mutex<ReadHalf<TcpStream>>>,
-    pub write_stream: Arc<Mutex<WriteHalf<TcpStream>>>,
+    /// Dropped once the connection stops listening
+    pub read_stream: Arc<Mutex<Option<ReadHalf<BoxedStream>>>>,
+    pub sender: SendScheduler,
     pub uuid: Uuid,
     pub jobs: Vec<Arc<Mutex<Job>>>,
     pub connections_broadcast_receiver: Arc<Mutex<Receiver<ConnectionUpdate>>>,
     pub connections_broadcast_sender: Arc<Mutex<Sender<ConnectionUpdate>>>,
     pub new_job_id: u32,
-    pub last_index: HashMap<String, usize>
+    pub last_index: HashMap<String, IndexEntry>,
+    pub handshake: Option<Hello>,
+    /// Name the client introduced itself with, or a short form of the connection uuid
+    pub client_name: String,
+    /// Who the client authenticated as, no command runs before this is set
+    pub user: Option<String>,
+    pub failed_authentications: u32,
+    /// Set when the server gives up on the client, nothing more is read from it
+    pub closed: bool,
+    /// What the client syncs, transfers and broadcasts are limited to it
+    pub sync_scope: Option<SyncScope>
 }
 
 impl ConnectionData {
-    pub fn new(stream: TcpStream, connections_broadcast_receiver: Receiver<ConnectionUpdate>, connections_broadcast_sender: Sender<ConnectionUpdate>) -> ConnectionData {
+    pub fn new(stream: BoxedStream, connections_broadcast_receiver: Receiver<ConnectionUpdate>, connections_broadcast_sender: Sender<ConnectionUpdate>, max_concurrent_transfers: usize) -> ConnectionData {
         let (read_stream, write_stream) = tokio::io::split(stream);
+        let uuid = Uuid::new_v4();
         ConnectionData {
-            read_stream: Arc::new(Mutex::new(read_stream)),
-            write_stream: Arc::new(Mutex::new(write_stream)),
-            uuid: Uuid::new_v4(),
+            read_stream: Arc::new(Mutex::new(Some(read_stream))),
+            sender: SendScheduler::new(write_stream, max_concurrent_transfers),
+            uuid,
             jobs: vec![],
             connections_broadcast_receiver: Arc::new(Mutex::new(connections_broadcast_receiver)),
             connections_broadcast_sender: Arc::new(Mutex::new(connections_broadcast_sender)),
             new_job_id: 0,
-            last_index: HashMap::new()
+            last_index: HashMap::new(),
+            handshake: None,
+            client_name: uuid.to_string()[..8].to_string(),
+            user: None,
+            failed_authentications: 0,
+            closed: false,
+            sync_scope: None
         }
     }
 }
@@ -1,12 +1,16 @@
-use std::{sync::Arc};
+use std::{collections::HashMap, sync::Arc, net::SocketAddr, time::Duration};
 
-use tokio::{net::{TcpListener, TcpStream}, sync::{Mutex, broadcast:: {Sender, Receiver}}};
-use crate::CONFIG;
+use djinn_core_lib::{connectivity::{tls::{self, ServerConfig}, BoxedStream}, data::packets::PacketReader};
+use tokio::{net::{TcpListener, TcpStream}, sync::{Mutex, mpsc, broadcast:: {Sender, Receiver}}, time::timeout};
+use uuid::Uuid;
+use crate::{auth::ANONYMOUS_USER, CONFIG};
 
 use super::{ConnectionData, Connection, ConnectionUpdate};
 
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
 pub struct ConnectionManager {
-    connections: Vec<Arc<Mutex<ConnectionData>>>,
+    connections: HashMap<Uuid, Arc<Mutex<ConnectionData>>>,
     _connections_broadcast_receiver: Receiver<ConnectionUpdate>,
     connections_broadcast_sender: Sender<ConnectionUpdate>,
 }
@@ -15,34 +19,122 @@ impl ConnectionManager {
     pub fn new() -> ConnectionManager {
         let (connection_broadcast_writer, connection_broadcast_reader) = tokio::sync::broadcast::channel(100);
         ConnectionManager {
-            connections: vec![],
+            connections: HashMap::new(),
             connections_broadcast_sender: connection_broadcast_writer,
             _connections_broadcast_receiver: connection_broadcast_reader
         }
     }
 
     pub async fn listen_for_connections(&mut self) {
+        let tls_config = match tls_config() {
+            Ok(tls_config) => tls_config,
+            Err(reason) => {
+                error!("{}", reason);
+                std::process::exit(1);
+            }
+        };
+
+        if CONFIG.users.as_ref().unwrap().is_empty() && !CONFIG.allow_anonymous.unwrap() {
+            warn!("No users configured and anonymous access is off, no client can authenticate");
+        }
+
         let host = CONFIG.host.clone().unwrap();
         let port = CONFIG.port.unwrap();
         let listener = TcpListener::bind(format!("{}:{}", host, port)).await.unwrap();
         info!("Listening on {}:{}", host, port);
+
+        // TLS handshakes run on their own so a slow client does not hold up the others
+        let (secured_sender, mut secured_receiver) = mpsc::unbounded_channel();
+        // Connections report back when they end, so they can be forgotten
+        let (closed_sender, mut closed_receiver) = mpsc::unbounded_channel();
+
         loop {
-            let (socket, _) = listener.accept().await.unwrap();
-            info!("New connection accepted from: {}", socket.peer_addr().unwrap());
-            self.handle_new_connection(socket).await;
+            tokio::select! {
+                accepted = listener.accept() => {
+                    let (socket, address) = accepted.unwrap();
+                    info!("New connection accepted from: {}", address);
+                    tokio::spawn(secure(socket, address, tls_config.clone(), secured_sender.clone()));
+                }
+                Some(stream) = secured_receiver.recv() => {
+                    self.handle_new_connection(stream, closed_sender.clone()).await;
+                }
+                Some(connection_uuid) = closed_receiver.recv() => {
+                    self.connections.remove(&connection_uuid);
+                }
+            }
         }
     }
 
-    async fn handle_new_connection(&mut self, stream: TcpStream) {
+    async fn handle_new_connection(&mut self, stream: BoxedStream, closed: mpsc::UnboundedSender<Uuid>) {
         let new_receiver = self.connections_broadcast_sender.subscribe();
-        let connection_data = ConnectionData::new(stream, new_receiver, self.connections_broadcast_sender.clone());
+        let connection_data = ConnectionData::new(
+            stream,
+            new_receiver,
+            self.connections_broadcast_sender.clone(),
+            CONFIG.max_concurrent_transfers.unwrap(),
+        );
         let connection_uuid = connection_data.uuid;
         let packed_connection_data = Arc::new(Mutex::new(connection_data));
-        self.connections.push(packed_connection_data.clone());
+        self.connections.insert(connection_uuid, packed_connection_data.clone());
 
         tokio::spawn(async move {
-            let mut connection = Connection::new(connection_uuid, packed_connection_data);
-            connection.listen().await;
+            serve(Connection::new(connection_uuid, packed_connection_data)).await;
+            let _ = closed.send(connection_uuid);
         });
     }
 }
+
+/// Talks to the client until the connection ends
+async fn serve(mut connection: Connection) {
+    let mut packet_reader = PacketReader::with_max_packet_size(CONFIG.max_packet_size.unwrap());
+
+    // Refuse to talk to clients which don't speak our protocol
+    let handshake_result = connection.handshake(&mut packet_reader).await.map_err(|error| error.to_string());
+
+    if let Err(reason) = handshake_result {
+        warn!("Rejected connection {}: {}", connection.uuid, reason);
+        connection.shutdown().await;
+        return;
+    }
+
+    // Otherwise the client has to authenticate before anything else
+    if CONFIG.allow_anonymous.unwrap() {
+        connection.data.lock().await.user = Some(ANONYMOUS_USER.to_string());
+    }
+
+    connection.listen(packet_reader).await;
+}
+
+/// Without a certificate the server only starts when plaintext connections were explicitly allowed
+fn tls_config() -> Result<Option<Arc<ServerConfig>>, String> {
+    match (&CONFIG.tls_certificate, &CONFIG.tls_key) {
+        (Some(certificate), Some(key)) => tls::server_config(certificate, key)
+            .map(Some)
+            .map_err(|error| format!("Failed to load the TLS certificate: {}", error)),
+        (None, None) if CONFIG.allow_plaintext.unwrap() => {
+            warn!("No TLS certificate configured, connections are not encrypted");
+            Ok(None)
+        }
+        (None, None) => Err("No TLS certificate configured, set tls_certificate and tls_key or allow_plaintext".to_string()),
+        _ => Err("Both tls_certificate and tls_key have to be set".to_string()),
+    }
+}
+
+async fn secure(socket: TcpStream, address: SocketAddr, tls_config: Option<Arc<ServerConfig>>, secured: mpsc::UnboundedSender<BoxedStream>) {
+    let stream: BoxedStream = match tls_config {
+        Some(tls_config) => match timeout(TLS_HANDSHAKE_TIMEOUT, tls::accept(tls_config, socket)).await {
+            Ok(Ok(stream)) => stream,
+            Ok(Err(error)) => {
+                warn!("TLS handshake with {} failed: {}", address, error);
+                return;
+            }
+            Err(_) => {
+                warn!("TLS handshake with {} timed out", address);
+                return;
+            }
+        },
+        None => Box::new(socket),
+    };
+
+    let _ = secured.send(stream);
+}
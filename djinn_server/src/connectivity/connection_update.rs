@@ -1,7 +1,10 @@
 use std::collections::HashMap;
 
+use djinn_core_lib::data::syncing::IndexEntry;
 use uuid::Uuid;
 
+use crate::syncing::SyncScope;
+
 #[derive(Clone, Debug)]
 pub enum ConnectionUpdateType {
     ServerIndexUpdated,
@@ -12,15 +15,20 @@ pub enum ConnectionUpdateType {
 pub struct ConnectionUpdate {
     pub update_type: ConnectionUpdateType,
     pub connection_uuid: Uuid,
-    pub data: HashMap<String, usize>,
+    /// Share the changed files are in, only connections syncing it are told
+    pub share: String,
+    /// Changed entries by their path in the share, each connection rebases them to what it syncs
+    pub data: HashMap<String, IndexEntry>,
 }
 
 impl ConnectionUpdate {
-    pub fn new(connection_uuid: Uuid, data: HashMap<String, usize>) -> Self {
+    /// Update of changes made by a connection, `data` is keyed by paths of its sync
+    pub fn new(connection_uuid: Uuid, scope: &SyncScope, data: HashMap<String, IndexEntry>) -> Self {
         Self {
             connection_uuid,
+            share: scope.share.name.clone(),
             update_type: ConnectionUpdateType::ServerIndexUpdated,
-            data,
+            data: data.into_iter().map(|(path, entry)| (scope.to_share_path(&path), entry)).collect(),
         }
     }
 }
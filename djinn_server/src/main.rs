@@ -1,11 +1,10 @@
 extern crate pretty_env_logger;
-use std::collections::HashMap;
-
 use configuration::application_config::ApplicationConfig;
+use djinn_core_lib::data::syncing::HashCache;
 use connectivity::ConnectionManager;
 use lazy_static::lazy_static;
-use tokio::sync::Mutex;
 
+mod auth;
 mod connectivity;
 mod configuration;
 mod processing;
@@ -16,13 +15,22 @@ mod syncing;
 
 lazy_static! {
     static ref CONFIG: ApplicationConfig = ApplicationConfig::build();
-    static ref SERVER_DELETES: Mutex<HashMap<String, usize>> = Mutex::new(HashMap::new());
+    static ref HASH_CACHE: HashCache = HashCache::new();
 }
 
 
 #[tokio::main]
 async fn main(){
     pretty_env_logger::init();
+
+    // Prints the hash of a password read from stdin, for the users in config.yaml
+    if std::env::args().nth(1).as_deref() == Some("hash-password") {
+        let mut password = String::new();
+        std::io::stdin().read_line(&mut password).expect("Failed to read password");
+        println!("{}", auth::hash_password(password.trim_end_matches(['\r', '\n'])));
+        return;
+    }
+
     let mut listener = ConnectionManager::new();
     listener.listen_for_connections().await;
 }
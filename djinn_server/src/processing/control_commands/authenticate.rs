@@ -0,0 +1,101 @@
+use std::error::Error;
+
+use async_trait::async_trait;
+use djinn_core_lib::data::messages::{Authenticate, AuthenticateAck, AuthenticateDeny, ControlMessage};
+
+use crate::{auth::verify_credentials, configuration::user_config::UserConfig, connectivity::Connection};
+
+use super::ControlCommand;
+
+/// Wrong passwords a connection may send before it is closed
+const MAX_FAILED_AUTHENTICATIONS: u32 = 3;
+
+pub struct AuthenticateCommand {
+    /// Users the credentials are checked against
+    pub users: &'static [UserConfig],
+}
+
+#[async_trait]
+impl ControlCommand for AuthenticateCommand {
+    type Message = Authenticate;
+
+    async fn execute(&self, connection: &mut Connection, _job_id: Option<u32>, message: &Authenticate) -> Result<(), Box<dyn Error>> {
+        // Once the attempts are used up nothing is verified anymore, so guessing can not go on and keep the server busy
+        let failed_authentications = connection.data.lock().await.failed_authentications;
+        if failed_authentications >= MAX_FAILED_AUTHENTICATIONS {
+            warn!("Refusing authentication from {} after {} failed attempts", connection.uuid, failed_authentications);
+            connection.close().await;
+            return Ok(());
+        }
+
+        let users = self.users;
+        let user = message.user.clone();
+        let password = message.password.clone();
+        let valid = tokio::task::spawn_blocking(move || verify_credentials(users, &user, &password)).await?;
+
+        let mut data = connection.data.lock().await;
+
+        if valid {
+            data.user = Some(message.user.clone());
+            drop(data);
+
+            info!("{} authenticated as {}", connection.uuid, message.user);
+            let response = ControlMessage::AuthenticateAck(AuthenticateAck { user: message.user.clone() });
+            connection.send_packet(response.to_packet(None)).await?;
+            return Ok(());
+        }
+
+        data.failed_authentications += 1;
+        let failed_authentications = data.failed_authentications;
+        drop(data);
+
+        warn!("{} failed to authenticate as {}", connection.uuid, message.user);
+        let response = ControlMessage::AuthenticateDeny(AuthenticateDeny {
+            reason: "Invalid user or password".to_string(),
+        });
+        connection.send_packet(response.to_packet(None)).await?;
+
+        if failed_authentications >= MAX_FAILED_AUTHENTICATIONS {
+            warn!("Closing {} after {} failed authentications", connection.uuid, failed_authentications);
+            connection.close().await;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tokio::sync::{broadcast, Mutex};
+
+    use crate::{auth::hash_password, connectivity::ConnectionData};
+
+    use super::*;
+
+    fn authenticate(password: &str) -> Authenticate {
+        Authenticate { user: "alice".to_string(), password: password.to_string() }
+    }
+
+    #[tokio::test]
+    async fn test_attempts_after_the_limit_are_not_verified() {
+        let users = vec![UserConfig { name: "alice".to_string(), password_hash: hash_password("secret"), groups: vec![] }];
+        let command = AuthenticateCommand { users: Box::leak(users.into_boxed_slice()) };
+
+        let (_peer, stream) = tokio::io::duplex(1024);
+        let (broadcast_sender, broadcast_receiver) = broadcast::channel(1);
+        let data = ConnectionData::new(Box::new(stream), broadcast_receiver, broadcast_sender, 1);
+        let mut connection = Connection::new(data.uuid, Arc::new(Mutex::new(data)));
+
+        for _ in 0..MAX_FAILED_AUTHENTICATIONS {
+            command.execute(&mut connection, None, &authenticate("wrong")).await.unwrap();
+        }
+        assert!(connection.is_closed().await);
+
+        // Even the right password is refused without being checked
+        command.execute(&mut connection, None, &authenticate("secret")).await.unwrap();
+        assert_eq!(connection.user().await, None);
+        assert_eq!(connection.data.lock().await.failed_authentications, MAX_FAILED_AUTHENTICATIONS);
+    }
+}
@@ -1,7 +1,7 @@
-use std::{collections::HashMap, error::Error};
+use std::error::Error;
 
 use async_trait::async_trait;
-use djinn_core_lib::data::packets::{ControlPacket, PacketType, ControlPacketType};
+use djinn_core_lib::data::messages::ControlMessage;
 
 use crate::connectivity::Connection;
 
@@ -11,13 +11,10 @@ pub struct EchoRequestCommand {}
 
 #[async_trait]
 impl ControlCommand for EchoRequestCommand {
-    async fn execute(&self, connection: &mut Connection, _packet: &ControlPacket) -> Result<(), Box<dyn Error>> {
-        let response = ControlPacket {
-            packet_type: PacketType::Control,
-            control_packet_type: ControlPacketType::EchoReply,
-            job_id: None,
-            params: HashMap::new(),
-        };
+    type Message = ();
+
+    async fn execute(&self, connection: &mut Connection, _job_id: Option<u32>, _message: &()) -> Result<(), Box<dyn Error>> {
+        let response = ControlMessage::EchoReply.to_packet(None);
 
         connection.send_packet(response).await?;
         Ok(())
@@ -6,7 +6,11 @@ mod transfer_request;
 pub use transfer_request::TransferRequestCommand;
 mod transfer_start;
 pub use transfer_start::TransferStartCommand;
+mod transfer_cancel;
+pub use transfer_cancel::TransferCancelCommand;
 mod sync_request;
 pub use sync_request::SyncRequestCommand;
 mod sync_index_update;
 pub use sync_index_update::SyncIndexUpdateCommand;
+mod authenticate;
+pub use authenticate::AuthenticateCommand;
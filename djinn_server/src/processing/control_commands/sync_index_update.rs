@@ -1,8 +1,8 @@
-use std::{collections::HashMap, error::Error, sync::Arc};
+use std::{error::Error, sync::Arc};
 
 use async_trait::async_trait;
 use djinn_core_lib::{
-    data::packets::ControlPacket,
+    data::messages::SyncIndex,
     jobs::{Job, JobType},
 };
 use tokio::sync::Mutex;
@@ -20,13 +20,16 @@ pub struct SyncIndexUpdateCommand {
 
 #[async_trait]
 impl ControlCommand for SyncIndexUpdateCommand {
+    type Message = SyncIndex;
+
     async fn execute(
         &self,
         connection: &mut Connection,
-        packet: &ControlPacket,
+        job_id: Option<u32>,
+        message: &SyncIndex,
     ) -> Result<(), Box<dyn Error>> {
         // Get sync job
-        let arc_sync_job_result = self.get_linked_sync_job(connection, packet).await;
+        let arc_sync_job_result = self.get_linked_sync_job(connection, job_id).await;
 
         if arc_sync_job_result.is_err() {
             return Ok(()); //TODO: Handle error
@@ -34,16 +37,10 @@ impl ControlCommand for SyncIndexUpdateCommand {
 
         let unwrapped_arc_sync_job = arc_sync_job_result.unwrap();
 
-        // Unwrap client index
-        let mut client_index = HashMap::new();
-
-        for (key, value) in packet.params.iter() {
-            client_index.insert(key.clone(), value.parse::<usize>().unwrap());
-        }
-
         // Handle sync index response
         let client_index_handler = ClientIndexHandler::new(
-            client_index,
+            message.index.clone(),
+            message.base.clone(),
             unwrapped_arc_sync_job.clone(),
             self.source_of_truth,
         );
@@ -57,15 +54,15 @@ impl SyncIndexUpdateCommand {
     async fn get_linked_sync_job(
         &self,
         connection: &mut Connection,
-        packet: &ControlPacket,
+        job_id: Option<u32>,
     ) -> Result<Arc<Mutex<Job>>, Box<dyn Error + Send + Sync>> {
         //Check if job id exists
-        if packet.job_id.is_none() {
+        if job_id.is_none() {
             return Err("SyncIndexResponse packet does not contain a job id".into());
         }
 
         //Check if sync exists
-        let possible_sync_job = connection.get_job(packet.job_id.unwrap()).await;
+        let possible_sync_job = connection.get_job(job_id.unwrap()).await;
 
         if possible_sync_job.is_none() {
             return Err("SyncIndexResponse packet does not contain a valid job id".into());
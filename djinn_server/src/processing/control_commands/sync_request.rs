@@ -1,12 +1,16 @@
 use async_trait::async_trait;
 use djinn_core_lib::{
-    data::packets::{ControlPacket, ControlPacketType, TransferDenyReason},
+    data::{
+        messages::{ControlMessage, SyncAck, SyncDeny, SyncRequest},
+        packets::{TransferChecksum, TransferDenyReason},
+        syncing::expire_partial_transfers,
+    },
     jobs::{Job, JobStatus, JobType},
 };
-use std::{collections::HashMap, error::Error};
+use std::{error::Error, path::Path, time::Duration};
 use tokio::{fs, time::sleep};
 
-use crate::{connectivity::Connection, CONFIG};
+use crate::{configuration::share_config::DEFAULT_SHARE, connectivity::Connection, syncing::SyncScope, CONFIG};
 
 use super::ControlCommand;
 
@@ -14,27 +18,43 @@ pub struct SyncRequestCommand {}
 
 #[async_trait]
 impl ControlCommand for SyncRequestCommand {
+    type Message = SyncRequest;
+
     async fn execute(
         &self,
         connection: &mut Connection,
-        packet: &ControlPacket,
+        _job_id: Option<u32>,
+        message: &SyncRequest,
     ) -> Result<(), Box<dyn Error>> {
-        let path = packet.params.get("path").unwrap();
-        let full_path = CONFIG.serving_directory.clone().unwrap() + "/" + path;
+        let scope = match SyncScope::of(message) {
+            Some(Ok(scope)) => scope,
+            Some(Err(error)) => {
+                warn!("{} may not sync: {}", connection.uuid, error);
+                return self.deny(connection, TransferDenyReason::PermissionDenied).await;
+            }
+            None => {
+                warn!("{} asked for unknown share {}", connection.uuid, message.share().unwrap_or(DEFAULT_SHARE));
+                return self.deny(connection, TransferDenyReason::FileNotFound).await;
+            }
+        };
+
+        let full_path = match scope.resolve("/").await {
+            Ok(full_path) => full_path,
+            Err(error) => {
+                warn!("{} may not sync: {}", connection.uuid, error);
+                return self.deny(connection, TransferDenyReason::PermissionDenied).await;
+            }
+        };
+
+        // The sync plan only holds what the user may read, so the root itself does not have to be readable
+        if !scope.is_readable(&connection.user().await.unwrap_or_default()) {
+            warn!("{} may not sync {}", connection.uuid, message.path);
+            return self.deny(connection, TransferDenyReason::PermissionDenied).await;
+        }
 
         //Check if file exists if download request
-        if fs::metadata(full_path).await.is_err() {
-            let mut params = HashMap::new();
-            params.insert(
-                "reason".to_string(),
-                TransferDenyReason::FileNotFound.to_string(),
-            );
-
-            let response_packet = ControlPacket::new(ControlPacketType::SyncDeny, params);
-            connection.send_packet(response_packet).await?;
-            connection.flush().await;
-
-            return Ok(());
+        if fs::metadata(&full_path).await.is_err() {
+            return self.deny(connection, TransferDenyReason::FileNotFound).await;
         }
 
         let job_id = connection.new_job_id().await;
@@ -43,24 +63,26 @@ impl ControlCommand for SyncRequestCommand {
             id: job_id,
             job_type: JobType::Sync,
             status: JobStatus::Pending,
-            params: packet.params.clone(),
+            params: message.to_params(),
             open_file: None,
+            checksum: TransferChecksum::new(),
+            delta: None,
         };
 
         connection.add_job(job).await;
+        connection.data.lock().await.sync_scope = Some(scope);
+
+        // Uploads which were not continued in time are not coming back
+        let retention = Duration::from_secs(CONFIG.partial_transfer_retention_hours.unwrap() * 60 * 60);
+        expire_partial_transfers(Path::new(&full_path), retention).await;
 
         //Send response
-        let mut response = ControlPacket::new(ControlPacketType::SyncAck, HashMap::new());
-        response
-            .params
-            .insert("job_id".to_string(), job_id.to_string());
+        let response = ControlMessage::SyncAck(SyncAck { job_id }).to_packet(None);
         connection.send_packet(response).await?;
         connection.flush().await;
 
         //Also send index request packet
-        let mut index_request_packet =
-            ControlPacket::new(ControlPacketType::SyncIndexRequest, HashMap::new());
-        index_request_packet.job_id = Some(job_id);
+        let index_request_packet = ControlMessage::SyncIndexRequest.to_packet(Some(job_id));
         connection.send_packet(index_request_packet).await?;
         connection.flush().await;
 
@@ -71,3 +93,13 @@ impl ControlCommand for SyncRequestCommand {
         return Ok(());
     }
 }
+
+impl SyncRequestCommand {
+    async fn deny(&self, connection: &mut Connection, reason: TransferDenyReason) -> Result<(), Box<dyn Error>> {
+        let response_packet = ControlMessage::SyncDeny(SyncDeny { reason }).to_packet(None);
+        connection.send_packet(response_packet).await?;
+        connection.flush().await;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,54 @@
+use std::error::Error;
+
+use async_trait::async_trait;
+use djinn_core_lib::{
+    data::{
+        messages::{TransferCancel, TransferDirection, TransferRequest},
+        syncing::{join_path, PartialTransfer},
+    },
+    jobs::{JobStatus, JobType},
+};
+
+use crate::{connectivity::Connection, syncing::unlock_upload};
+
+use super::ControlCommand;
+
+pub struct TransferCancelCommand {}
+
+#[async_trait]
+impl ControlCommand for TransferCancelCommand {
+    type Message = TransferCancel;
+
+    async fn execute(&self, connection: &mut Connection, _job_id: Option<u32>, message: &TransferCancel) -> Result<(), Box<dyn Error>> {
+        let Some(arc_job) = connection.get_job(message.job_id).await else {
+            warn!("{} canceled unknown job {}", connection.uuid, message.job_id);
+            return Ok(());
+        };
+
+        let scope = connection.sync_scope().await;
+        let mut job = arc_job.lock().await;
+
+        // A transfer which already ended has nothing left to cancel
+        if !matches!(job.job_type, JobType::Transfer) || matches!(job.status, JobStatus::Finished | JobStatus::Canceled) {
+            return Ok(());
+        }
+
+        job.status = JobStatus::Canceled;
+        job.open_file = None;
+        job.delta = None;
+
+        let request = TransferRequest::from_params(&job.params)?;
+        info!("{} -> server: canceled {} ({})", connection.uuid, request.file_path, message.reason);
+
+        // Drop what was received of an upload, the client sends the file again once it is settled
+        if let (TransferDirection::ToServer, Some(scope)) = (&request.direction, scope) {
+            let full_path = join_path(&scope.root(), &request.file_path)?;
+            if let Err(error) = PartialTransfer::new(&full_path).discard().await {
+                warn!("Failed to remove partial upload of {}: {}", request.file_path, error);
+            }
+            unlock_upload(&full_path, connection.uuid).await;
+        }
+
+        Ok(())
+    }
+}
@@ -1,9 +1,9 @@
-use std::{collections::HashMap, error::Error};
+use std::error::Error;
 use async_trait::async_trait;
-use djinn_core_lib::{data::packets::{ControlPacket, ControlPacketType, TransferDenyReason}, jobs::{Job, JobType, JobStatus}};
+use djinn_core_lib::{data::{delta::{DeltaApplier, Signature, DELTA_MIN_SIZE}, messages::{ControlMessage, TransferAck, TransferDeny, TransferDirection, TransferRequest}, packets::{TransferChecksum, TransferDenyReason}, syncing::{transfer_key, PartialTransfer}}, jobs::{Job, JobType, JobStatus}};
 use tokio::fs;
 
-use crate::{connectivity::Connection, CONFIG};
+use crate::{configuration::acl_rule::Access, connectivity::Connection, syncing::lock_upload};
 
 use super::ControlCommand;
 
@@ -11,33 +11,80 @@ pub struct TransferRequestCommand {}
 
 #[async_trait]
 impl ControlCommand for TransferRequestCommand {
-    async fn execute(&self, connection: &mut Connection, packet: &ControlPacket) -> Result<(), Box<dyn Error>> {
-        let path = packet.params.get("file_path").unwrap();
-        let direction = packet.params.get("direction").unwrap();
-        let transfer_id = packet.params.get("transfer_id").unwrap();
-        let full_path = CONFIG.serving_directory.clone().unwrap() + "/" + path;
-        debug!("Transfer request for {} to {}", path, direction);
+    type Message = TransferRequest;
 
-        //Check if file exists if download request
-        if direction == "toClient" && fs::metadata(&full_path).await.is_err() {
-            let mut params = HashMap::new();
-            params.insert("reason".to_string(), TransferDenyReason::FileNotFound.to_string());
-            params.insert("transfer_id".to_string(), transfer_id.to_string());
+    async fn execute(&self, connection: &mut Connection, _job_id: Option<u32>, message: &TransferRequest) -> Result<(), Box<dyn Error>> {
+        debug!("Transfer request for {} to {}", message.file_path, message.direction);
+
+        // Uploads are stored with the modification time of the client, without it the request is malformed
+        if message.direction == TransferDirection::ToServer && message.modified_time.is_none() {
+            warn!("Ignoring upload request for {} from {} without a modification time", message.file_path, connection.uuid);
+            return Ok(());
+        }
+
+        // Paths of transfers are relative to the synced directory
+        let Some(scope) = connection.sync_scope().await else {
+            warn!("{} requested {} without syncing", connection.uuid, message.file_path);
+            let response = ControlMessage::TransferDeny(TransferDeny {
+                transfer_id: message.transfer_id,
+                reason: TransferDenyReason::FileNotFound,
+            }).to_packet(None);
+
+            connection.send_packet(response).await?;
+
+            return Ok(());
+        };
+
+        let full_path = match scope.resolve(&message.file_path).await {
+            Ok(full_path) => full_path,
+            Err(error) => {
+                warn!("{} may not transfer: {}", connection.uuid, error);
+                let response = ControlMessage::TransferDeny(TransferDeny {
+                    transfer_id: message.transfer_id,
+                    reason: TransferDenyReason::PermissionDenied,
+                }).to_packet(None);
 
-            let response = ControlPacket::new(ControlPacketType::TransferDeny, params);
+                connection.send_packet(response).await?;
+
+                return Ok(());
+            }
+        };
+
+        // Downloads need read access and uploads write access to the file
+        let required_access = match message.direction {
+            TransferDirection::ToClient => Access::ReadOnly,
+            TransferDirection::ToServer => Access::ReadWrite,
+        };
+        if connection.access_to(&scope, &message.file_path).await < required_access {
+            warn!("{} may not transfer {} to {}", connection.uuid, message.file_path, message.direction);
+            let response = ControlMessage::TransferDeny(TransferDeny {
+                transfer_id: message.transfer_id,
+                reason: TransferDenyReason::PermissionDenied,
+            }).to_packet(None);
 
             connection.send_packet(response).await?;
 
             return Ok(());
         }
 
-        // Check if file is currently being transferred and upload request
-        if fs::metadata(full_path.clone() + ".djinn_temp").await.is_ok() && direction == "toServer" {
-            let mut params = HashMap::new();
-            params.insert("reason".to_string(), TransferDenyReason::FileWriteLock.to_string());
-            params.insert("transfer_id".to_string(), transfer_id.to_string());
+        //Check if file exists if download request
+        if message.direction == TransferDirection::ToClient && fs::metadata(&full_path).await.is_err() {
+            let response = ControlMessage::TransferDeny(TransferDeny {
+                transfer_id: message.transfer_id,
+                reason: TransferDenyReason::FileNotFound,
+            }).to_packet(None);
+
+            connection.send_packet(response).await?;
+
+            return Ok(());
+        }
 
-            let response = ControlPacket::new(ControlPacketType::TransferDeny, params);
+        // Check if another upload of the file is running, a partial file left by a dropped connection does not count
+        if message.direction == TransferDirection::ToServer && !lock_upload(&full_path, connection.uuid).await {
+            let response = ControlMessage::TransferDeny(TransferDeny {
+                transfer_id: message.transfer_id,
+                reason: TransferDenyReason::FileWriteLock,
+            }).to_packet(None);
 
             connection.send_packet(response).await?;
 
@@ -46,7 +93,41 @@ impl ControlCommand for TransferRequestCommand {
             //TODO: possible cancel job
         }
 
+        // Continue an interrupted transfer of the same file version where it stopped
+        let mut request = message.clone();
+        let mut modified_time = None;
+        if message.direction == TransferDirection::ToServer {
+            request.offset = PartialTransfer::new(&full_path).resume_offset(message.key.as_deref()).await;
+        } else {
+            let metadata = fs::metadata(&full_path).await?;
+            let file_modified_time = metadata.modified()?.duration_since(std::time::UNIX_EPOCH)?.as_secs();
+            let key = transfer_key(&message.file_path, file_modified_time, metadata.len());
+
+            if message.key.as_ref() != Some(&key) || message.offset > metadata.len() {
+                request.offset = 0;
+            }
+            request.key = Some(key);
+            modified_time = Some(file_modified_time);
+        }
 
+        if request.offset > 0 {
+            info!("{} <-> server: continuing {} at {} bytes", connection.uuid, message.file_path, request.offset);
+            request.signature = None;
+        }
+
+        // Let the client send only what changed if the server has an older copy
+        let mut signature = None;
+        let mut delta = None;
+        if message.direction == TransferDirection::ToServer && message.delta && request.offset == 0 {
+            match self.prepare_delta(&full_path).await {
+                Ok(Some((prepared_signature, applier))) => {
+                    signature = Some(prepared_signature);
+                    delta = Some(applier);
+                }
+                Ok(None) => {}
+                Err(error) => warn!("Receiving {} whole, no delta possible: {}", message.file_path, error),
+            }
+        }
 
         //Create job
         let job_id = connection.new_job_id().await;
@@ -54,26 +135,50 @@ impl ControlCommand for TransferRequestCommand {
             id: job_id,
             job_type: JobType::Transfer,
             status: JobStatus::Pending,
-            params: packet.params.clone(),
-            open_file: None
+            params: request.to_params(),
+            open_file: None,
+            checksum: TransferChecksum::new(),
+            delta
         };
 
         connection.add_job(job).await;
 
         //Send ack response
-        let mut response = ControlPacket::new(ControlPacketType::TransferAck, HashMap::new());
-        response.params.insert("job_id".to_string(), job_id.to_string());
-        response.params.insert("transfer_id".to_string(), packet.params.get("transfer_id").unwrap().clone());
-
-        if direction == "toClient" {
-            let modified_time = fs::metadata(&full_path).await?.modified().unwrap().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs();
-            response.params.insert("modified_time".to_string(), modified_time.to_string());
-        }
+        let response = TransferAck {
+            transfer_id: message.transfer_id,
+            job_id,
+            modified_time,
+            // Downloads are sent as a delta whenever the client has a copy and nothing is continued
+            delta: message.direction == TransferDirection::ToClient && request.signature.is_some(),
+            signature,
+            key: if message.direction == TransferDirection::ToClient { request.key } else { None },
+            offset: request.offset,
+        };
 
-        connection.send_packet(response).await?;
+        connection.send_packet(ControlMessage::TransferAck(response).to_packet(None)).await?;
 
         connection.flush().await;
 
         return Ok(());
     }
 }
+
+impl TransferRequestCommand {
+    /// Signature of the existing file and the applier to rebuild the new version from it,
+    /// None when the file is missing or too small for a delta to pay off
+    async fn prepare_delta(&self, full_path: &str) -> Result<Option<(Signature, DeltaApplier)>, Box<dyn Error>> {
+        let Ok(metadata) = fs::metadata(full_path).await else {
+            return Ok(None);
+        };
+
+        if !metadata.is_file() || metadata.len() < DELTA_MIN_SIZE {
+            return Ok(None);
+        }
+
+        let path = full_path.to_string();
+        let signature = tokio::task::spawn_blocking(move || Signature::generate(&path)).await??;
+        let applier = DeltaApplier::open(full_path, signature.block_size).await?;
+
+        Ok(Some((signature, applier)))
+    }
+}
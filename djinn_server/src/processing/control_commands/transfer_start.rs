@@ -1,14 +1,14 @@
 use async_trait::async_trait;
-use djinn_core_lib::data::packets::{DataPacketGenerator, ControlPacketType};
-use djinn_core_lib::data::packets::{packet::Packet, ControlPacket};
+use djinn_core_lib::data::messages::{ControlMessage, TransferCancel, TransferRequest, TransferStart};
+use djinn_core_lib::data::delta::DeltaPacketGenerator;
+use djinn_core_lib::data::packets::{DataPacket, DataPacketGenerator, TransferCancelReason};
+use djinn_core_lib::data::syncing::FileVersion;
 use djinn_core_lib::jobs::{Job, JobStatus, JobType};
-use std::collections::HashMap;
 use std::error::Error;
 use std::sync::Arc;
-use tokio::io::{AsyncWriteExt};
 use tokio::sync::Mutex;
 
-use crate::{connectivity::Connection, CONFIG};
+use crate::connectivity::Connection;
 
 use super::ControlCommand;
 
@@ -16,14 +16,17 @@ pub struct TransferStartCommand {}
 
 #[async_trait]
 impl ControlCommand for TransferStartCommand {
+    type Message = TransferStart;
+
     async fn execute(
         &self,
         connection: &mut Connection,
-        packet: &ControlPacket,
+        _job_id: Option<u32>,
+        message: &TransferStart,
     ) -> Result<(), Box<dyn Error>> {
         // Get job
         debug!("Transfer start command received");
-        let arc_job_result = self.get_linked_job(connection, packet).await;
+        let arc_job_result = self.get_linked_job(connection, message.job_id).await;
 
         if arc_job_result.is_err() {
             return Ok(()); //TODO: Handle error
@@ -31,26 +34,28 @@ impl ControlCommand for TransferStartCommand {
 
         let unwrapped_arc_job = arc_job_result.unwrap();
         let job = unwrapped_arc_job.lock().await;
-        let file_path = job.params.get("file_path").unwrap().clone();
+        let file_path = TransferRequest::from_params(&job.params)?.file_path;
 
         // If the job is not in the pending state, return an error
         if !matches!(job.status, JobStatus::Pending) {
-            return Err(Box::new(std::io::Error::new(
-                std::io::ErrorKind::Other,
+            return Err(Box::new(std::io::Error::other(
                 "Job is not in the pending state",
             )));
         }
 
         drop(job);
 
-        // Transfer the file
-        let push_result = self.push_file(connection, unwrapped_arc_job).await;
+        // Send the file next to other transfers, so this connection keeps handling packets meanwhile
+        let mut transfer_connection = Connection::new(connection.uuid, connection.data.clone());
 
-        if push_result.is_err() {
-            return Ok(()); //TODO: Handle error
-        }
-
-        info!("server -> {}: {}", connection.uuid, file_path);
+        tokio::spawn(async move {
+            let command = TransferStartCommand {};
+            match command.push_file(&mut transfer_connection, unwrapped_arc_job).await {
+                Ok(true) => info!("server -> {}: {}", transfer_connection.uuid, file_path),
+                Ok(false) => {}
+                Err(error) => debug!("Failed to send {} to {}: {}", file_path, transfer_connection.uuid, error),
+            }
+        });
 
         return Ok(());
     }
@@ -60,17 +65,13 @@ impl TransferStartCommand {
     async fn get_linked_job(
         &self,
         connection: &mut Connection,
-        packet: &ControlPacket,
+        job_id: u32,
     ) -> Result<Arc<Mutex<Job>>, Box<dyn Error + Send + Sync>> {
-        // Get the file path from the packet
-        let job_id = packet.params.get("job_id").unwrap().parse::<u32>().unwrap();
-
         // Get the job from the connection
         let option_sync_job_arc: Option<Arc<Mutex<Job>>> = connection.get_job(job_id).await;
 
         if option_sync_job_arc.is_none() {
-            return Err(Box::new(std::io::Error::new(
-                std::io::ErrorKind::Other,
+            return Err(Box::new(std::io::Error::other(
                 "Job does not exist",
             )));
         }
@@ -80,8 +81,7 @@ impl TransferStartCommand {
 
         // If the job is not a transfer job, return an error
         if !matches!(job.job_type, JobType::Transfer) {
-            return Err(Box::new(std::io::Error::new(
-                std::io::ErrorKind::Other,
+            return Err(Box::new(std::io::Error::other(
                 "Job is not a transfer job",
             )));
         }
@@ -89,42 +89,95 @@ impl TransferStartCommand {
         Ok(job_arc.clone())
     }
 
+    /// Sends the file of a download. Returns false when the transfer was canceled by either side.
     async fn push_file(
         &self,
         connection: &mut Connection,
         arc_job: Arc<Mutex<Job>>,
-    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+    ) -> Result<bool, Box<dyn Error + Send + Sync>> {
         let mut job = arc_job.lock().await;
         let job_id = job.id;
         // Set the job status to running
         job.status = JobStatus::Running;
         // Get the file path from the job
-        let file_path = job.params.get("file_path").unwrap().clone();
-        let full_path = CONFIG.serving_directory.clone().unwrap() + "/" + &file_path;
+        let request = TransferRequest::from_params(&job.params)?;
 
         drop(job);
 
-        // Open da file
-        let packet_generator = DataPacketGenerator::new(job_id, full_path);
-        let iterator = packet_generator.iter();
-
-        // Get connection read_stream
-        let write_stream_arc = connection.get_write_stream().await;
-        let mut write_stream = write_stream_arc.lock().await;
+        // The client gets the new version with the next update if the file changes while it is sent
+        let full_path = match connection.sync_scope().await {
+            Some(scope) => scope.resolve(&request.file_path).await.ok(),
+            None => None,
+        };
+        let found = match full_path {
+            Some(full_path) => FileVersion::of(&full_path).await.ok().map(|version| (full_path, version)),
+            None => None,
+        };
+        let Some((full_path, version)) = found else {
+            info!("server -> {}: canceled {}, it is gone", connection.uuid, request.file_path);
+            self.cancel(connection, &arc_job, TransferCancelReason::SourceChanged).await?;
+            return Ok(false);
+        };
+
+        let compression = connection.compression().await;
+
+        // Wait for a free transfer slot of the connection
+        let stream = connection.get_sender().await.open_stream().await?;
+
+        // Open da file, only sending the changes if the client has a copy or the rest if it has a partial one
+        let iterator: Box<dyn Iterator<Item = DataPacket> + Send> = match request.signature {
+            Some(signature) => {
+                debug!("Sending {} as a delta", request.file_path);
+                Box::new(DeltaPacketGenerator::new(job_id, full_path.clone(), signature).with_compression(compression).iter())
+            }
+            None => Box::new(
+                DataPacketGenerator::new(job_id, full_path.clone())
+                    .with_offset(request.offset)
+                    .with_compression(compression)
+                    .iter(),
+            ),
+        };
 
         debug!("Sending file");
         for packet in iterator {
-            // Write packet
-            let buffer = &packet.to_buffer();
-            write_stream.write_all(buffer).await?;
+            // The client gave up on it
+            if matches!(arc_job.lock().await.status, JobStatus::Canceled) {
+                return Ok(false);
+            }
+
+            if version.has_changed(&full_path).await {
+                info!("server -> {}: canceled {}, it changed while sending", connection.uuid, request.file_path);
+                self.cancel(connection, &arc_job, TransferCancelReason::SourceChanged).await?;
+                return Ok(false);
+            }
+
+            stream.send(packet).await?;
         }
 
+        // Wait until the client has everything before calling it done
+        stream.finish().await?;
+
         // Set the job status to complete
         let mut job = arc_job.lock().await;
         job.status = JobStatus::Finished;
 
-        // Flush the write stream
-        write_stream.flush().await?;
+        Ok(true)
+    }
+
+    async fn cancel(
+        &self,
+        connection: &mut Connection,
+        arc_job: &Arc<Mutex<Job>>,
+        reason: TransferCancelReason,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let mut job = arc_job.lock().await;
+        job.status = JobStatus::Canceled;
+        let job_id = job.id;
+        drop(job);
+
+        let packet = ControlMessage::TransferCancel(TransferCancel { job_id, reason }).to_packet(None);
+        connection.send_packet(packet).await.map_err(|error| error.to_string())?;
+        connection.flush().await;
 
         Ok(())
     }
@@ -1,13 +1,14 @@
-use std::{hash::Hash, collections::HashMap};
+use std::collections::HashMap;
 
-use djinn_core_lib::{data::packets::{packet::{Packet}, PacketType, ControlPacketType, ControlPacket, DataPacket}, jobs::JobStatus};
+use djinn_core_lib::{data::{messages::{AuthenticateDeny, ControlMessage, TransferCancel, TransferDeny, TransferDirection, TransferRequest}, packets::{packet::{Packet}, PacketType, ControlPacket, DataPacket, TransferCancelReason, TransferDenyReason}, syncing::{check_within, join_path, IndexEntry, PartialTransfer}}, jobs::{Job, JobStatus}};
 use filetime::{FileTime, set_file_mtime};
-use tokio::fs::{File, rename};
+use tokio::fs::metadata;
 use tokio::io::AsyncWriteExt;
+use tokio::sync::MutexGuard;
 
-use crate::{connectivity::{Connection, ConnectionUpdate}, CONFIG, syncing::SourceOfTruth};
+use crate::{configuration::acl_rule::Access, connectivity::{Connection, ConnectionUpdate}, syncing::{unlock_upload, SourceOfTruth}, CONFIG};
 
-use super::control_commands::{EchoRequestCommand, ControlCommand, TransferRequestCommand, TransferStartCommand, SyncIndexUpdateCommand, SyncRequestCommand};
+use super::control_commands::{AuthenticateCommand, EchoRequestCommand, ControlCommand, TransferRequestCommand, TransferStartCommand, TransferCancelCommand, SyncIndexUpdateCommand, SyncRequestCommand};
 
 
 
@@ -34,105 +35,302 @@ impl PacketHandler {
     }
 
     pub async fn handle_control_packet(&self, packet: &ControlPacket, connection: &mut Connection) {
-        match packet.control_packet_type {
-            ControlPacketType::EchoRequest => {
+        let message = match ControlMessage::from_packet(packet) {
+            Ok(message) => message,
+            Err(error) => {
+                warn!("Ignoring malformed {:?} packet from {}: {}", packet.control_packet_type, connection.uuid, error);
+                return;
+            }
+        };
+        let job_id = packet.job_id;
+
+        // Nothing but authentication runs until the client said who it is
+        if !matches!(message, ControlMessage::Authenticate(_)) && connection.user().await.is_none() {
+            warn!("Refusing {:?} packet from {}, it did not authenticate", packet.control_packet_type, connection.uuid);
+            let response = ControlMessage::AuthenticateDeny(AuthenticateDeny {
+                reason: "Not authenticated".to_string(),
+            });
+            connection.send_packet(response.to_packet(job_id)).await.unwrap();
+            return;
+        }
+
+        // Commands fail on requests which raced with the state of their job, like a start after a cancel
+        let result = match message {
+            ControlMessage::Authenticate(message) => {
+                let command = AuthenticateCommand {
+                    users: CONFIG.users.as_ref().unwrap()
+                };
+                command.execute(connection, job_id, &message).await
+            },
+            ControlMessage::EchoRequest => {
                 let command = EchoRequestCommand {};
-                command.execute(connection, packet).await.unwrap();
+                command.execute(connection, job_id, &()).await
             },
-            ControlPacketType::TransferRequest => {
+            ControlMessage::TransferRequest(message) => {
                 let command = TransferRequestCommand {};
-                command.execute(connection, packet).await.unwrap();
+                command.execute(connection, job_id, &message).await
             },
-            ControlPacketType::TransferAck => {
+            ControlMessage::TransferAck(_) => {
                 //Transfer reverse for server -> client
+                Ok(())
             },
-            ControlPacketType::SyncIndexResponse => {
+            ControlMessage::SyncIndexResponse(message) => {
                 let command = SyncIndexUpdateCommand {
                     source_of_truth: SourceOfTruth::Server
                 };
-                command.execute(connection, packet).await.unwrap();
+                command.execute(connection, job_id, &message).await
             },
-            ControlPacketType::TransferStart => {
+            ControlMessage::TransferStart(message) => {
                 let command = TransferStartCommand {};
-                command.execute(connection, packet).await.unwrap();
+                command.execute(connection, job_id, &message).await
+            },
+            ControlMessage::TransferCancel(message) => {
+                let command = TransferCancelCommand {};
+                command.execute(connection, job_id, &message).await
             },
-            ControlPacketType::SyncRequest => {
+            ControlMessage::SyncRequest(message) => {
                 let command = SyncRequestCommand {};
-                command.execute(connection, packet).await.unwrap();
+                command.execute(connection, job_id, &message).await
             },
-            ControlPacketType::SyncIndexUpdate => {
+            ControlMessage::SyncIndexUpdate(message) => {
                 let command = SyncIndexUpdateCommand {
                     source_of_truth: SourceOfTruth::Client
                 };
-                command.execute(connection, packet).await.unwrap();
+                command.execute(connection, job_id, &message).await
             },
             _ => {
-                // Throw error
-                panic!("Unknown control packet type")
+                warn!("Ignoring unexpected {:?} packet from {}", packet.control_packet_type, connection.uuid);
+                Ok(())
             }
+        };
+
+        if let Err(error) = result {
+            warn!("Failed to handle {:?} packet from {}: {}", packet.control_packet_type, connection.uuid, error);
         }
     }
 
     pub async fn handle_data_packet(&self, packet: &DataPacket, connection: &mut Connection) {
         let job_id = packet.job_id;
 
-        let option_arc_job = connection.get_job(job_id).await;
+        let Some(user) = connection.user().await else {
+            warn!("Ignoring data packet from {}, it did not authenticate", connection.uuid);
+            return;
+        };
+        let Some(scope) = connection.sync_scope().await else {
+            warn!("Ignoring data packet from {}, it is not syncing", connection.uuid);
+            return;
+        };
+
+        // The job id comes from the client, it may name a job which never existed or already ended
+        let Some(arc_job) = connection.get_job(job_id).await else {
+            warn!("Ignoring data packet from {} for unknown job {}", connection.uuid, job_id);
+            return;
+        };
+        let mut job_guard = arc_job.lock().await;
+        let job = &mut *job_guard;
 
-        if option_arc_job.is_none() {
-            // Throw error
-            panic!("Job not found");
+        // If Job is still pending, create file
+        // The job params were built from the TransferRequest which opened this job, with the agreed offset
+        let request = match TransferRequest::from_params(&job.params) {
+            Ok(request) => request,
+            Err(error) => {
+                warn!("Ignoring data packet from {} for job {}, it is no transfer: {}", connection.uuid, job_id, error);
+                return;
+            }
+        };
+        // The path was checked when the transfer was requested
+        let root = scope.root();
+        let Ok(full_path) = join_path(&root, &request.file_path) else {
+            warn!("Ignoring data packet for {} from {}", request.file_path, connection.uuid);
+            return;
+        };
+        let partial = PartialTransfer::new(&full_path);
+
+        // Downloads are sent by the server, a client has no business writing to them
+        if request.direction != TransferDirection::ToServer {
+            warn!("Ignoring data packet for {} from {}, the transfer is no upload", request.file_path, connection.uuid);
+            return;
         }
 
-        let arc_job = option_arc_job.unwrap();
-        let mut job = arc_job.lock().await;
+        // The finished file gets the modification time of the request, a request without one can not be written
+        if matches!(job.status, JobStatus::Pending) && request.modified_time.is_none() {
+            warn!("Ignoring data packet for {} from {}, the transfer has no modification time", request.file_path, connection.uuid);
+            job.status = JobStatus::Canceled;
+            job.delta = None;
+            unlock_upload(&full_path, connection.uuid).await;
+            return;
+        }
 
-        // If Job is still pending, create file
-        if matches!(job.status, JobStatus::Pending) {
-            // Throw error
-            let file_path = job.params.get("file_path").unwrap();
-            let full_path = CONFIG.serving_directory.clone().unwrap() + "/" + file_path;
+        // The write access may have changed since the request was accepted
+        if matches!(job.status, JobStatus::Pending)
+            && (scope.access(&user, &request.file_path) != Access::ReadWrite
+                || check_within(&root, &full_path).await.is_err())
+        {
+            warn!("{} may not write {}, canceling transfer", connection.uuid, request.file_path);
+            job.status = JobStatus::Canceled;
+            job.delta = None;
 
-            // Open da file
-            job.open_file = Some(File::create(full_path + ".djinn_temp").await.unwrap());
+            unlock_upload(&full_path, connection.uuid).await;
 
-            job.status = JobStatus::Running;
+            let response = ControlMessage::TransferDeny(TransferDeny {
+                transfer_id: request.transfer_id,
+                reason: TransferDenyReason::PermissionDenied,
+            }).to_packet(None);
+            drop(job_guard);
+            if let Err(error) = connection.send_packet(response).await {
+                warn!("Failed to deny transfer of {} to {}: {}", request.file_path, connection.uuid, error);
+            }
+            connection.flush().await;
+            return;
+        }
+
+        if matches!(job.status, JobStatus::Pending) {
+            // Open da file, keeping what an interrupted upload already received
+            match partial.open(request.key.as_deref(), request.offset).await {
+                Ok((file, checksum)) => {
+                    job.open_file = Some(file);
+                    job.checksum = checksum;
+                    job.status = JobStatus::Running;
+                }
+                Err(error) => {
+                    warn!("Failed to open {}: {}", request.file_path, error);
+                    self.cancel_upload(connection, job_guard, &partial, &full_path).await;
+                    return;
+                }
+            }
         }
 
         // If Job is running, write to file
         if matches!(job.status, JobStatus::Running) {
-            let file = job.open_file.as_mut().unwrap();
+            // Running uploads always have their file open
+            let Some(file) = job.open_file.as_mut() else {
+                return;
+            };
+
+            // Delta chunks are ops, the checksum covers the file they rebuild
+            let verified = match job.delta.as_mut() {
+                Some(delta) if packet.has_data => {
+                    job.checksum.verify_chunk(packet)
+                        && delta
+                            .apply(&packet.data, file, &mut job.checksum)
+                            .await
+                            .is_ok()
+                }
+                Some(delta) => delta.is_complete() && job.checksum.verify(packet),
+                None => job.checksum.verify(packet),
+            };
+
+            // Cancel the job on a corrupted chunk or file, the rest of its packets are ignored
+            if !verified {
+                warn!("Checksum mismatch for {} from {}, discarding transfer", request.file_path, connection.uuid);
+                job.status = JobStatus::Canceled;
+                job.open_file = None;
+                job.delta = None;
+
+                if let Err(error) = partial.discard().await {
+                    warn!("Failed to discard partial upload of {}: {}", request.file_path, error);
+                }
+                unlock_upload(&full_path, connection.uuid).await;
+
+                let response = ControlMessage::TransferDeny(TransferDeny {
+                    transfer_id: request.transfer_id,
+                    reason: TransferDenyReason::ChecksumMismatch,
+                }).to_packet(None);
+                drop(job_guard);
+                if let Err(error) = connection.send_packet(response).await {
+                    warn!("Failed to deny transfer of {} to {}: {}", request.file_path, connection.uuid, error);
+                }
+                connection.flush().await;
+                return;
+            }
+
             if packet.has_data {
-                file.write_all(&packet.data).await.unwrap();
-            } else {
-                // Close file
-                file.flush().await.unwrap();
-                job.status = JobStatus::Finished;
-
-                // Rename file
-                let file_path = job.params.get("file_path").unwrap();
-                let full_path = CONFIG.serving_directory.clone().unwrap() + "/" + file_path;
-
-                rename(full_path.clone() + ".djinn_temp", &full_path)
-                    .await
-                    .unwrap();
-
-                // Set file mtime
-                let modified_time = job.params.get("modified_time").unwrap();
-                let modified_time = modified_time.parse::<u64>().unwrap();
-                let file_time = FileTime::from_unix_time(modified_time as i64, 0);
-                set_file_mtime(&full_path, file_time).unwrap();
-
-                // Send connection update to all connections
-                let data = connection.data.lock().await;
-                let sender = &data.connections_broadcast_sender.lock().await;
-
-                let mut update_data: HashMap<String, usize> = HashMap::new();
-                update_data.insert(file_path.clone(), modified_time as usize);
-                sender.send(ConnectionUpdate::new(data.uuid, update_data)).expect("Failed to send connection update");
-
-                // Log
-                info!("{} -> server: {}", connection.uuid, file_path);
+                if job.delta.is_none() {
+                    if let Err(error) = file.write_all(&packet.data).await {
+                        warn!("Failed to write {}: {}", request.file_path, error);
+                        self.cancel_upload(connection, job_guard, &partial, &full_path).await;
+                    }
+                }
+                return;
+            }
+
+            // Close file
+            if let Err(error) = file.flush().await {
+                warn!("Failed to write {}: {}", request.file_path, error);
+                self.cancel_upload(connection, job_guard, &partial, &full_path).await;
+                return;
+            }
+
+            // Rename file
+            let file_path = &request.file_path;
+            if let Err(error) = partial.finish(&full_path).await {
+                warn!("Failed to move {} into place: {}", file_path, error);
+                self.cancel_upload(connection, job_guard, &partial, &full_path).await;
+                return;
             }
+
+            // Set file mtime
+            // Checked when the job started
+            let modified_time = request.modified_time.unwrap_or_default();
+            let file_time = FileTime::from_unix_time(modified_time as i64, 0);
+            if let Err(error) = set_file_mtime(&full_path, file_time) {
+                warn!("Failed to set modification time of {}: {}", file_path, error);
+                self.cancel_upload(connection, job_guard, &partial, &full_path).await;
+                return;
+            }
+
+            job.status = JobStatus::Finished;
+            job.open_file = None;
+            job.delta = None;
+            unlock_upload(&full_path, connection.uuid).await;
+
+            // The end packet was verified, so the transfer checksum is the hash of the file
+            let size = match metadata(&full_path).await {
+                Ok(metadata) => metadata.len(),
+                Err(error) => {
+                    warn!("Failed to read {} after upload: {}", file_path, error);
+                    return;
+                }
+            };
+            let entry = IndexEntry::new(modified_time as usize, size, Some(job.checksum.finalize()));
+            drop(job_guard);
+
+            // Send connection update to all connections
+            let data = connection.data.lock().await;
+            let sender = &data.connections_broadcast_sender.lock().await;
+
+            let mut update_data: HashMap<String, IndexEntry> = HashMap::new();
+            update_data.insert(file_path.clone(), entry);
+            if sender.send(ConnectionUpdate::new(data.uuid, &scope, update_data)).is_err() {
+                warn!("No connection to tell about {}", file_path);
+            }
+
+            // Log
+            info!("{} -> server: {}", connection.uuid, file_path);
+        }
+    }
+
+    /// Gives up an upload the server could not write, the client keeps the file on its checklist for the next run
+    async fn cancel_upload(&self, connection: &mut Connection, mut job_guard: MutexGuard<'_, Job>, partial: &PartialTransfer, full_path: &str) {
+        job_guard.status = JobStatus::Canceled;
+        job_guard.open_file = None;
+        job_guard.delta = None;
+        let job_id = job_guard.id;
+        drop(job_guard);
+
+        if let Err(error) = partial.discard().await {
+            warn!("Failed to discard partial upload of {}: {}", full_path, error);
+        }
+        unlock_upload(full_path, connection.uuid).await;
+
+        // The client sees its upload dropped the same way as when the server's copy changed under it
+        let packet = ControlMessage::TransferCancel(TransferCancel {
+            job_id,
+            reason: TransferCancelReason::TargetChanged,
+        }).to_packet(None);
+        if let Err(error) = connection.send_packet(packet).await {
+            warn!("Failed to cancel job {} of {}: {}", job_id, connection.uuid, error);
         }
+        connection.flush().await;
     }
 }
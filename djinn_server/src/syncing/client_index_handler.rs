@@ -1,25 +1,27 @@
 use std::{
     collections::HashMap,
-    sync::{Arc}, time::{SystemTime, UNIX_EPOCH}, error::Error,
+    sync::{Arc}, error::Error,
 };
 
-use djinn_core_lib::{data::{packets::{ControlPacket, ControlPacketType}, syncing::IndexManager}, jobs::Job};
+use djinn_core_lib::{data::{messages::{ControlMessage, SyncRequest, SyncUpdate}, syncing::{conflict_file_name, normalize_path, resolve_path, IndexEntry, IndexManager, SyncAction, SyncPlan, SyncReason}}, jobs::Job};
 use tokio::{fs, sync::Mutex};
 
-use crate::{connectivity::{Connection, ConnectionUpdate}, CONFIG, SERVER_DELETES};
+use crate::{connectivity::{Connection, ConnectionUpdate}, HASH_CACHE};
 
-use super::{SourceOfTruth, IndexComparer};
+use super::{SourceOfTruth, IndexComparer, SyncScope, is_upload_locked, restrict_plan, tombstones_for, unix_now};
 
 pub struct ClientIndexHandler {
-    client_index: HashMap<String, usize>,
+    client_index: HashMap<String, IndexEntry>,
+    base_index: HashMap<String, IndexEntry>,
     arc_sync_job: Arc<Mutex<Job>>,
     source_of_truth: SourceOfTruth
 }
 
 impl ClientIndexHandler {
-    pub fn new(client_index: HashMap<String, usize>, arc_sync_job: Arc<Mutex<Job>>, source_of_truth: SourceOfTruth) -> Self {
+    pub fn new(client_index: HashMap<String, IndexEntry>, base_index: HashMap<String, IndexEntry>, arc_sync_job: Arc<Mutex<Job>>, source_of_truth: SourceOfTruth) -> Self {
         Self {
-            client_index,
+            client_index: normalize_index(client_index),
+            base_index: normalize_index(base_index),
             arc_sync_job,
             source_of_truth
         }
@@ -58,20 +60,44 @@ impl ClientIndexHandler {
     async fn generate_changes(
         &self,
         connection: &mut Connection,
-    ) -> Result<HashMap<String, String>, Box<dyn Error + Send + Sync>> {
+    ) -> Result<SyncPlan, Box<dyn Error + Send + Sync>> {
+        let user = connection.user().await.unwrap_or_default();
+
         // Get server index
         let sync_job = self.arc_sync_job.lock().await;
-        let path = sync_job.params.get("path").unwrap();
-        let full_path = CONFIG.serving_directory.clone().unwrap() + "/" + path;
+        let request = SyncRequest::from_params(&sync_job.params)?;
+        let scope = scope_of(&request)?;
+        let full_path = scope.resolve("/").await?;
 
-        let mut server_index_manager = IndexManager::new(full_path.clone());
+        let mut server_index_manager = IndexManager::with_hash_cache(full_path.clone(), HASH_CACHE.clone());
         server_index_manager.build().await;
 
         debug!("{} Server index: {:?}", connection.uuid, server_index_manager.index);
         debug!("{} Client index: {:?}", connection.uuid, self.client_index);
-        let server_deletes = SERVER_DELETES.lock().await;
-        let clone_server_deletes = server_deletes.clone();
-        drop(server_deletes);
+
+        // Tombstones are kept per share, so a delete is known to every client syncing a part of it
+        let mut tombstone_store = tombstones_for(&scope.share.path).await;
+        // A dry run must not change the tombstones real syncs read
+        if !request.dry_run {
+            let share_index = server_index_manager
+                .index
+                .iter()
+                .filter(|(path, _)| !path.starts_with('#'))
+                .map(|(path, entry)| (scope.to_share_path(path), entry.clone()))
+                .collect();
+
+            if tombstone_store.compact(&share_index, unix_now(), scope.tombstone_retention_days()) {
+                if let Err(error) = tombstone_store.save().await {
+                    warn!("Failed to save tombstones of share {}: {}", scope.share.name, error);
+                }
+            }
+        }
+        let clone_server_deletes: HashMap<String, usize> = tombstone_store
+            .tombstones()
+            .iter()
+            .filter_map(|(path, deleted_time)| Some((scope.rebase(path)?, *deleted_time)))
+            .collect();
+        drop(tombstone_store);
 
         debug!("{} Server deletes: {:?}", connection.uuid, clone_server_deletes.clone());
 
@@ -81,72 +107,169 @@ impl ClientIndexHandler {
             server_index_manager.index,
             self.source_of_truth,
             clone_server_deletes
-        );
+        ).with_base_index(self.base_index.clone());
 
-        let changes = index_comparer.compare();
+        // Only what the user may read is synced, and only what they may write is changed on the server
+        let changes = restrict_plan(index_comparer.compare(), |path| scope.access(&user, path));
 
-        debug!("{} Changes: {:?}", connection.uuid, changes);
+        debug!("{} Sync plan: {}", connection.uuid, changes);
 
         Ok(changes)
     }
 
     async fn process_changes(
         &self,
-        changes: &HashMap<String, String>,
+        changes: &SyncPlan,
         connection: &mut Connection
-    ) -> Result<HashMap<String, String>, Box<dyn Error + Send + Sync>> {
+    ) -> Result<SyncPlan, Box<dyn Error + Send + Sync>> {
         let mut changes_for_client = changes.clone();
         let sync_job = self.arc_sync_job.lock().await;
-        let path = sync_job.params.get("path").unwrap();
-        let full_path = CONFIG.serving_directory.clone().unwrap() + "/" + path;
+        let request = SyncRequest::from_params(&sync_job.params)?;
+        let scope = scope_of(&request)?;
+        let full_path = scope.resolve("/").await?;
+
+        // A dry run only reports the plan, the server side actions are left to the client to print
+        if request.dry_run {
+            info!("{} -> server: dry run, not applying {} changes", connection.uuid, changes.len());
+            return Ok(changes_for_client);
+        }
+
+        let mut deleted = vec![];
 
         // First proces self deletes
-        for (path, type_change) in changes.iter() {
-            if type_change == "SELF_DELETE" {
+        for (path, plan_entry) in changes.iter() {
+            if plan_entry.action == SyncAction::SelfDelete {
+                let full_file_path = match resolve_path(&full_path, path).await {
+                    Ok(full_file_path) => full_file_path,
+                    Err(error) => {
+                        warn!("{} Not deleting: {}", connection.uuid, error);
+                        changes_for_client.remove(path);
+                        continue;
+                    }
+                };
+
                 // Skip if file is transfering
-                if fs::metadata(path.clone() + ".djinn_temp").await.is_ok() {
+                if is_upload_locked(&full_file_path).await {
                     continue;
                 }
 
                 // Delete file
-                let mut full_file_path = full_path.clone() + "/" + path;
-                full_file_path = full_file_path.replace("//", "/");
-
                 info!("{} -> server: DEL {}", connection.uuid, path);
 
-                fs::remove_file(full_file_path).await.unwrap();
+                // Nothing is broadcast for a file the server still has
+                if let Err(error) = fs::remove_file(&full_file_path).await {
+                    warn!("{} Failed to delete {}: {}", connection.uuid, path, error);
+                    continue;
+                }
 
                 // Remove from index
                 changes_for_client.remove(path);
 
-                // Add to global deletes
-                let mut server_deletes = SERVER_DELETES.lock().await;
+                deleted.push(path.clone());
 
-                // Let get current unix timestamp
-                let current_time = SystemTime::now()
-                    .duration_since(UNIX_EPOCH)
-                    .expect("Failed to get system time");
+                // Broadcast delete to all clients
+                let data = connection.data.lock().await;
+                let sender = &data.connections_broadcast_sender.lock().await;
+                let mut update_data = HashMap::new();
+                update_data.insert(path.clone(), IndexEntry::deleted());
+                sender.send(ConnectionUpdate::new(data.uuid, &scope, update_data)).expect("Failed to send connection update");
+            } else if plan_entry.action == SyncAction::SelfMove {
+                // The client renamed the file, so the server renames its own copy instead of receiving it again
+                let from = plan_entry.from.clone().unwrap_or_default();
+                let (full_from_path, full_to_path) = match (resolve_path(&full_path, &from).await, resolve_path(&full_path, path).await) {
+                    (Ok(full_from_path), Ok(full_to_path)) => (full_from_path, full_to_path),
+                    (Err(error), _) | (_, Err(error)) => {
+                        warn!("{} Not moving: {}", connection.uuid, error);
+                        changes_for_client.remove(path);
+                        continue;
+                    }
+                };
+
+                info!("{} -> server: MOVE {} -> {}", connection.uuid, from, path);
+
+                if let Some(parent) = std::path::Path::new(&full_to_path).parent() {
+                    if let Err(error) = fs::create_dir_all(parent).await {
+                        warn!("{} Failed to create the directory of {}, uploading instead: {}", connection.uuid, path, error);
+                        changes_for_client.insert(path.clone(), SyncAction::Put, SyncReason::MissingOnServer, plan_entry.entry.clone());
+                        continue;
+                    }
+                }
 
-                let unix_timestamp = current_time.as_secs();
-                server_deletes.insert(path.clone(), unix_timestamp as usize);
+                if let Err(error) = fs::rename(&full_from_path, &full_to_path).await {
+                    warn!("{} Failed to move {} to {}, uploading instead: {}", connection.uuid, from, path, error);
+                    changes_for_client.insert(path.clone(), SyncAction::Put, SyncReason::MissingOnServer, plan_entry.entry.clone());
+                    continue;
+                }
+
+                changes_for_client.remove(path);
+
+                deleted.push(from.clone());
+
+                // Broadcast the move to all clients
+                let entry = plan_entry.entry.clone().unwrap_or_default();
+                let mut update_data = HashMap::new();
+                update_data.insert(from, entry.to_deleted());
+                update_data.insert(path.clone(), entry);
 
-                // Broadcast delete to all clients
                 let data = connection.data.lock().await;
                 let sender = &data.connections_broadcast_sender.lock().await;
+                sender.send(ConnectionUpdate::new(data.uuid, &scope, update_data)).expect("Failed to send connection update");
+            } else if plan_entry.action == SyncAction::SelfConflict {
+                // Keep the server version as a copy, the client uploads its own version in its place
+                let data = connection.data.lock().await;
+                let client_name = data.client_name.clone();
+                drop(data);
+
+                let copy_path = conflict_file_name(path, &client_name, unix_now() as u64);
+                let (full_file_path, full_copy_path) = match (resolve_path(&full_path, path).await, resolve_path(&full_path, &copy_path).await) {
+                    (Ok(full_file_path), Ok(full_copy_path)) => (full_file_path, full_copy_path),
+                    (Err(error), _) | (_, Err(error)) => {
+                        warn!("{} Not keeping a conflict copy: {}", connection.uuid, error);
+                        changes_for_client.remove(path);
+                        continue;
+                    }
+                };
+
+                info!("{} -> server: CONFLICT {}, server version kept as {}", connection.uuid, path, copy_path);
+
+                fs::rename(&full_file_path, &full_copy_path).await?;
+
+                let metadata = fs::metadata(&full_copy_path).await?;
+                let modified_time = metadata.modified()?.duration_since(std::time::UNIX_EPOCH)?.as_secs();
+                let copy_entry = IndexEntry::new(modified_time as usize, metadata.len(), None);
+
+                changes_for_client.insert(path.clone(), SyncAction::Put, SyncReason::ChangedOnBoth, plan_entry.entry.clone());
+                changes_for_client.insert(copy_path.clone(), SyncAction::Get, SyncReason::ConflictCopy, Some(copy_entry.clone()));
+
+                // Broadcast the copy to all clients
                 let mut update_data = HashMap::new();
-                update_data.insert(path.clone(), 0);
-                sender.send(ConnectionUpdate::new(data.uuid, update_data)).expect("Failed to send connection update");
+                update_data.insert(copy_path, copy_entry);
+
+                let data = connection.data.lock().await;
+                let sender = &data.connections_broadcast_sender.lock().await;
+                sender.send(ConnectionUpdate::new(data.uuid, &scope, update_data)).expect("Failed to send connection update");
             }
         }
 
+        // Remember the deletes, so stale clients can not bring the files back
+        if !deleted.is_empty() {
+            let mut tombstone_store = tombstones_for(&scope.share.path).await;
+            let now = unix_now();
+
+            for path in deleted {
+                tombstone_store.add(scope.to_share_path(&path), now);
+            }
+
+            tombstone_store.save().await?;
+        }
+
         Ok(changes_for_client)
     }
 
-    async fn send_sync_update(&self, connection: &mut Connection, changes: &HashMap<String, String>) -> Result<(), Box<dyn Error + Send + Sync>> {
+    async fn send_sync_update(&self, connection: &mut Connection, changes: &SyncPlan) -> Result<(), Box<dyn Error + Send + Sync>> {
         // Build packet
-        let mut response = ControlPacket::new(ControlPacketType::SyncUpdate, changes.clone());
         let sync_job = self.arc_sync_job.lock().await;
-        response.job_id = Some(sync_job.id);
+        let response = ControlMessage::SyncUpdate(SyncUpdate { plan: changes.clone() }).to_packet(Some(sync_job.id));
         // Send packet
         connection.send_packet(response).await.unwrap();
         connection.flush().await;
@@ -161,3 +284,47 @@ impl ClientIndexHandler {
         drop(data);
     }
 }
+
+/// Scope of a sync job, it was checked when the sync was requested
+fn scope_of(request: &SyncRequest) -> Result<SyncScope, Box<dyn Error + Send + Sync>> {
+    Ok(SyncScope::of(request).ok_or("Unknown share")??)
+}
+
+/// Keys of a client index in the form of the server index, like `/docs/a.txt`.
+/// Paths which could lead outside the synced directory are left out of the plan.
+fn normalize_index(index: HashMap<String, IndexEntry>) -> HashMap<String, IndexEntry> {
+    index
+        .into_iter()
+        .filter_map(|(path, entry)| {
+            if path.starts_with('#') {
+                return Some((path, entry));
+            }
+
+            match normalize_path(&path) {
+                Ok(normalized) => Some((normalized, entry)),
+                Err(error) => {
+                    warn!("Ignoring unsafe path in a client index: {}", error);
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize_index() {
+        let entry = IndexEntry::new(1680000000, 12, None);
+        let index: HashMap<String, IndexEntry> = ["docs//a.txt", "/docs/./b.txt", "/../c.txt", "#timestamp"]
+            .into_iter()
+            .map(|path| (path.to_string(), entry.clone()))
+            .collect();
+
+        let mut paths: Vec<String> = normalize_index(index).into_keys().collect();
+        paths.sort();
+        assert_eq!(paths, vec!["#timestamp", "/docs/a.txt", "/docs/b.txt"]);
+    }
+}
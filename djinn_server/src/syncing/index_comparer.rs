@@ -1,5 +1,7 @@
 use std::collections::HashMap;
 
+use djinn_core_lib::data::syncing::{IndexEntry, SyncAction, SyncPlan, SyncReason};
+
 #[derive(Copy, Clone)]
 pub enum SourceOfTruth {
     Client,
@@ -7,16 +9,18 @@ pub enum SourceOfTruth {
 }
 
 pub struct IndexComparer {
-    pub client_index: HashMap<String, usize>,
-    pub server_index: HashMap<String, usize>,
+    pub client_index: HashMap<String, IndexEntry>,
+    pub server_index: HashMap<String, IndexEntry>,
     pub source_of_truth: SourceOfTruth,
     pub server_deleted: HashMap<String, usize>,
+    /// Last synced version of files the client changed since
+    pub base_index: HashMap<String, IndexEntry>,
 }
 
 impl IndexComparer {
     pub fn new(
-        client_index: HashMap<String, usize>,
-        server_index: HashMap<String, usize>,
+        client_index: HashMap<String, IndexEntry>,
+        server_index: HashMap<String, IndexEntry>,
         source_of_truth: SourceOfTruth,
         server_deleted: HashMap<String, usize>,
     ) -> Self {
@@ -25,98 +29,140 @@ impl IndexComparer {
             server_index,
             source_of_truth,
             server_deleted,
+            base_index: HashMap::new(),
         }
     }
 
-    pub fn compare(&self) -> HashMap<String, String> {
-        let mut result = HashMap::new();
+    pub fn with_base_index(mut self, base_index: HashMap<String, IndexEntry>) -> Self {
+        self.base_index = base_index;
+        self
+    }
+
+    pub fn compare(&self) -> SyncPlan {
+        let mut result = SyncPlan::new();
 
         // Get timestamps
         let some_server_timestamp = self.server_index.get("#timestamp");
-        let _server_timestamp = if some_server_timestamp.is_some() {
-            some_server_timestamp.unwrap()
-        } else {
-            &0
-        };
+        let _server_timestamp = some_server_timestamp.map_or(0, |entry| entry.modified_time);
 
         let some_client_timestamp = self.client_index.get("#timestamp");
-        let client_timestamp = if some_client_timestamp.is_some() {
-            some_client_timestamp.unwrap()
-        } else {
-            &0
-        };
-
-        //PATH -> METHOD
+        let client_timestamp = some_client_timestamp.map_or(0, |entry| entry.modified_time);
 
         //Changes from client perspective
-        for (key, timestamp) in &self.client_index {
+        for (key, entry) in &self.client_index {
             debug!("Checking key from client: {}", key);
-            if &key[..1] == "#" {
+            if key.starts_with('#') {
                 continue;
             }
+            let timestamp = &entry.modified_time;
             //Check if key exists in server index (file exists on server)
-            if self.server_index.contains_key(key) {
-                //Check if timestamp is the same
-                if self.server_index.get(key).unwrap() == timestamp {
-                    //File is the same
-                    continue;
-                } else if timestamp == &0 {
+            if let Some(server_entry) = self.server_index.get(key) {
+                if entry.is_deleted() {
                     // Client requests delete
                     if matches!(self.source_of_truth, SourceOfTruth::Client)
-                        && client_timestamp > self.server_index.get(key).unwrap()
+                        && client_timestamp > server_entry.modified_time
                     {
                         //File delete
-                        result.insert(key.to_string(), "SELF_DELETE".to_string());
+                        result.insert(key.to_string(), SyncAction::SelfDelete, SyncReason::DeletedOnClient, Some(server_entry.clone()));
+                    } else if entry.hash.is_some() && entry.hash == server_entry.hash {
+                        //Deleted while offline and not changed on server since
+                        result.insert(key.to_string(), SyncAction::SelfDelete, SyncReason::DeletedOnClient, Some(server_entry.clone()));
                     } else {
                         //File does not exist on client
-                        result.insert(key.to_string(), "GET".to_string());
+                        result.insert(key.to_string(), SyncAction::Get, SyncReason::MissingOnClient, Some(server_entry.clone()));
                     }
-                } else if self.server_index.get(key).unwrap() > timestamp {
-                    //Server has newer version
-                    result.insert(key.to_string(), "GET".to_string());
+                } else if server_entry.is_same_as(entry) {
+                    //File is the same
+                    continue;
                 } else {
-                    //Client has newer version
-                    result.insert(key.to_string(), "PUT".to_string());
+                    match self.changed_since_base(key, entry, server_entry) {
+                        Some((true, true)) => {
+                            // Both sides changed, the newest version wins and the other one is kept as a copy
+                            if &server_entry.modified_time >= timestamp {
+                                result.insert(key.to_string(), SyncAction::Conflict, SyncReason::ChangedOnBoth, Some(server_entry.clone()));
+                            } else {
+                                result.insert(key.to_string(), SyncAction::SelfConflict, SyncReason::ChangedOnBoth, Some(entry.clone()));
+                            }
+                        }
+                        Some((true, false)) => {
+                            //Only client changed
+                            result.insert(key.to_string(), SyncAction::Put, SyncReason::ChangedOnClient, Some(entry.clone()));
+                        }
+                        Some((false, true)) => {
+                            //Only server changed
+                            result.insert(key.to_string(), SyncAction::Get, SyncReason::ChangedOnServer, Some(server_entry.clone()));
+                        }
+                        _ => {
+                            if &server_entry.modified_time > timestamp {
+                                //Server has newer version
+                                result.insert(key.to_string(), SyncAction::Get, SyncReason::ChangedOnServer, Some(server_entry.clone()));
+                            } else {
+                                //Client has newer version
+                                result.insert(key.to_string(), SyncAction::Put, SyncReason::ChangedOnClient, Some(entry.clone()));
+                            }
+                        }
+                    }
                 }
-            } else if timestamp != &0 {
+            } else if !entry.is_deleted() {
                 debug!("Key does not exist on server and timestamp is not 0)");
                 let possible_deleted_timestamp = self.server_deleted.get(key);
                 if matches!(self.source_of_truth, SourceOfTruth::Client)
-                    // && (possible_deleted_timestamp.is_none()
-                    //     || possible_deleted_timestamp.unwrap() < timestamp)
+                    && possible_deleted_timestamp.is_none_or(|deleted_timestamp| deleted_timestamp < timestamp)
                 {
                     //File does not exist on server
-                    result.insert(key.to_string(), "PUT".to_string());
+                    result.insert(key.to_string(), SyncAction::Put, SyncReason::MissingOnServer, Some(entry.clone()));
+                } else if possible_deleted_timestamp.is_some() {
+                    //File was deleted on server
+                    result.insert(key.to_string(), SyncAction::Delete, SyncReason::DeletedOnServer, Some(entry.clone()));
                 } else {
-                    //File delete
-                    result.insert(key.to_string(), "DELETE".to_string());
+                    //Server is the source of truth and does not have the file
+                    result.insert(key.to_string(), SyncAction::Delete, SyncReason::MissingOnServer, Some(entry.clone()));
                 }
             }
         }
 
         //Check for files server has that client does not
-        for (key, _) in &self.server_index {
-            if &key[..1] == "#" {
+        for (key, server_entry) in &self.server_index {
+            if key.starts_with('#') {
                 continue;
             }
             if !self.client_index.contains_key(key) {
                 //File does not exist on client
-                result.insert(key.to_string(), "GET".to_string());
+                result.insert(key.to_string(), SyncAction::Get, SyncReason::MissingOnClient, Some(server_entry.clone()));
             }
         }
 
+        // Renamed files only have to be moved on the other side. A file renamed while offline would
+        // otherwise be removed on both sides when the server is the source of truth.
+        result.pair_moves(SyncAction::SelfDelete, SyncAction::Put, SyncAction::SelfMove, SyncReason::MovedOnClient);
+        result.pair_moves(SyncAction::SelfDelete, SyncAction::Delete, SyncAction::SelfMove, SyncReason::MovedOnClient);
+        result.pair_moves(SyncAction::Delete, SyncAction::Get, SyncAction::Move, SyncReason::MovedOnServer);
+
         result
     }
+
+    /// Whether the client and the server changed the file since the last sync, if the base version is known
+    fn changed_since_base(&self, key: &str, client_entry: &IndexEntry, server_entry: &IndexEntry) -> Option<(bool, bool)> {
+        let base = self.base_index.get(key)?;
+
+        Some((!client_entry.is_same_as(base), !server_entry.is_same_as(base)))
+    }
 }
 
 #[cfg(test)]
 mod tests {
+    use djinn_core_lib::data::packets::CHECKSUM_SIZE;
+
     use super::*;
 
+    fn entry(modified_time: usize) -> IndexEntry {
+        IndexEntry::new(modified_time, 0, None)
+    }
+
     #[test]
     fn test_client_add() {
         let mut client_index = HashMap::new();
-        client_index.insert("test.txt".to_string(), 123);
+        client_index.insert("test.txt".to_string(), entry(123));
 
         let server_index = HashMap::new();
 
@@ -130,18 +176,35 @@ mod tests {
         );
         let result = comparer.compare();
 
-        assert_eq!(result.get("test.txt").unwrap(), "PUT");
+        assert_eq!(result.get("test.txt").unwrap().action, SyncAction::Put);
+    }
+
+    #[test]
+    fn test_odd_client_keys() {
+        let mut client_index = HashMap::new();
+        client_index.insert("".to_string(), entry(123));
+        client_index.insert("é.txt".to_string(), entry(123));
+
+        let comparer = IndexComparer::new(
+            client_index,
+            HashMap::new(),
+            SourceOfTruth::Client,
+            HashMap::new(),
+        );
+        let result = comparer.compare();
+
+        assert_eq!(result.get("é.txt").unwrap().action, SyncAction::Put);
     }
 
     #[test]
     fn test_client_delete() {
         let mut client_index = HashMap::new();
-        client_index.insert("test.txt".to_string(), 0);
-        client_index.insert("#timestamp".to_string(), 123);
+        client_index.insert("test.txt".to_string(), entry(0));
+        client_index.insert("#timestamp".to_string(), entry(123));
 
         let mut server_index = HashMap::new();
-        server_index.insert("test.txt".to_string(), 120);
-        server_index.insert("#timestamp".to_string(), 122);
+        server_index.insert("test.txt".to_string(), entry(120));
+        server_index.insert("#timestamp".to_string(), entry(122));
 
         let server_deleted = HashMap::new();
 
@@ -153,16 +216,16 @@ mod tests {
         );
         let result = comparer.compare();
 
-        assert_eq!(result.get("test.txt").unwrap(), "SELF_DELETE");
+        assert_eq!(result.get("test.txt").unwrap().action, SyncAction::SelfDelete);
     }
 
     #[test]
     fn test_server_add() {
         let mut client_index = HashMap::new();
-        client_index.insert("#timestamp".to_string(), 123);
+        client_index.insert("#timestamp".to_string(), entry(123));
 
         let mut server_index = HashMap::new();
-        server_index.insert("test.txt".to_string(), 123);
+        server_index.insert("test.txt".to_string(), entry(123));
 
         let server_deleted = HashMap::new();
 
@@ -174,13 +237,13 @@ mod tests {
         );
         let result = comparer.compare();
 
-        assert_eq!(result.get("test.txt").unwrap(), "GET");
+        assert_eq!(result.get("test.txt").unwrap().action, SyncAction::Get);
     }
 
     #[test]
     fn test_server_delete() {
         let mut client_index = HashMap::new();
-        client_index.insert("test.txt".to_string(), 123);
+        client_index.insert("test.txt".to_string(), entry(123));
 
         let server_index = HashMap::new();
 
@@ -194,16 +257,16 @@ mod tests {
         );
         let result = comparer.compare();
 
-        assert_eq!(result.get("test.txt").unwrap(), "DELETE");
+        assert_eq!(result.get("test.txt").unwrap().action, SyncAction::Delete);
     }
 
     #[test]
     fn test_server_update() {
         let mut client_index = HashMap::new();
-        client_index.insert("test.txt".to_string(), 123);
+        client_index.insert("test.txt".to_string(), entry(123));
 
         let mut server_index = HashMap::new();
-        server_index.insert("test.txt".to_string(), 124);
+        server_index.insert("test.txt".to_string(), entry(124));
 
         let server_deleted = HashMap::new();
 
@@ -215,16 +278,16 @@ mod tests {
         );
         let result = comparer.compare();
 
-        assert_eq!(result.get("test.txt").unwrap(), "GET");
+        assert_eq!(result.get("test.txt").unwrap().action, SyncAction::Get);
     }
 
     #[test]
     fn test_client_update() {
         let mut client_index = HashMap::new();
-        client_index.insert("test.txt".to_string(), 123);
+        client_index.insert("test.txt".to_string(), entry(123));
 
         let mut server_index = HashMap::new();
-        server_index.insert("test.txt".to_string(), 124);
+        server_index.insert("test.txt".to_string(), entry(124));
 
         let server_deleted = HashMap::new();
 
@@ -236,16 +299,16 @@ mod tests {
         );
         let result = comparer.compare();
 
-        assert_eq!(result.get("test.txt").unwrap(), "GET");
+        assert_eq!(result.get("test.txt").unwrap().action, SyncAction::Get);
     }
 
     #[test]
     fn test_client_same() {
         let mut client_index = HashMap::new();
-        client_index.insert("test.txt".to_string(), 123);
+        client_index.insert("test.txt".to_string(), entry(123));
 
         let mut server_index = HashMap::new();
-        server_index.insert("test.txt".to_string(), 123);
+        server_index.insert("test.txt".to_string(), entry(123));
 
         let server_deleted = HashMap::new();
 
@@ -264,11 +327,11 @@ mod tests {
     #[test]
     fn test_if_extra_info_gets_ignored() {
         let mut client_index = HashMap::new();
-        client_index.insert("test.txt".to_string(), 123);
-        client_index.insert("#test.txt".to_string(), 123);
+        client_index.insert("test.txt".to_string(), entry(123));
+        client_index.insert("#test.txt".to_string(), entry(123));
 
         let mut server_index = HashMap::new();
-        server_index.insert("test.txt".to_string(), 124);
+        server_index.insert("test.txt".to_string(), entry(124));
 
         let server_deleted = HashMap::new();
 
@@ -280,7 +343,7 @@ mod tests {
         );
         let result = comparer.compare();
 
-        assert_eq!(result.get("test.txt").unwrap(), "GET");
+        assert_eq!(result.get("test.txt").unwrap().action, SyncAction::Get);
         assert_eq!(result.len(), 1);
     }
 
@@ -290,15 +353,169 @@ mod tests {
     */
     fn test_out_of_sync_client_put_after_delete() {
         let mut client_index = HashMap::new();
-        client_index.insert("test.txt".to_string(), 123);
-        client_index.insert("#timestamp".to_string(), 123);
+        client_index.insert("test.txt".to_string(), entry(123));
+        client_index.insert("#timestamp".to_string(), entry(123));
+
+        let mut server_index = HashMap::new();
+        server_index.insert("#timestamp".to_string(), entry(120));
+
+        let mut server_deleted = HashMap::new();
+        server_deleted.insert("test.txt".to_string(), 124);
+
+        let comparer = IndexComparer::new(
+            client_index,
+            server_index,
+            SourceOfTruth::Client,
+            server_deleted,
+        );
+        let result = comparer.compare();
+
+        assert_eq!(result.get("test.txt").unwrap().action, SyncAction::Delete);
+        assert_eq!(result.get("test.txt").unwrap().reason, SyncReason::DeletedOnServer);
+    }
+
+    #[test]
+    fn test_touched_file_is_unchanged() {
+        let mut client_index = HashMap::new();
+        client_index.insert("test.txt".to_string(), IndexEntry::new(130, 4, Some([1; CHECKSUM_SIZE])));
+
+        let mut server_index = HashMap::new();
+        server_index.insert("test.txt".to_string(), IndexEntry::new(120, 4, Some([1; CHECKSUM_SIZE])));
+
+        let comparer = IndexComparer::new(
+            client_index,
+            server_index,
+            SourceOfTruth::Client,
+            HashMap::new(),
+        );
+        let result = comparer.compare();
+
+        assert_eq!(result.len(), 0);
+    }
+
+    #[test]
+    fn test_edit_within_same_second() {
+        let mut client_index = HashMap::new();
+        client_index.insert("test.txt".to_string(), IndexEntry::new(123, 4, Some([2; CHECKSUM_SIZE])));
+
+        let mut server_index = HashMap::new();
+        server_index.insert("test.txt".to_string(), IndexEntry::new(123, 4, Some([1; CHECKSUM_SIZE])));
+
+        let comparer = IndexComparer::new(
+            client_index,
+            server_index,
+            SourceOfTruth::Client,
+            HashMap::new(),
+        );
+        let result = comparer.compare();
+
+        assert_eq!(result.get("test.txt").unwrap().action, SyncAction::Put);
+    }
+
+    #[test]
+    fn test_client_delete_while_offline() {
+        let hash = Some([1; CHECKSUM_SIZE]);
+
+        let mut client_index = HashMap::new();
+        client_index.insert("unchanged.txt".to_string(), IndexEntry::new(0, 4, hash));
+        client_index.insert("changed.txt".to_string(), IndexEntry::new(0, 4, hash));
+        client_index.insert("#timestamp".to_string(), entry(100));
 
         let mut server_index = HashMap::new();
-        server_index.insert("#timestamp".to_string(), 120);
+        server_index.insert("unchanged.txt".to_string(), IndexEntry::new(120, 4, hash));
+        server_index.insert("changed.txt".to_string(), IndexEntry::new(120, 4, Some([2; CHECKSUM_SIZE])));
+
+        let comparer = IndexComparer::new(
+            client_index,
+            server_index,
+            SourceOfTruth::Server,
+            HashMap::new(),
+        );
+        let result = comparer.compare();
+
+        assert_eq!(result.get("unchanged.txt").unwrap().action, SyncAction::SelfDelete);
+        assert_eq!(result.get("changed.txt").unwrap().action, SyncAction::Get);
+    }
+
+    #[test]
+    fn test_client_put_after_delete() {
+        let mut client_index = HashMap::new();
+        client_index.insert("test.txt".to_string(), entry(125));
 
         let mut server_deleted = HashMap::new();
         server_deleted.insert("test.txt".to_string(), 124);
 
+        let comparer = IndexComparer::new(
+            client_index,
+            HashMap::new(),
+            SourceOfTruth::Client,
+            server_deleted,
+        );
+        let result = comparer.compare();
+
+        assert_eq!(result.get("test.txt").unwrap().action, SyncAction::Put);
+    }
+
+    #[test]
+    fn test_conflict() {
+        let base = IndexEntry::new(100, 4, Some([1; CHECKSUM_SIZE]));
+
+        let mut client_index = HashMap::new();
+        client_index.insert("client_newer.txt".to_string(), IndexEntry::new(130, 4, Some([2; CHECKSUM_SIZE])));
+        client_index.insert("server_newer.txt".to_string(), IndexEntry::new(110, 4, Some([2; CHECKSUM_SIZE])));
+        client_index.insert("client_only.txt".to_string(), IndexEntry::new(110, 4, Some([2; CHECKSUM_SIZE])));
+
+        let mut server_index = HashMap::new();
+        server_index.insert("client_newer.txt".to_string(), IndexEntry::new(120, 4, Some([3; CHECKSUM_SIZE])));
+        server_index.insert("server_newer.txt".to_string(), IndexEntry::new(120, 4, Some([3; CHECKSUM_SIZE])));
+        // Server still has the base version, even though its mtime is newer
+        server_index.insert("client_only.txt".to_string(), IndexEntry::new(120, 4, Some([1; CHECKSUM_SIZE])));
+
+        let mut base_index = HashMap::new();
+        base_index.insert("client_newer.txt".to_string(), base.clone());
+        base_index.insert("server_newer.txt".to_string(), base.clone());
+        base_index.insert("client_only.txt".to_string(), base);
+
+        let comparer = IndexComparer::new(
+            client_index,
+            server_index,
+            SourceOfTruth::Client,
+            HashMap::new(),
+        ).with_base_index(base_index);
+        let result = comparer.compare();
+
+        assert_eq!(result.get("client_newer.txt").unwrap().action, SyncAction::SelfConflict);
+        assert_eq!(result.get("server_newer.txt").unwrap().action, SyncAction::Conflict);
+        assert_eq!(result.get("client_only.txt").unwrap().action, SyncAction::Put);
+        assert_eq!(result.get("client_only.txt").unwrap().reason, SyncReason::ChangedOnClient);
+
+        // The plan carries the version that wins
+        let server_newer = result.get("server_newer.txt").unwrap();
+        assert_eq!(server_newer.reason, SyncReason::ChangedOnBoth);
+        assert_eq!(server_newer.entry, Some(IndexEntry::new(120, 4, Some([3; CHECKSUM_SIZE]))));
+    }
+
+    #[test]
+    fn test_move() {
+        let moved = IndexEntry::new(100, 4, Some([1; CHECKSUM_SIZE]));
+        let server_moved = IndexEntry::new(100, 4, Some([2; CHECKSUM_SIZE]));
+
+        let mut client_index = HashMap::new();
+        // Moved on the client
+        client_index.insert("old/a.txt".to_string(), moved.to_deleted());
+        client_index.insert("new/a.txt".to_string(), moved.clone());
+        // Moved on the server
+        client_index.insert("old/b.txt".to_string(), server_moved.clone());
+        client_index.insert("#timestamp".to_string(), entry(130));
+
+        let mut server_index = HashMap::new();
+        server_index.insert("old/a.txt".to_string(), moved);
+        server_index.insert("new/b.txt".to_string(), server_moved);
+        server_index.insert("#timestamp".to_string(), entry(120));
+
+        let mut server_deleted = HashMap::new();
+        server_deleted.insert("old/b.txt".to_string(), 125);
+
         let comparer = IndexComparer::new(
             client_index,
             server_index,
@@ -307,6 +524,39 @@ mod tests {
         );
         let result = comparer.compare();
 
-        assert_eq!(result.get("test.txt").unwrap(), "DELETE");
+        let client_move = result.get("new/a.txt").unwrap();
+        assert_eq!(client_move.action, SyncAction::SelfMove);
+        assert_eq!(client_move.from.as_deref(), Some("old/a.txt"));
+
+        let server_move = result.get("new/b.txt").unwrap();
+        assert_eq!(server_move.action, SyncAction::Move);
+        assert_eq!(server_move.from.as_deref(), Some("old/b.txt"));
+
+        assert_eq!(result.len(), 2);
+    }
+
+    #[test]
+    fn test_move_while_offline() {
+        let moved = IndexEntry::new(100, 4, Some([1; CHECKSUM_SIZE]));
+
+        let mut client_index = HashMap::new();
+        client_index.insert("old/a.txt".to_string(), moved.to_deleted());
+        client_index.insert("new/a.txt".to_string(), moved.clone());
+
+        let mut server_index = HashMap::new();
+        server_index.insert("old/a.txt".to_string(), moved);
+
+        let comparer = IndexComparer::new(
+            client_index,
+            server_index,
+            SourceOfTruth::Server,
+            HashMap::new(),
+        );
+        let result = comparer.compare();
+
+        let client_move = result.get("new/a.txt").unwrap();
+        assert_eq!(client_move.action, SyncAction::SelfMove);
+        assert_eq!(client_move.from.as_deref(), Some("old/a.txt"));
+        assert_eq!(result.len(), 1);
     }
 }
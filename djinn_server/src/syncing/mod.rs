@@ -1,5 +1,17 @@
 mod index_comparer;
 pub use index_comparer::IndexComparer;
 pub use index_comparer::SourceOfTruth;
+mod tombstone_store;
+pub use tombstone_store::tombstones_for;
+pub use tombstone_store::unix_now;
+mod upload_locks;
+pub use upload_locks::is_upload_locked;
+pub use upload_locks::lock_upload;
+pub use upload_locks::unlock_upload;
+pub use upload_locks::unlock_uploads_of;
 mod client_index_handler;
 pub use client_index_handler::ClientIndexHandler;
+mod plan_access;
+pub use plan_access::restrict_plan;
+mod sync_scope;
+pub use sync_scope::SyncScope;
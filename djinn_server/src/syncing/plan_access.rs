@@ -0,0 +1,116 @@
+use djinn_core_lib::data::syncing::{SyncAction, SyncPlan, SyncReason};
+
+use crate::configuration::acl_rule::Access;
+
+/// Cuts a sync plan down to what the user may do with its paths. Paths they can not read are left out.
+/// Without write access the server keeps its own version, so a read-only client can never delete,
+/// move or replace anything on the server.
+pub fn restrict_plan(plan: SyncPlan, access_to: impl Fn(&str) -> Access) -> SyncPlan {
+    let mut restricted = SyncPlan::new();
+
+    for (path, plan_entry) in plan.entries {
+        let path_access = access_to(&path);
+        if path_access == Access::None {
+            continue;
+        }
+
+        let writable = path_access == Access::ReadWrite;
+        match plan_entry.action {
+            // The client deleted its copy, it gets the server version back
+            SyncAction::SelfDelete if !writable => {
+                restricted.insert(path, SyncAction::Get, SyncReason::MissingOnClient, plan_entry.entry);
+            }
+            // The client renamed its copy, it gets the old name back and drops the new one
+            SyncAction::SelfMove
+                if !writable || plan_entry.from.as_deref().is_some_and(|from| access_to(from) != Access::ReadWrite) =>
+            {
+                let from = plan_entry.from.unwrap_or_default();
+                if access_to(&from) != Access::None {
+                    restricted.insert(from, SyncAction::Get, SyncReason::MissingOnClient, plan_entry.entry.clone());
+                }
+                restricted.insert(path, SyncAction::Delete, SyncReason::DeletedOnServer, plan_entry.entry);
+            }
+            // Both changed, the client keeps its version as a local copy
+            SyncAction::SelfConflict if !writable => {
+                restricted.insert(path, SyncAction::Conflict, SyncReason::ChangedOnBoth, plan_entry.entry);
+            }
+            // Local changes stay on the client
+            SyncAction::Put if !writable => {}
+            _ => {
+                restricted.entries.insert(path, plan_entry);
+            }
+        }
+    }
+
+    restricted
+}
+
+#[cfg(test)]
+mod tests {
+    use djinn_core_lib::data::syncing::IndexEntry;
+
+    use super::*;
+
+    fn writes_to_server(plan_entry: &djinn_core_lib::data::syncing::SyncPlanEntry) -> bool {
+        matches!(
+            plan_entry.action,
+            SyncAction::Put | SyncAction::SelfDelete | SyncAction::SelfMove | SyncAction::SelfConflict
+        )
+    }
+
+    fn access(path: &str) -> Access {
+        if path.starts_with("private/") {
+            Access::None
+        } else if path.starts_with("shared/") {
+            Access::ReadWrite
+        } else {
+            Access::ReadOnly
+        }
+    }
+
+    fn plan() -> SyncPlan {
+        let entry = Some(IndexEntry::new(1680000000, 12, None));
+        let mut plan = SyncPlan::new();
+        plan.insert("a.txt".to_string(), SyncAction::SelfDelete, SyncReason::DeletedOnClient, entry.clone());
+        plan.insert("b.txt".to_string(), SyncAction::Put, SyncReason::ChangedOnClient, entry.clone());
+        plan.insert("c.txt".to_string(), SyncAction::SelfConflict, SyncReason::ChangedOnBoth, entry.clone());
+        plan.insert("d.txt".to_string(), SyncAction::SelfMove, SyncReason::MovedOnClient, entry.clone());
+        plan.entries.get_mut("d.txt").unwrap().from = Some("old/d.txt".to_string());
+        plan.insert("e.txt".to_string(), SyncAction::Get, SyncReason::MissingOnClient, entry.clone());
+        plan.insert("private/f.txt".to_string(), SyncAction::Get, SyncReason::MissingOnClient, entry.clone());
+        plan.insert("shared/g.txt".to_string(), SyncAction::SelfDelete, SyncReason::DeletedOnClient, entry.clone());
+        plan.insert("shared/h.txt".to_string(), SyncAction::Put, SyncReason::ChangedOnClient, entry);
+        plan
+    }
+
+    #[test]
+    fn test_read_only_never_writes() {
+        let restricted = restrict_plan(plan(), access);
+
+        assert_eq!(restricted.get("a.txt").unwrap().action, SyncAction::Get);
+        assert!(restricted.get("b.txt").is_none());
+        assert_eq!(restricted.get("c.txt").unwrap().action, SyncAction::Conflict);
+        assert_eq!(restricted.get("d.txt").unwrap().action, SyncAction::Delete);
+        assert_eq!(restricted.get("old/d.txt").unwrap().action, SyncAction::Get);
+        assert_eq!(restricted.get("e.txt").unwrap().action, SyncAction::Get);
+
+        for (path, plan_entry) in restricted.iter() {
+            assert!(!writes_to_server(plan_entry) || path.starts_with("shared/"), "{} writes", path);
+        }
+    }
+
+    #[test]
+    fn test_unreadable_paths_are_left_out() {
+        let restricted = restrict_plan(plan(), access);
+
+        assert!(restricted.get("private/f.txt").is_none());
+    }
+
+    #[test]
+    fn test_writable_paths_are_kept() {
+        let restricted = restrict_plan(plan(), access);
+
+        assert_eq!(restricted.get("shared/g.txt").unwrap().action, SyncAction::SelfDelete);
+        assert_eq!(restricted.get("shared/h.txt").unwrap().action, SyncAction::Put);
+    }
+}
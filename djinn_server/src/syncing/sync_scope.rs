@@ -0,0 +1,111 @@
+use djinn_core_lib::data::{
+    messages::SyncRequest,
+    syncing::{join_path, normalize_path, resolve_path, PathError},
+};
+
+use crate::{
+    auth::{readable_below, resolve_access},
+    configuration::{acl_rule::Access, share_config::{ShareConfig, SyncPolicy}},
+    CONFIG,
+};
+
+/// Directory inside a share a client syncs. Paths of the sync, like the index keys, are relative to it.
+#[derive(Clone, Debug)]
+pub struct SyncScope {
+    pub share: &'static ShareConfig,
+    /// The synced directory relative to the share root, like `/docs`
+    pub path: String,
+}
+
+impl SyncScope {
+    pub fn new(share: &'static ShareConfig, path: &str) -> Result<SyncScope, PathError> {
+        Ok(SyncScope {
+            share,
+            path: normalize_path(path)?,
+        })
+    }
+
+    /// Scope of a sync request, None when it names a share the server does not have
+    pub fn of(request: &SyncRequest) -> Option<Result<SyncScope, PathError>> {
+        let share = CONFIG.share(request.share())?;
+
+        Some(SyncScope::new(share, request.share_path()))
+    }
+
+    /// The synced directory on disk
+    pub fn root(&self) -> String {
+        join_path(&self.share.path, &self.path).unwrap()
+    }
+
+    /// Path on disk of a path of the sync, refusing anything which leads outside the synced directory
+    pub async fn resolve(&self, path: &str) -> Result<String, PathError> {
+        resolve_path(&self.root(), path).await
+    }
+
+    /// Path in the share of a path of the sync, which was checked before it got here
+    pub fn to_share_path(&self, path: &str) -> String {
+        join_path(&self.path, path).unwrap()
+    }
+
+    /// Path of the sync of a path in the share, None when the path is outside the synced directory
+    pub fn rebase(&self, share_path: &str) -> Option<String> {
+        if self.path == "/" {
+            return Some(share_path.to_string());
+        }
+
+        match share_path.strip_prefix(&self.path) {
+            Some("") => Some("/".to_string()),
+            Some(path) if path.starts_with('/') => Some(path.to_string()),
+            _ => None,
+        }
+    }
+
+    /// What the user may do with a path of the sync, the policy of the share included
+    pub fn access(&self, user: &str, path: &str) -> Access {
+        let access = resolve_access(&self.share.acl, CONFIG.users.as_ref().unwrap(), user, &self.to_share_path(path));
+
+        match self.share.policy {
+            SyncPolicy::TwoWay => access,
+            SyncPolicy::DownloadOnly => access.min(Access::ReadOnly),
+        }
+    }
+
+    /// Whether the user may read the synced directory or anything below it
+    pub fn is_readable(&self, user: &str) -> bool {
+        readable_below(&self.share.acl, CONFIG.users.as_ref().unwrap(), user, &self.path)
+    }
+
+    pub fn tombstone_retention_days(&self) -> u64 {
+        self.share.tombstone_retention_days.or(CONFIG.tombstone_retention_days).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn share() -> &'static ShareConfig {
+        Box::leak(Box::new(ShareConfig {
+            name: "projects".to_string(),
+            path: "/srv/projects".to_string(),
+            acl: vec![],
+            policy: SyncPolicy::TwoWay,
+            tombstone_retention_days: None,
+        }))
+    }
+
+    #[test]
+    fn test_share_paths() {
+        let scope = SyncScope::new(share(), "/docs/").unwrap();
+
+        assert_eq!(scope.to_share_path("/a.txt"), "/docs/a.txt");
+        assert_eq!(scope.rebase("/docs/a.txt").as_deref(), Some("/a.txt"));
+        assert_eq!(scope.rebase("/docs/sub/b.txt").as_deref(), Some("/sub/b.txt"));
+        assert_eq!(scope.rebase("/photos/a.jpg"), None);
+        assert_eq!(scope.rebase("/docs2/a.txt"), None);
+
+        let root_scope = SyncScope::new(share(), "/").unwrap();
+        assert_eq!(root_scope.to_share_path("/docs/a.txt"), "/docs/a.txt");
+        assert_eq!(root_scope.rebase("/photos/a.jpg").as_deref(), Some("/photos/a.jpg"));
+    }
+}
@@ -0,0 +1,165 @@
+use std::{
+    collections::HashMap,
+    io,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use djinn_core_lib::data::{
+    packets::params_codec::{decode_params, encode_params},
+    syncing::{IndexEntry, STATE_DIRECTORY},
+};
+use lazy_static::lazy_static;
+use tokio::{
+    fs,
+    sync::{MappedMutexGuard, Mutex, MutexGuard},
+};
+
+const TOMBSTONES_FILE: &str = "tombstones";
+const TOMBSTONES_VERSION: &str = "1";
+const SECONDS_PER_DAY: usize = 24 * 60 * 60;
+
+lazy_static! {
+    static ref TOMBSTONE_STORES: Mutex<HashMap<String, TombstoneStore>> = Mutex::new(HashMap::new());
+}
+
+/// Returns the tombstones of a share, loading them from disk the first time the share root is used.
+/// The store stays locked until the guard is dropped.
+pub async fn tombstones_for(root: &str) -> MappedMutexGuard<'static, TombstoneStore> {
+    let root = root.trim_end_matches('/');
+    let mut stores = TOMBSTONE_STORES.lock().await;
+
+    if !stores.contains_key(root) {
+        let store = TombstoneStore::load(root).await;
+        stores.insert(root.to_string(), store);
+    }
+
+    MutexGuard::map(stores, |stores| stores.get_mut(root).unwrap())
+}
+
+/// Deletion times of files in a share, kept in `<share root>/.djinn/tombstones`.
+/// Paths are relative to the share root, clients syncing a directory of it rebase them to their own.
+pub struct TombstoneStore {
+    path: PathBuf,
+    tombstones: HashMap<String, usize>,
+}
+
+impl TombstoneStore {
+    pub async fn load(root: &str) -> TombstoneStore {
+        let path = Path::new(root).join(STATE_DIRECTORY).join(TOMBSTONES_FILE);
+        let tombstones = match fs::read(&path).await {
+            Ok(buffer) => TombstoneStore::decode(&buffer).unwrap_or_else(|| {
+                warn!("Ignoring corrupt {}", path.display());
+                HashMap::new()
+            }),
+            Err(error) => {
+                if error.kind() != io::ErrorKind::NotFound {
+                    warn!("Failed to read {}: {}", path.display(), error);
+                }
+                HashMap::new()
+            }
+        };
+
+        TombstoneStore { path, tombstones }
+    }
+
+    pub fn tombstones(&self) -> &HashMap<String, usize> {
+        &self.tombstones
+    }
+
+    pub fn add(&mut self, path: String, deleted_time: usize) {
+        self.tombstones.insert(path, deleted_time);
+    }
+
+    /// Drops tombstones older than the retention period and of files which exist again.
+    /// Returns whether anything was dropped.
+    pub fn compact(&mut self, index: &HashMap<String, IndexEntry>, now: usize, retention_days: u64) -> bool {
+        let retention = retention_days as usize * SECONDS_PER_DAY;
+        let count = self.tombstones.len();
+
+        self.tombstones.retain(|path, deleted_time| {
+            now.saturating_sub(*deleted_time) <= retention
+                && index.get(path).is_none_or(|entry| entry.is_deleted())
+        });
+
+        self.tombstones.len() != count
+    }
+
+    /// Rewrites the tombstones file. Written to a temporary file first so a crash never loses existing tombstones.
+    pub async fn save(&self) -> io::Result<()> {
+        let mut params: HashMap<String, String> = self
+            .tombstones
+            .iter()
+            .map(|(path, deleted_time)| (path.clone(), deleted_time.to_string()))
+            .collect();
+        params.insert("#version".to_string(), TOMBSTONES_VERSION.to_string());
+
+        fs::create_dir_all(self.path.parent().unwrap()).await?;
+
+        let temp_path = self.path.with_extension("djinn_temp");
+        fs::write(&temp_path, encode_params(&params)).await?;
+        fs::rename(&temp_path, &self.path).await
+    }
+
+    fn decode(buffer: &[u8]) -> Option<HashMap<String, usize>> {
+        let mut params = decode_params(buffer).ok()?;
+
+        if params.remove("#version").as_deref() != Some(TOMBSTONES_VERSION) {
+            return None;
+        }
+
+        params
+            .into_iter()
+            .map(|(path, deleted_time)| Some((path, deleted_time.parse().ok()?)))
+            .collect()
+    }
+}
+
+pub fn unix_now() -> usize {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Failed to get system time")
+        .as_secs() as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_save_and_load() {
+        let root = std::env::temp_dir().join("djinn_test_tombstones");
+        let root = root.to_str().unwrap();
+
+        let mut store = TombstoneStore::load(root).await;
+        assert!(store.tombstones().is_empty());
+
+        store.add("/a;b=c.txt".to_string(), 123);
+        store.add("/sub/b.txt".to_string(), 124);
+        store.save().await.unwrap();
+
+        let loaded = TombstoneStore::load(root).await;
+        assert_eq!(loaded.tombstones(), store.tombstones());
+
+        fs::remove_dir_all(root).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_compact() {
+        let now = 100 * SECONDS_PER_DAY;
+        let retention_days = 30;
+        let retention = retention_days as usize * SECONDS_PER_DAY;
+
+        let mut store = TombstoneStore::load("/nonexistent").await;
+        store.add("/expired.txt".to_string(), now - retention - 1);
+        store.add("/recreated.txt".to_string(), now);
+        store.add("/deleted.txt".to_string(), now);
+
+        let mut index = HashMap::new();
+        index.insert("/recreated.txt".to_string(), IndexEntry::new(now, 4, None));
+
+        assert!(store.compact(&index, now, retention_days));
+        assert_eq!(store.tombstones().keys().collect::<Vec<_>>(), vec!["/deleted.txt"]);
+        assert!(!store.compact(&index, now, retention_days));
+    }
+}
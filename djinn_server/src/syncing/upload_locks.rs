@@ -0,0 +1,71 @@
+use std::{collections::HashMap, path::{Path, PathBuf}};
+
+use lazy_static::lazy_static;
+use tokio::sync::Mutex;
+use uuid::Uuid;
+
+lazy_static! {
+    static ref UPLOAD_LOCKS: Mutex<HashMap<String, Uuid>> = Mutex::new(HashMap::new());
+}
+
+/// Claims a file for an upload by a connection. Returns false while another upload is writing it.
+pub async fn lock_upload(full_path: &str, connection_uuid: Uuid) -> bool {
+    let mut locks = UPLOAD_LOCKS.lock().await;
+    let key = lock_key(full_path);
+
+    if locks.contains_key(&key) {
+        return false;
+    }
+
+    locks.insert(key, connection_uuid);
+    true
+}
+
+/// Releases the upload of a file, only the connection which claimed it can
+pub async fn unlock_upload(full_path: &str, connection_uuid: Uuid) {
+    let mut locks = UPLOAD_LOCKS.lock().await;
+    let key = lock_key(full_path);
+
+    if locks.get(&key) == Some(&connection_uuid) {
+        locks.remove(&key);
+    }
+}
+
+/// Releases the uploads of a closed connection, their partial files can be continued by the next one
+pub async fn unlock_uploads_of(connection_uuid: Uuid) {
+    UPLOAD_LOCKS.lock().await.retain(|_, uuid| *uuid != connection_uuid);
+}
+
+pub async fn is_upload_locked(full_path: &str) -> bool {
+    UPLOAD_LOCKS.lock().await.contains_key(&lock_key(full_path))
+}
+
+// Paths are built by joining with "/", so the same file may come with doubled slashes
+fn lock_key(full_path: &str) -> String {
+    Path::new(full_path).components().collect::<PathBuf>().to_string_lossy().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_upload_locks() {
+        let (first, second) = (Uuid::new_v4(), Uuid::new_v4());
+
+        assert!(lock_upload("/locks/a.txt", first).await);
+        assert!(!lock_upload("/locks/a.txt", second).await);
+        assert!(lock_upload("/locks/b.txt", first).await);
+
+        // Only the owner releases a lock
+        unlock_upload("/locks/a.txt", second).await;
+        assert!(is_upload_locked("/locks/a.txt").await);
+        unlock_upload("/locks//a.txt", first).await;
+        assert!(lock_upload("/locks/a.txt", second).await);
+
+        // Closing the first connection only frees its own uploads
+        unlock_uploads_of(first).await;
+        assert!(!is_upload_locked("/locks/b.txt").await);
+        assert!(is_upload_locked("/locks/a.txt").await);
+    }
+}